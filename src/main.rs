@@ -1,18 +1,32 @@
-use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::fmt::Display;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use async_std::io;
-use clap::Parser;
-use foundationdb::future::{FdbKeyValue, FdbValues};
-use foundationdb::tuple::{pack, unpack, Subspace};
+use async_std::io::{self, ReadExt};
+use chrono::SubsecRound;
+use clap::{ArgEnum, Parser, Subcommand};
+use foundationdb::api::NetworkAutoStop;
+use foundationdb::future::FdbKeyValue;
+use foundationdb::options::{MutationType, TransactionOption};
+use foundationdb::tuple::{pack, pack_with_versionstamp, unpack, Element, Subspace, Versionstamp};
 use foundationdb::{Database, FdbError, FdbResult, KeySelector, RangeOption, Transaction};
-use futures::future::select;
-use futures::Future;
+use futures::future::{select, LocalBoxFuture};
+use futures::stream::LocalBoxStream;
 use futures::{future::Either, future::FutureExt, pin_mut, stream::StreamExt};
+use futures::{Future, Stream, TryFutureExt, TryStreamExt};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use signal_hook::consts::signal::*;
 use signal_hook_async_std::Signals;
+use unicode_normalization::UnicodeNormalization;
 use uuid::Uuid;
 
 type DateTime = chrono::DateTime<chrono::Utc>;
@@ -61,403 +75,9195 @@ impl foundationdb::TransactError for AnyErr {
     }
 }
 
+/// Returned when trying to act on behalf of a session that has already left its room.
+#[derive(Debug)]
+pub struct SessionInactive {
+    pub room: String,
+    pub username: String,
+}
+
+impl Display for SessionInactive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Session for {} in room {} is no longer active",
+            self.username, self.room
+        )
+    }
+}
+
+impl std::error::Error for SessionInactive {}
+
+/// Returned when joining a room that already has as many users as its limit allows.
+#[derive(Debug)]
+pub struct RoomFull {
+    pub room: String,
+    pub max_users: u64,
+}
+
+impl Display for RoomFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Room {} is full ({} users at most)",
+            self.room, self.max_users
+        )
+    }
+}
+
+impl std::error::Error for RoomFull {}
+
+/// Returned by Session::write_if_unchanged when the room's latest message isn't the one expected.
+#[derive(Debug)]
+pub struct RoomChanged {
+    pub room: String,
+    /// When the latest message was expected to have been sent, or None for an empty room.
+    pub expected: Option<DateTime>,
+    /// When the latest message was in fact sent, or None if the room has none.
+    pub found: Option<DateTime>,
+}
+
+impl Display for RoomChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let describe = |dt: Option<DateTime>| match dt {
+            None => "no messages".to_string(),
+            Some(dt) => format!("a latest message at {}", dt),
+        };
+        write!(
+            f,
+            "Room {} has changed: expected {}, but found {}",
+            self.room,
+            describe(self.expected),
+            describe(self.found)
+        )
+    }
+}
+
+impl std::error::Error for RoomChanged {}
+
 const CHAT_OPTS: foundationdb::TransactOption = foundationdb::TransactOption {
     retry_limit: Some(3),
     time_out: None,
     is_idempotent: false,
 };
 
-struct Input {
-    stdin: io::Stdin,
-    line: String,
-}
+/// Options for the transactions that read and arm a watch.
+///
+/// These write nothing, so they are safe to retry even when a commit's outcome is unknown. A
+/// watch is only live once its transaction commits; on a failed commit transact_boxed_local
+/// re-runs the whole closure, reading again and arming a fresh watch, and the watch from the
+/// failed attempt is dropped (and so cancelled) without ever being returned.
+const WATCH_OPTS: foundationdb::TransactOption = foundationdb::TransactOption {
+    retry_limit: Some(3),
+    time_out: None,
+    is_idempotent: true,
+};
+
+/// How long joining a room waits for the cluster before giving up, unless configured otherwise.
+const DEFAULT_INIT_TIMEOUT_MS: u64 = 5000;
+
+/// How far the local clock may be behind the room's latest message before warning, unless
+/// configured otherwise.
+const DEFAULT_CLOCK_SKEW_WARNING_MS: u64 = 1000;
+
+/// How many entries the activity log keeps; older entries are pruned as new ones are written.
+const ACTIVITY_LOG_LIMIT: i32 = 1000;
+
+/// How many keys Session::rename_room moves in each transaction.
+const RENAME_BATCH_SIZE: usize = 500;
+
+/// How many messages Session::stream_reverse reads at a time.
+const REVERSE_PAGE_SIZE: usize = 100;
+
+/// FoundationDB's error code for a transaction that hit its timeout.
+const TRANSACTION_TIMED_OUT: i32 = 1031;
+
+/// FoundationDB's error codes for a watch that can't be armed, because watches are disabled or
+/// the client already has as many as it is allowed.
+const WATCHES_DISABLED: i32 = 1034;
+const TOO_MANY_WATCHES: i32 = 1032;
+
+/// FoundationDB's error code for reading in a transaction past its 5 second limit.
+const TRANSACTION_TOO_OLD: i32 = 1007;
+
+/// How long Session::read_range_paged reads for by default before returning what it has, leaving
+/// a margin under the transaction limit.
+const PAGED_READ_BUDGET: Duration = Duration::from_secs(4);
+
+/// Replace a transaction timeout with a friendlier error about reaching the cluster.
+fn unreachable_cluster_error(err: AnyErr, timeout: Duration) -> AnyErr {
+    match err {
+        AnyErr::Fdb(e) if e.code() == TRANSACTION_TIMED_OUT => anyhow::format_err!(
+            "Could not reach FoundationDB cluster within {} ms; is the cluster file correct?",
+            timeout.as_millis()
+        )
+        .into(),
+        e => e,
+    }
+}
+
+/// Whether Event::emit writes events, as turned on with --json-events.
+static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+
+/// A lifecycle event, for programs wrapping this one to follow what it is doing.
+///
+/// With --json-events, each is written to stderr as a line of JSON, tagged with its kind under
+/// "event" and the time it happened under "ts", e.g.
+/// `{"ts":"2022-02-27T20:37:57.034Z","event":"joined","room":"general","username":"alice"}`.
+/// Chat output on stdout is unaffected.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Joined {
+        room: &'a str,
+        username: &'a str,
+    },
+    Left {
+        room: &'a str,
+        username: &'a str,
+    },
+    /// A message was shown; cursor is its timestamp as in its key, as used by /acks.
+    Message {
+        room: &'a str,
+        cursor: Option<&'a str>,
+        sender: Option<&'a str>,
+    },
+    Error {
+        room: Option<&'a str>,
+        error: String,
+    },
+    WatchFired {
+        room: &'a str,
+    },
+    /// A watch fired after earlier ones on the room had failed.
+    Reconnected {
+        room: &'a str,
+    },
+}
+
+impl Event<'_> {
+    /// Write this event to stderr, if events are turned on.
+    fn emit(&self) {
+        if !JSON_EVENTS.load(Ordering::Relaxed) {
+            return;
+        }
+
+        #[derive(Serialize)]
+        struct Timestamped<'a, 'b> {
+            ts: String,
+            #[serde(flatten)]
+            event: &'b Event<'a>,
+        }
+        let event = Timestamped {
+            ts: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            event: self,
+        };
+        match serde_json::to_string(&event) {
+            Ok(line) => eprintln!("{}", line),
+            Err(e) => log::warn!("Failed to write event {:?}: {}", self, e),
+        }
+    }
+}
+
+/// A trace span covering one database operation, exported over OTLP when built with the otel
+/// feature and run with --otlp-endpoint, and a no-op otherwise.
+///
+/// Each span is a root: there is no incoming trace context for the chat client to continue.
+///
+/// With --profile, each span's duration is also recorded for print_profile.
+struct OpSpan {
+    name: &'static str,
+    started: Instant,
+    #[cfg(feature = "otel")]
+    span: opentelemetry::global::BoxedSpan,
+}
+
+impl OpSpan {
+    /// Start a span named for the operation, on the given room and (raw) key.
+    fn start(name: &'static str, room: &str, key: &[u8]) -> OpSpan {
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry::trace::{Span, Tracer};
+            use opentelemetry::KeyValue;
+
+            let mut span = opentelemetry::global::tracer("fdbchat").start(name);
+            span.set_attribute(KeyValue::new("room", room.to_string()));
+            span.set_attribute(KeyValue::new(
+                "key",
+                String::from_utf8_lossy(key).into_owned(),
+            ));
+            OpSpan {
+                name,
+                started: Instant::now(),
+                span,
+            }
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = (room, key);
+            OpSpan {
+                name,
+                started: Instant::now(),
+            }
+        }
+    }
+
+    /// End the span with the operation's result and, if known, how many attempts it took.
+    ///
+    /// Committed versions aren't recorded, as Database::transact doesn't expose the
+    /// transaction after it commits.
+    fn end<T, E: Display>(self, attempts: Option<u32>, result: &Result<T, E>) {
+        record_latency(self.name, self.started.elapsed());
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry::trace::{Span, StatusCode};
+            use opentelemetry::KeyValue;
+
+            let mut span = self.span;
+            if let Some(attempts) = attempts {
+                span.set_attribute(KeyValue::new("attempts", i64::from(attempts)));
+            }
+            if let Err(e) = result {
+                span.set_status(StatusCode::Error, e.to_string());
+            }
+            span.end();
+        }
+        #[cfg(not(feature = "otel"))]
+        {
+            let _ = (self, attempts, result);
+        }
+    }
+}
+
+/// The durations of every span so far, by span name, if --profile is on.
+static PROFILE: Mutex<Option<BTreeMap<&'static str, Vec<Duration>>>> = Mutex::new(None);
+
+/// Start recording the duration of every span, for print_profile.
+fn enable_profile() {
+    if let Ok(mut profile) = PROFILE.lock() {
+        *profile = Some(BTreeMap::new());
+    }
+}
+
+fn record_latency(name: &'static str, elapsed: Duration) {
+    if let Ok(mut profile) = PROFILE.lock() {
+        if let Some(profile) = profile.as_mut() {
+            profile.entry(name).or_default().push(elapsed);
+        }
+    }
+}
+
+/// Print a table of how long each kind of operation took, at a few percentiles, to stderr.
+///
+/// Writes are timed from start to commit, including retries, reads for the whole range, and
+/// watches for the whole wait until they fire.
+fn print_profile() {
+    let profile = match PROFILE.lock() {
+        Ok(mut profile) => match profile.take() {
+            Some(profile) => profile,
+            None => return,
+        },
+        Err(_) => return,
+    };
+
+    let ms = |d: Duration| format!("{:.1}ms", d.as_secs_f64() * 1000.0);
+    eprintln!(
+        "{:<10} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "operation", "count", "p50", "p90", "p99", "max"
+    );
+    for (name, mut durations) in profile {
+        durations.sort();
+        let percentile = |p: f64| {
+            let i = (p * (durations.len() - 1) as f64).round() as usize;
+            ms(durations[i])
+        };
+        eprintln!(
+            "{:<10} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            name,
+            durations.len(),
+            percentile(0.5),
+            percentile(0.9),
+            percentile(0.99),
+            ms(durations[durations.len() - 1]),
+        );
+    }
+}
+
+/// Send spans to the OTLP collector at endpoint (over HTTP) until shutdown_tracing is called.
+#[cfg(feature = "otel")]
+fn init_tracing(endpoint: &str) -> anyhow::Result<()> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .install_simple()
+        .context("Setting up OTLP trace export")?;
+
+    Ok(())
+}
+
+/// Flush any spans not yet exported.
+#[cfg(feature = "otel")]
+fn shutdown_tracing() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// The canonical form of a room or username, for --case-insensitive: NFKC-normalized and
+/// lowercased, so that names that look the same are the same.
+fn canonical_name(name: &str) -> String {
+    name.nfkc().collect::<String>().to_lowercase()
+}
+
+/// Make reads in tx fail after timeout, rather than hanging forever against an unreachable cluster.
+fn set_timeout(tx: &dyn Store, timeout: Duration) -> FdbResult<()> {
+    let timeout_ms = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+    tx.set_option(TransactionOption::Timeout(timeout_ms))
+}
+
+/// The prompt shown by --prompt when no format is given.
+const DEFAULT_PROMPT: &str = "{username}> ";
+
+/// Typed alone on a line, starts a message spanning several lines.
+const MULTILINE_START: &str = "/multiline";
+
+/// Typed alone on a line, ends a message started with MULTILINE_START.
+const MULTILINE_END: &str = ".";
+
+/// Followed by a message's timestamp, quotes that message at the start of the next one sent.
+const QUOTE_COMMAND: &str = "/quote";
+
+/// Followed by a message's timestamp, lists who has acknowledged that message (see --acks).
+const ACKS_COMMAND: &str = "/acks";
+
+/// Followed by a message's timestamp and an emoji, reacts to that message with the emoji.
+const REACT_COMMAND: &str = "/react";
+
+/// Followed by a message's timestamp and an emoji, takes back that reaction to the message.
+const UNREACT_COMMAND: &str = "/unreact";
+
+/// Followed by a message's timestamp, summarizes who has reacted to that message with what.
+const REACTIONS_COMMAND: &str = "/reactions";
+
+/// Followed by a message, sends it to be deleted once read (see --allow-ephemeral).
+const EPHEMERAL_COMMAND: &str = "/ephemeral";
+
+/// Typed alone on a line, prints the read version and the age of the latest message (see
+/// --show-lag).
+const LAG_COMMAND: &str = "/lag";
+
+/// Followed by a username, tells when that user joined the room and last sent a message.
+const SEEN_COMMAND: &str = "/seen";
+
+/// Followed by a channel name, like #random, switches to sending to and reading from that
+/// channel within the room; alone, switches back to the room's default channel.
+const CHANNEL_COMMAND: &str = "/channel";
+
+/// If line is command, return what follows it, which is empty or starts with a space, so that
+/// e.g. "/reactions" isn't taken for "/react" followed by "ions".
+fn command_args<'a>(line: &'a str, command: &str) -> Option<&'a str> {
+    line.strip_prefix(command)
+        .filter(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+/// Puts the terminal on stdin into non-canonical mode without echo until dropped, so that input
+/// can be read, and echoed, a key at a time. Signals like Ctrl-C still work as usual.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        // Safety: termios is plain data, and is filled in by tcgetattr before it is read
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = termios;
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // Safety: original was filled in by tcgetattr
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Writes chat output to stdout, keeping the input prompt, if any, on the line below it.
+///
+/// When editing (with the terminal in RawMode), Input echoes what is typed itself and keeps it
+/// here, so that it can be redrawn intact below each incoming message.
+struct Terminal {
+    prompt: Option<String>,
+    /// What has been typed on the input line so far, when editing.
+    editing: Option<RefCell<String>>,
+    /// How many columns to wrap messages to, if any.
+    wrap: Option<usize>,
+}
+
+impl Terminal {
+    /// Print a line of output, redrawing the prompt and any input in progress after it.
+    fn print(&self, line: &str) -> io::Result<()> {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        if self.prompt.is_none() && self.editing.is_none() {
+            return writeln!(out, "{}", line);
+        }
+
+        // Return to the start of the input line and clear it before printing over it
+        write!(out, "\r\x1b[2K{}\n", line)?;
+        if let Some(prompt) = &self.prompt {
+            write!(out, "{}", prompt)?;
+        }
+        if let Some(typed) = &self.editing {
+            write!(out, "{}", typed.borrow())?;
+        }
+        out.flush()
+    }
+
+    /// Show the prompt, if any, when waiting for input.
+    fn show_prompt(&self) -> io::Result<()> {
+        if let Some(prompt) = &self.prompt {
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            write!(out, "{}", prompt)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Echo output for what was typed while editing.
+    fn echo(&self, s: &str) -> io::Result<()> {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        write!(out, "{}", s)?;
+        out.flush()
+    }
+}
+
+/// Where Input is within a terminal escape sequence (such as for an arrow key), which it skips.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// After the escape byte.
+    Start,
+    /// Within the sequence, which ends with a byte from @ to ~.
+    Sequence,
+}
+
+struct Input<'a> {
+    reader: Box<dyn io::BufRead + Unpin + 'a>,
+    line: String,
+    terminal: &'a Terminal,
+    /// Bytes read while editing but not yet handled, e.g. pasted after the end of a line.
+    pending: VecDeque<u8>,
+    /// The start of a multi-byte UTF-8 character being typed.
+    partial: Vec<u8>,
+    escape: Escape,
+    /// Whether the last byte handled while editing was a carriage return.
+    after_cr: bool,
+    /// Whether a line has been read yet, as only the first can start with a byte order mark.
+    started: bool,
+}
+
+impl<'a> Input<'a> {
+    /// Read lines from stdin.
+    fn new(terminal: &'a Terminal) -> Input<'a> {
+        Input::from_reader(terminal, io::BufReader::new(io::stdin()))
+    }
+
+    /// Read lines from reader rather than stdin, e.g. in tests.
+    fn from_reader(terminal: &'a Terminal, reader: impl io::BufRead + Unpin + 'a) -> Input<'a> {
+        Input {
+            reader: Box::new(reader),
+            line: String::new(),
+            terminal,
+            pending: VecDeque::new(),
+            partial: Vec::new(),
+            escape: Escape::None,
+            after_cr: false,
+            started: false,
+        }
+    }
+
+    /// Read the next line, or None at the end of input.
+    async fn next(&mut self) -> io::Result<Option<String>> {
+        use async_std::io::prelude::BufReadExt;
+
+        if let Some(typed) = &self.terminal.editing {
+            return self.next_edited(typed).await;
+        }
+
+        if self.reader.read_line(&mut self.line).await? == 0 {
+            return Ok(None);
+        }
+        let line = std::mem::take(&mut self.line);
+        let first = !std::mem::replace(&mut self.started, true);
+        Ok(Some(Input::clean_line(line, first)))
+    }
+
+    /// Drop the byte order mark that text files from Windows may start with, from the first
+    /// line, and end the line with LF rather than CRLF.
+    fn clean_line(mut line: String, first: bool) -> String {
+        if first {
+            if let Some(rest) = line.strip_prefix('\u{feff}') {
+                line = rest.to_string();
+            }
+        }
+        if line.ends_with("\r\n") {
+            line.truncate(line.len() - 2);
+            line.push('\n');
+        }
+        line
+    }
+
+    /// Read a line a key at a time, echoing it and keeping it in typed as it changes.
+    async fn next_edited(&mut self, typed: &RefCell<String>) -> io::Result<Option<String>> {
+        let mut buf = [0; 256];
+        loop {
+            while let Some(byte) = self.pending.pop_front() {
+                if let Some(line) = self.edit(byte, typed)? {
+                    return Ok(Some(line));
+                }
+            }
+
+            let n = self.reader.read(&mut buf).await?;
+            if n == 0 {
+                // The end of input, after whatever was typed on the last line
+                let line = std::mem::take(&mut *typed.borrow_mut());
+                return Ok(Some(line).filter(|line| !line.is_empty()));
+            }
+            self.pending.extend(&buf[..n]);
+        }
+    }
+
+    /// Handle one byte of input, returning the line if it is complete.
+    fn edit(&mut self, byte: u8, typed: &RefCell<String>) -> io::Result<Option<String>> {
+        let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+        match (self.escape, byte) {
+            (Escape::Start, b'[') | (Escape::Start, b'O') => self.escape = Escape::Sequence,
+            (Escape::Start, _) => self.escape = Escape::None,
+            (Escape::Sequence, 0x40..=0x7e) => self.escape = Escape::None,
+            (Escape::Sequence, _) => {}
+            (Escape::None, 0x1b) => self.escape = Escape::Start,
+            // The rest of a CRLF, e.g. when pasting, which already ended the line
+            (Escape::None, b'\n') if after_cr => {}
+            (Escape::None, b'\r') | (Escape::None, b'\n') => {
+                self.terminal.echo("\n")?;
+                let mut line = std::mem::take(&mut *typed.borrow_mut());
+                line.push('\n');
+                return Ok(Some(line));
+            }
+            // Backspace and delete
+            (Escape::None, 0x7f) | (Escape::None, 0x08) => {
+                self.partial.clear();
+                if typed.borrow_mut().pop().is_some() {
+                    self.terminal.echo("\x08 \x08")?;
+                }
+            }
+            // Ctrl-U clears the line
+            (Escape::None, 0x15) => {
+                typed.borrow_mut().clear();
+                self.terminal.echo("\r\x1b[2K")?;
+                self.terminal.show_prompt()?;
+            }
+            (Escape::None, byte) if byte < 0x20 => {}
+            (Escape::None, byte) => {
+                self.partial.push(byte);
+                match std::str::from_utf8(&self.partial) {
+                    Ok(c) => {
+                        typed.borrow_mut().push_str(c);
+                        self.terminal.echo(c)?;
+                        self.partial.clear();
+                    }
+                    // Not valid even with more bytes, so drop it
+                    Err(e) if e.error_len().is_some() => self.partial.clear(),
+                    Err(_) => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read lines up to one containing only ".", or the end of input, and join them into one
+    /// message. Unlike single lines, leading whitespace is kept, for code and pastes.
+    async fn next_multiline(&mut self) -> io::Result<String> {
+        let mut lines = Vec::new();
+        while let Some(line) = self.next().await? {
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            if line == MULTILINE_END {
+                break;
+            }
+            lines.push(line.to_string());
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+/// What kind of message this is, which determines how it is rendered.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    /// An ordinary chat line.
+    Text,
+    /// An IRC-style action, sent as `/me waves` and rendered as `* username waves`.
+    Action,
+}
+
+impl MessageKind {
+    /// The tag stored alongside the message text.
+    fn tag(self) -> i64 {
+        match self {
+            MessageKind::Text => 0,
+            MessageKind::Action => 1,
+        }
+    }
+
+    fn from_tag(tag: i64) -> Self {
+        match tag {
+            1 => MessageKind::Action,
+            0 => MessageKind::Text,
+            _ => {
+                log::debug!("Unknown message kind {}, treating as text", tag);
+                MessageKind::Text
+            }
+        }
+    }
+}
+
+/// How a message's body is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// UTF-8 text.
+    Utf8,
+    /// Arbitrary bytes, which are not necessarily printable.
+    Binary,
+}
+
+impl Encoding {
+    /// The tag stored alongside the message body.
+    fn tag(self) -> i64 {
+        match self {
+            Encoding::Utf8 => 0,
+            Encoding::Binary => 1,
+        }
+    }
+
+    fn from_tag(tag: i64) -> Self {
+        match tag {
+            0 => Encoding::Utf8,
+            1 => Encoding::Binary,
+            _ => {
+                log::debug!("Unknown message encoding {}, treating as binary", tag);
+                Encoding::Binary
+            }
+        }
+    }
+}
+
+/// A single chat message, along with metadata about how it is stored.
+///
+/// Displaying a message gives the line printed in the chat. Messages serialize as a
+/// MessageRecord.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(into = "MessageRecord", from = "MessageRecord")]
+pub struct Message {
+    pub ts: DateTime,
+    pub kind: MessageKind,
+    pub encoding: Encoding,
+    /// The raw message body; see Message::text for text messages.
+    pub body: Vec<u8>,
+    /// The username of the sender, if known.
+    pub sender: Option<String>,
+    /// Length of the raw (packed) key, in bytes.
+    pub key_len: usize,
+    /// Length of the raw value, in bytes.
+    pub value_len: usize,
+}
+
+impl Message {
+    /// The body as text, if it is UTF-8 encoded.
+    pub fn text(&self) -> Option<&str> {
+        match self.encoding {
+            Encoding::Utf8 => from_utf8(&self.body).ok(),
+            Encoding::Binary => None,
+        }
+    }
+
+    /// The body as text if possible, or a lossy rendering of it otherwise.
+    pub fn text_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    /// The message as printed in the chat, wrapped to fit in wrap columns if given.
+    fn format(&self, wrap: Option<usize>) -> String {
+        let sender = self.sender.as_deref();
+        format_message(self.ts, self.kind, sender, &self.display_body(), wrap)
+    }
+
+    /// The body in a printable form: text as-is, and anything else as its size and base64.
+    pub fn display_body(&self) -> std::borrow::Cow<'_, str> {
+        match self.text() {
+            Some(text) => text.into(),
+            None => format!("[{} bytes] {}", self.body.len(), base64::encode(&self.body)).into(),
+        }
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.format(None))
+    }
+}
+
+/// How a Message is serialized: text bodies as text, and any other body as base64.
+///
+/// Storage metadata (key_len and value_len) is not included, and is zero for deserialized
+/// messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageRecord {
+    pub ts: DateTime,
+    pub sender: Option<String>,
+    pub kind: MessageKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base64: Option<String>,
+    /// The room the message was sent to, included by export with --show-room and ignored by
+    /// import.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+}
+
+impl From<Message> for MessageRecord {
+    fn from(msg: Message) -> Self {
+        let text = msg.text().map(str::to_string);
+        let base64 = match text {
+            Some(_) => None,
+            None => Some(base64::encode(&msg.body)),
+        };
+        MessageRecord {
+            ts: msg.ts,
+            sender: msg.sender,
+            kind: msg.kind,
+            text,
+            base64,
+            room: None,
+        }
+    }
+}
+
+impl From<MessageRecord> for Message {
+    fn from(record: MessageRecord) -> Self {
+        // Bodies that aren't valid base64 are kept as their raw bytes
+        let (encoding, body) = match (record.text, record.base64) {
+            (Some(text), _) => (Encoding::Utf8, text.into_bytes()),
+            (None, Some(b64)) => (
+                Encoding::Binary,
+                base64::decode(&b64).unwrap_or_else(|_| b64.into_bytes()),
+            ),
+            (None, None) => (Encoding::Utf8, Vec::new()),
+        };
+        Message {
+            ts: record.ts,
+            kind: record.kind,
+            encoding,
+            body,
+            sender: record.sender,
+            key_len: 0,
+            value_len: 0,
+        }
+    }
+}
+
+/// The parts of a message that are stored in its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedMessage {
+    pub kind: MessageKind,
+    pub sender: Option<String>,
+    pub encoding: Encoding,
+    pub body: Vec<u8>,
+}
+
+/// Converts the contents of messages to and from the values they are stored as.
+pub trait ValueCodec {
+    fn encode(&self, msg: &DecodedMessage) -> Vec<u8>;
+    fn decode(&self, value: &[u8]) -> AnyResult<DecodedMessage>;
+}
+
+/// A source of the current time, for the timestamps sessions give messages and compare them to,
+/// so that they can be run against a controlled clock.
+pub trait Clock {
+    fn now(&self) -> DateTime;
+}
+
+/// The default clock, which reads the system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime {
+        chrono::Utc::now()
+    }
+}
+
+/// The default codec, which stores messages as a tuple of their kind, their sender, their
+/// encoding, and their body, and also reads the older formats that lacked some of those.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TupleCodec;
+
+impl ValueCodec for TupleCodec {
+    fn encode(&self, msg: &DecodedMessage) -> Vec<u8> {
+        let sender = msg.sender.as_deref().unwrap_or("");
+        pack(&(msg.kind.tag(), sender, msg.encoding.tag(), &msg.body[..]))
+    }
+
+    fn decode(&self, value: &[u8]) -> AnyResult<DecodedMessage> {
+        if let Ok((kind, sender, encoding, body)) = unpack::<(i64, String, i64, Vec<u8>)>(value) {
+            return Ok(DecodedMessage {
+                kind: MessageKind::from_tag(kind),
+                sender: Some(sender),
+                encoding: Encoding::from_tag(encoding),
+                body,
+            });
+        }
+
+        // Before messages had an encoding, they were a tuple of kind, sender, and text
+        if let Ok((kind, sender, text)) = unpack::<(i64, String, String)>(value) {
+            return Ok(DecodedMessage {
+                kind: MessageKind::from_tag(kind),
+                sender: Some(sender),
+                encoding: Encoding::Utf8,
+                body: text.into_bytes(),
+            });
+        }
+
+        // Before that, they were just their UTF-8 text
+        let msg = str::to_string(from_utf8(value).context("Parsing message")?);
+        Ok(DecodedMessage {
+            kind: MessageKind::Text,
+            sender: None,
+            encoding: Encoding::Utf8,
+            body: msg.into_bytes(),
+        })
+    }
+}
+
+/// The sub-second precision used for timestamps in message keys.
+///
+/// Keys at every precision are zero-padded RFC3339 strings, so they parse the same way and sort
+/// chronologically. A room mixing precisions still sorts correctly down to the coarser precision,
+/// but within that (e.g. a single millisecond) coarser keys sort after finer ones.
+///
+/// Messages are always read in key order, so every read sees the same order. There are no ties
+/// to break: the timestamp is the whole key, so a message written at the same timestamp (at the
+/// same precision) as another replaces it. Writers avoid this by spacing their own messages at
+/// least one unit apart, as Session::write_many does, but two senders writing in the same unit
+/// can still collide; finer precision makes that less likely.
+#[derive(ArgEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPrecision {
+    #[default]
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl KeyPrecision {
+    fn seconds_format(self) -> chrono::SecondsFormat {
+        match self {
+            KeyPrecision::Millis => chrono::SecondsFormat::Millis,
+            KeyPrecision::Micros => chrono::SecondsFormat::Micros,
+            KeyPrecision::Nanos => chrono::SecondsFormat::Nanos,
+        }
+    }
+
+    /// The number of sub-second digits in keys at this precision.
+    fn digits(self) -> u16 {
+        match self {
+            KeyPrecision::Millis => 3,
+            KeyPrecision::Micros => 6,
+            KeyPrecision::Nanos => 9,
+        }
+    }
+
+    /// The smallest step between two distinct timestamps at this precision.
+    fn unit(self) -> chrono::Duration {
+        match self {
+            KeyPrecision::Millis => chrono::Duration::milliseconds(1),
+            KeyPrecision::Micros => chrono::Duration::microseconds(1),
+            KeyPrecision::Nanos => chrono::Duration::nanoseconds(1),
+        }
+    }
+}
+
+/// How much effort a successful write took.
+#[derive(Debug, Clone, Copy)]
+struct WriteStats {
+    /// How many times the transaction ran, including retries.
+    attempts: u32,
+    latency: Duration,
+}
+
+/// Set once the FoundationDB network has been started, as it can only be started once per process.
+static NETWORK_BOOTED: AtomicBool = AtomicBool::new(false);
+
+/// Keeps the FoundationDB network running until dropped, then stops it and waits for it to finish.
+///
+/// Drop it once every Session and SessionManager is done, before the process exits; holding it
+/// in a local of main, as main does, does both. The network can't be started again afterwards.
+/// The test sessions_are_done_with_before_the_network walks through the whole lifecycle.
+pub struct NetworkGuard {
+    _network: NetworkAutoStop,
+}
+
+/// Start the FoundationDB network, returning a guard that stops it when dropped.
+///
+/// This fails if the network was already started in this process, rather than panicking in the
+/// client library.
+pub fn boot() -> anyhow::Result<NetworkGuard> {
+    if NETWORK_BOOTED.swap(true, Ordering::SeqCst) {
+        return Err(anyhow::format_err!(
+            "The FoundationDB network was already started"
+        ));
+    }
+    // The guard is the only handle on the network, and stops it when dropped, as boot requires
+    let network = unsafe { foundationdb::boot() };
+    Ok(NetworkGuard { _network: network })
+}
+
+/// A key and value read from a Store, owned so that it outlives the read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyValue {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl KeyValue {
+    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        KeyValue { key, value }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+impl From<&FdbKeyValue> for KeyValue {
+    fn from(kv: &FdbKeyValue) -> Self {
+        KeyValue::new(kv.key().to_vec(), kv.value().to_vec())
+    }
+}
+
+/// The reads and writes a Session makes inside a transaction.
+///
+/// These mirror the Transaction methods of the same names, which implement them; StoreDb runs
+/// the transactions. Writes are visible to later reads in the same transaction, and are only
+/// seen by others once it commits.
+pub trait Store {
+    fn get(&self, key: &[u8], snapshot: bool) -> LocalBoxFuture<'_, FdbResult<Option<Vec<u8>>>>;
+
+    fn get_key(
+        &self,
+        selector: &KeySelector<'_>,
+        snapshot: bool,
+    ) -> LocalBoxFuture<'_, FdbResult<Vec<u8>>>;
+
+    /// Read one batch of range, the iteration'th when stepping through it in
+    /// StreamingMode::Iterator.
+    fn get_range(
+        &self,
+        range: &RangeOption<'_>,
+        iteration: usize,
+        snapshot: bool,
+    ) -> LocalBoxFuture<'_, FdbResult<Vec<KeyValue>>>;
+
+    /// Read the whole of range, in batches.
+    fn get_ranges<'a>(
+        &'a self,
+        range: RangeOption<'a>,
+        snapshot: bool,
+    ) -> LocalBoxStream<'a, FdbResult<Vec<KeyValue>>>;
+
+    fn set(&self, key: &[u8], value: &[u8]);
+
+    fn clear(&self, key: &[u8]);
+
+    fn clear_range(&self, begin: &[u8], end: &[u8]);
+
+    fn clear_subspace_range(&self, subspace: &Subspace) {
+        let (begin, end) = subspace.range();
+        self.clear_range(&begin, &end)
+    }
+
+    fn atomic_op(&self, key: &[u8], param: &[u8], op: MutationType);
+
+    /// Resolve once the value of key changes from the one this transaction saw, which can only
+    /// happen after the transaction commits.
+    fn watch(&self, key: &[u8]) -> LocalBoxFuture<'static, FdbResult<()>>;
+
+    fn get_read_version(&self) -> LocalBoxFuture<'_, FdbResult<i64>>;
+
+    fn set_read_version(&self, version: i64);
+
+    fn set_option(&self, option: TransactionOption) -> FdbResult<()>;
+}
+
+impl Store for Transaction {
+    fn get(&self, key: &[u8], snapshot: bool) -> LocalBoxFuture<'_, FdbResult<Option<Vec<u8>>>> {
+        Transaction::get(self, key, snapshot)
+            .map_ok(|value| value.map(|value| value.to_vec()))
+            .boxed_local()
+    }
+
+    fn get_key(
+        &self,
+        selector: &KeySelector<'_>,
+        snapshot: bool,
+    ) -> LocalBoxFuture<'_, FdbResult<Vec<u8>>> {
+        Transaction::get_key(self, selector, snapshot)
+            .map_ok(|key| key.to_vec())
+            .boxed_local()
+    }
+
+    fn get_range(
+        &self,
+        range: &RangeOption<'_>,
+        iteration: usize,
+        snapshot: bool,
+    ) -> LocalBoxFuture<'_, FdbResult<Vec<KeyValue>>> {
+        Transaction::get_range(self, range, iteration, snapshot)
+            .map_ok(|kvs| kvs.iter().map(KeyValue::from).collect())
+            .boxed_local()
+    }
+
+    fn get_ranges<'a>(
+        &'a self,
+        range: RangeOption<'a>,
+        snapshot: bool,
+    ) -> LocalBoxStream<'a, FdbResult<Vec<KeyValue>>> {
+        Transaction::get_ranges(self, range, snapshot)
+            .map_ok(|kvs| kvs.iter().map(KeyValue::from).collect())
+            .boxed_local()
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) {
+        Transaction::set(self, key, value)
+    }
+
+    fn clear(&self, key: &[u8]) {
+        Transaction::clear(self, key)
+    }
+
+    fn clear_range(&self, begin: &[u8], end: &[u8]) {
+        Transaction::clear_range(self, begin, end)
+    }
+
+    fn atomic_op(&self, key: &[u8], param: &[u8], op: MutationType) {
+        Transaction::atomic_op(self, key, param, op)
+    }
+
+    fn watch(&self, key: &[u8]) -> LocalBoxFuture<'static, FdbResult<()>> {
+        Transaction::watch(self, key).boxed_local()
+    }
+
+    fn get_read_version(&self) -> LocalBoxFuture<'_, FdbResult<i64>> {
+        Transaction::get_read_version(self).boxed_local()
+    }
+
+    fn set_read_version(&self, version: i64) {
+        Transaction::set_read_version(self, version)
+    }
+
+    fn set_option(&self, option: TransactionOption) -> FdbResult<()> {
+        Transaction::set_option(self, option)
+    }
+}
+
+/// The work done in one transaction, run by StoreDb::run_transaction as many times as it takes
+/// to commit. StoreDb::transact builds one from a closure.
+pub trait TransactionBody {
+    fn run<'a>(&'a mut self, tx: &'a dyn Store) -> LocalBoxFuture<'a, AnyResult<()>>;
+}
+
+/// Where a Session keeps its room: the FoundationDB Database, or a stand-in for it.
+pub trait StoreDb {
+    /// Run body in a transaction and commit it, retrying as options allow.
+    fn run_transaction<'a>(
+        &'a self,
+        body: &'a mut dyn TransactionBody,
+        options: foundationdb::TransactOption,
+    ) -> LocalBoxFuture<'a, AnyResult<()>>;
+}
+
+impl StoreDb for Database {
+    fn run_transaction<'a>(
+        &'a self,
+        body: &'a mut dyn TransactionBody,
+        options: foundationdb::TransactOption,
+    ) -> LocalBoxFuture<'a, AnyResult<()>> {
+        self.transact_boxed_local::<_, _, _, AnyErr>(body, |tx, body| body.run(tx), options)
+            .boxed_local()
+    }
+}
+
+impl dyn StoreDb + '_ {
+    /// Run f in a transaction and commit it, retrying as options allow, as
+    /// Database::transact_boxed_local does.
+    pub async fn transact<F, D, T, E>(
+        &self,
+        data: D,
+        f: F,
+        options: foundationdb::TransactOption,
+    ) -> AnyResult<T>
+    where
+        F: for<'t> FnMut(&'t dyn Store, &'t mut D) -> LocalBoxFuture<'t, Result<T, E>>,
+        E: Into<AnyErr>,
+    {
+        struct Body<F, D, T> {
+            f: F,
+            data: D,
+            result: Option<T>,
+        }
+
+        impl<F, D, T, E> TransactionBody for Body<F, D, T>
+        where
+            F: for<'t> FnMut(&'t dyn Store, &'t mut D) -> LocalBoxFuture<'t, Result<T, E>>,
+            E: Into<AnyErr>,
+        {
+            fn run<'a>(&'a mut self, tx: &'a dyn Store) -> LocalBoxFuture<'a, AnyResult<()>> {
+                let Body { f, data, result } = self;
+                async move {
+                    *result = Some(f(tx, data).await.map_err(Into::into)?);
+                    Ok(())
+                }
+                .boxed_local()
+            }
+        }
+
+        let mut body = Body {
+            f,
+            data,
+            result: None,
+        };
+        self.run_transaction(&mut body, options).await?;
+        Ok(body
+            .result
+            .expect("A committed transaction has run its body"))
+    }
+}
+
+/// Opens one Database and joins any number of rooms through it, for applications hosting many
+/// sessions at once.
+///
+/// FoundationDB clients should share a Database rather than open one per session; every Session
+/// joined here holds the same one. The manager can only be made once the network is running, and
+/// borrows its NetworkGuard so that it can't be stopped while the manager is still in use.
+/// Sessions share the Database but not that borrow, so leave and drop them before stopping the
+/// network.
+pub struct SessionManager<'n> {
+    db: Arc<Database>,
+    _network: &'n NetworkGuard,
+}
+
+impl<'n> SessionManager<'n> {
+    /// Open the database given by cluster_file, or the default cluster file if None.
+    pub fn new(network: &'n NetworkGuard, cluster_file: Option<&Path>) -> AnyResult<Self> {
+        let db = match cluster_file {
+            None => Database::default()?,
+            Some(path) => {
+                let path = path
+                    .to_str()
+                    .context("Cluster file path is not valid UTF-8")?;
+                Database::from_path(path)?
+            }
+        };
+
+        Ok(SessionManager {
+            db: Arc::new(db),
+            _network: network,
+        })
+    }
+
+    /// The database shared by every session, e.g. for Session::clear or Session::firehose.
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// Join room as username, with the default timeout.
+    pub async fn join(&self, room: &str, username: &str) -> AnyResult<Session> {
+        let timeout = Duration::from_millis(DEFAULT_INIT_TIMEOUT_MS);
+        self.join_with(room, username, timeout, None, Box::new(SystemClock))
+            .await
+    }
+
+    /// Join room as username, as Session::init does, sharing this manager's database.
+    pub async fn join_with(
+        &self,
+        room: &str,
+        username: &str,
+        timeout: Duration,
+        previous_id: Option<Uuid>,
+        clock: Box<dyn Clock>,
+    ) -> AnyResult<Session> {
+        let session = Session::init(
+            self.db.clone(),
+            room.to_string(),
+            username.to_string(),
+            timeout,
+            previous_id,
+            clock,
+        )
+        .await?;
+        Event::Joined { room, username }.emit();
+        Ok(session)
+    }
+}
+
+pub struct Session {
+    db: Arc<dyn StoreDb>,
+    room: String,
+    username: String,
+    /// The name messages are sent under, which is the username unless set otherwise.
+    sender: String,
+    id: Option<Uuid>,
+    precision: KeyPrecision,
+    track_activity: bool,
+    codec: Box<dyn ValueCodec>,
+    clock: Box<dyn Clock>,
+    paged_read_budget: Duration,
+}
+
+impl Session {
+    /// The key holding the ID of the session with username in room, as a tuple. The key layout
+    /// is described in the README.
+    pub fn user_key<'a>(room: &'a str, username: &'a str) -> (&'a str, &'a str, &'a str, &'a str) {
+        ("rooms", room, "users", username)
+    }
+
+    /// The packed key holding the ID of the session with username in room, for clients that
+    /// don't use the tuple layer to match.
+    pub fn user_key_bytes(room: &str, username: &str) -> Vec<u8> {
+        pack(&Session::user_key(room, username))
+    }
+
+    /// The key recording that username is in room, in the index read by
+    /// Session::rooms_for_user_indexed.
+    fn user_room_key<'a>(username: &'a str, room: &'a str) -> (&'a str, &'a str, &'a str, &'a str) {
+        ("users", username, "rooms", room)
+    }
+
+    fn max_users_key(room: &str) -> (&str, &str, &str, &str) {
+        ("rooms", room, "config", "max_users")
+    }
+
+    /// The key marking that the room keeps an index of messages by sender, holding the time it
+    /// started.
+    fn user_index_key(room: &str) -> (&str, &str, &str, &str) {
+        ("rooms", room, "config", "index_by_user")
+    }
+
+    /// The index entry for the message sent by user at ts, as formatted in its key.
+    fn by_user_key<'a>(
+        room: &'a str,
+        user: &'a str,
+        ts: &'a str,
+    ) -> (&'a str, &'a str, &'a str, &'a str, &'a str) {
+        ("rooms", room, "by_user", user, ts)
+    }
+
+    fn parse_user_index_since(value: &[u8]) -> AnyResult<DateTime> {
+        let since: String = unpack(value).context("Unpacking user index start")?;
+        let since = chrono::DateTime::parse_from_rfc3339(&since).context("Parsing date")?;
+        Ok(DateTime::from(since))
+    }
+
+    /// When the room started keeping an index of messages by sender, or None if it doesn't.
+    ///
+    /// Writes check this in their own transaction, and without snapshot isolation, so one that
+    /// races with Session::enable_user_index conflicts and retries rather than committing its
+    /// message unindexed.
+    async fn user_index_since_tx(tx: &dyn Store, room: &str) -> AnyResult<Option<DateTime>> {
+        match tx.get(&pack(&Session::user_index_key(room)), false).await? {
+            None => Ok(None),
+            Some(v) => Ok(Some(Session::parse_user_index_since(&v)?)),
+        }
+    }
+
+    /// Remove the index entry for the message at ts with the given raw value, if it has one.
+    ///
+    /// Values the codec can't decode, or without a sender, can't have an entry and are ignored.
+    fn unindex_message_tx(
+        tx: &dyn Store,
+        codec: &dyn ValueCodec,
+        room: &str,
+        ts: &str,
+        value: &[u8],
+    ) {
+        if let Ok(DecodedMessage {
+            sender: Some(sender),
+            ..
+        }) = codec.decode(value)
+        {
+            tx.clear(&pack(&Session::by_user_key(room, &sender, ts)));
+        }
+    }
+
+    /// Fail with RoomFull if the room has a user limit and already has that many users.
+    ///
+    /// The limit and roster are read without snapshot isolation, so that concurrent joins
+    /// conflict and retry rather than both taking the last place.
+    async fn check_capacity_tx(tx: &dyn Store, room: &str) -> AnyResult<()> {
+        let max_users = match tx.get(&pack(&Session::max_users_key(room)), false).await? {
+            None => return Ok(()),
+            Some(v) => unpack::<u64>(&v).context("Unpacking user limit")?,
+        };
+
+        let space = Subspace::from(&("rooms", room, "users"));
+        // A limit of 0 means no limit to FoundationDB, so always read at least one user
+        let r = RangeOption {
+            limit: Some(usize::try_from(max_users.max(1)).unwrap_or(usize::MAX)),
+            ..RangeOption::from(&space)
+        };
+        let mut users = 0;
+        let mut ranges = tx.get_ranges(r, false);
+        while let Some(kvs) = ranges.next().await {
+            users += kvs?.len() as u64;
+        }
+
+        if users >= max_users {
+            return Err(anyhow::Error::from(RoomFull {
+                room: room.to_string(),
+                max_users,
+            })
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Limit how many users can be in room at once. Users already in the room aren't affected,
+    /// but no more can join until enough have left.
+    pub async fn set_max_users(db: &dyn StoreDb, room: &str, max_users: u64) -> AnyResult<()> {
+        let key = pack(&Session::max_users_key(room));
+        db.transact::<_, _, _, FdbError>(
+            (key, pack(&max_users)),
+            |tx, (key, value)| {
+                tx.set(key, value);
+                futures::future::ready(Ok(())).boxed_local()
+            },
+            CHAT_OPTS,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    fn date_string(dt: DateTime, precision: KeyPrecision) -> String {
+        dt.to_rfc3339_opts(precision.seconds_format(), true)
+    }
+
+    /// The session ID and, if recorded, the join time held by a user key, whose value is the
+    /// tuple (id, joined), or just (id,) from clients that didn't record when they joined.
+    fn parse_user_value(value: &[u8]) -> AnyResult<(Uuid, Option<DateTime>)> {
+        if let Ok((id, joined)) = unpack::<(Uuid, String)>(value) {
+            let joined =
+                chrono::DateTime::parse_from_rfc3339(&joined).context("Parsing join time")?;
+            return Ok((id, Some(DateTime::from(joined))));
+        }
+        let id = unpack::<Uuid>(value).context("Unpacking user")?;
+        Ok((id, None))
+    }
+
+    async fn init_tx(
+        tx: &dyn Store,
+        room: &str,
+        username: &str,
+        uuid: Uuid,
+        joined: &str,
+        previous_id: Option<Uuid>,
+        timeout: Duration,
+    ) -> AnyResult<()> {
+        set_timeout(tx, timeout)?;
+
+        let key = Session::user_key(room, username);
+        let val = tx.get(&pack(&key), false).await?;
+
+        if let Some(taken_id) = val {
+            // A restarted client may reclaim the username it held before. It takes a new ID, so
+            // that if the earlier session is in fact still running, it finds out and stops.
+            let taken_id = Session::parse_user_value(&taken_id).ok().map(|(id, _)| id);
+            if previous_id.is_some() && taken_id == previous_id {
+                log::info!("Reclaiming username {} in room {}", username, room);
+            } else {
+                return Err(anyhow::format_err!(
+                    "Username {} already taken in room {}!",
+                    username,
+                    room
+                )
+                .into());
+            }
+        } else {
+            Session::check_capacity_tx(tx, room).await?;
+        }
+
+        tx.set(&pack(&key), &pack(&(uuid, joined)));
+        Ok(())
+    }
+
+    /// Join the room, giving up with an error if the cluster can't be reached within timeout.
+    ///
+    /// If previous_id is the ID of an earlier session that still holds the username, the username
+    /// is reclaimed under a new ID rather than rejected. The join time, like the timestamps of
+    /// messages, is read from clock.
+    async fn init(
+        db: Arc<dyn StoreDb>,
+        room: String,
+        username: String,
+        timeout: Duration,
+        previous_id: Option<Uuid>,
+        clock: Box<dyn Clock>,
+    ) -> AnyResult<Self> {
+        let id = Uuid::new_v4();
+        let joined = Session::date_string(clock.now(), KeyPrecision::default());
+        let opts = foundationdb::TransactOption {
+            time_out: Some(timeout),
+            ..CHAT_OPTS
+        };
+
+        db.transact(
+            (room.as_ref(), username.as_ref(), joined.as_str()),
+            move |tx: &dyn Store, (room, username, joined)| {
+                Session::init_tx(tx, room, username, id, joined, previous_id, timeout).boxed_local()
+            },
+            opts,
+        )
+        .await
+        .map_err(|e| unreachable_cluster_error(e, timeout))?;
+
+        Ok(Session {
+            db,
+            sender: username.clone(),
+            room,
+            username,
+            id: Some(id),
+            precision: KeyPrecision::default(),
+            track_activity: false,
+            codec: Box::new(TupleCodec),
+            clock,
+            paged_read_budget: PAGED_READ_BUDGET,
+        })
+    }
+
+    /// Set the precision used for the timestamps of newly written messages.
+    pub fn set_precision(&mut self, precision: KeyPrecision) {
+        self.precision = precision;
+    }
+
+    /// Set the name messages are sent under, e.g. to preserve how a normalized username was typed.
+    pub fn set_sender(&mut self, sender: String) {
+        self.sender = sender;
+    }
+
+    /// Set how message contents are stored, e.g. to compress or encrypt them. Every session in a
+    /// room needs the same codec to read the others' messages.
+    pub fn set_codec(&mut self, codec: Box<dyn ValueCodec>) {
+        self.codec = codec;
+    }
+
+    /// Set where the current time comes from, e.g. to control it in tests.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Set whether writes also record the room and timestamp in the global activity log, as read
+    /// by Session::recent_activity. This costs an extra read and write per message.
+    pub fn set_track_activity(&mut self, track_activity: bool) {
+        self.track_activity = track_activity;
+    }
+
+    /// Set how long Session::read_range_paged reads for before returning a page, which must be
+    /// well under the 5 second transaction limit.
+    pub fn set_paged_read_budget(&mut self, budget: Duration) {
+        self.paged_read_budget = budget;
+    }
+
+    /// Start keeping an index of the room's messages by sender, which Session::read_from_user
+    /// reads instead of scanning the room. This costs an extra key with each message.
+    ///
+    /// The index is a property of the room: once any session has enabled it, every session keeps
+    /// it up to date too, whether or not it asked to, as each write checks for it. Messages from
+    /// before it was enabled are still found by scanning.
+    pub async fn enable_user_index(&self) -> AnyResult<()> {
+        self.ensure_active()?;
+        let key = pack(&Session::user_index_key(&self.room));
+        let now = Session::date_string(self.clock.now(), KeyPrecision::Nanos);
+        self.db
+            .transact::<_, _, _, FdbError>(
+                (key, now),
+                |tx, (key, now)| {
+                    async move {
+                        if tx.get(key, false).await?.is_none() {
+                            tx.set(key, &pack(now));
+                        }
+                        Ok(())
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// When the room started keeping an index of messages by sender, or None if it doesn't.
+    async fn user_index_since(&self) -> AnyResult<Option<DateTime>> {
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                self.room.as_str(),
+                |tx, room| Session::user_index_since_tx(tx, room).boxed_local(),
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// Record that this session's user is in its room, under ("users", username, "rooms", room),
+    /// so Session::rooms_for_user_indexed finds it without checking every room. Leaving clears
+    /// the record.
+    pub async fn index_room(&self) -> AnyResult<()> {
+        self.ensure_active()?;
+        let key = pack(&Session::user_room_key(&self.username, &self.room));
+        self.db
+            .transact::<_, _, _, FdbError>(
+                key,
+                |tx, key| {
+                    tx.set(key, &[]);
+                    futures::future::ready(Ok(())).boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The current time, truncated to the precision of this session's keys, so that it matches
+    /// the timestamp that messages written at it are read back with.
+    fn now(&self) -> DateTime {
+        self.clock.now().trunc_subsecs(self.precision.digits())
+    }
+
+    pub async fn clear(db: &dyn StoreDb, room: &str) -> AnyResult<()> {
+        let space = Subspace::from(&("rooms", &room));
+
+        db.transact::<_, _, _, FdbError>(
+            space,
+            |tx, space| {
+                tx.clear_subspace_range(space);
+                futures::future::ready(Ok(())).boxed_local()
+            },
+            CHAT_OPTS,
+        )
+        .await
+    }
+
+    /// Delete the room's messages within scope, returning how many were deleted.
+    ///
+    /// The messages are counted and deleted in one transaction, so this is limited to what can
+    /// be read in one; clear large rooms a range at a time. The room's message and size
+    /// counters, if it has them, are reduced to match.
+    pub async fn clear_with(&self, scope: ClearScope) -> AnyResult<u64> {
+        self.ensure_active()?;
+        let (from, to) = match scope {
+            ClearScope::All => (None, None),
+            ClearScope::Before(dt) => (None, Some(dt)),
+            ClearScope::Range(from, to) => (Some(from), Some(to)),
+        };
+        let (begin, end) = match self.range_keys(from, to) {
+            None => return Ok(0),
+            Some(keys) => keys,
+        };
+
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (begin, end, self.room.as_str(), &*self.codec),
+                |tx, (begin, end, room, codec)| {
+                    async move {
+                        let mut count = 0;
+                        let mut bytes = 0;
+                        let r = RangeOption::from((begin.clone(), end.clone()));
+                        let mut ranges = tx.get_ranges(r, false);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                count += 1;
+                                bytes += kv.value().len() as i64;
+                                let (_, _, _, ts): (String, String, String, String) =
+                                    unpack(kv.key()).context("Unpacking")?;
+                                Session::unindex_message_tx(tx, *codec, room, &ts, kv.value());
+                            }
+                        }
+                        tx.clear_range(begin, end);
+                        Session::uncount_messages_tx(tx, room, count, bytes).await?;
+                        Ok(count as u64)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// The cursor of the room's most recent message, or None if it has no messages.
+    ///
+    /// Passing this to MessageIter::from_cursor skips the room's history.
+    pub async fn latest_cursor(&self) -> AnyResult<Option<String>> {
+        self.channel_cursor(None).await
+    }
+
+    /// The cursor of the most recent message sent to channel, or to the room's default channel if
+    /// None, or None if it has no messages.
+    async fn channel_cursor(&self, channel: Option<&str>) -> AnyResult<Option<String>> {
+        let recent_key = Session::channel_recent_key(&self.room, channel);
+        let recent = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                recent_key,
+                |tx, recent_key| tx.get(recent_key, true).boxed_local(),
+                CHAT_OPTS,
+            )
+            .await?;
+
+        match recent {
+            None => Ok(None),
+            Some(recent) => {
+                let recent = from_utf8(&recent).context("Parsing most recent message")?;
+                Ok(Some(recent.to_string()))
+            }
+        }
+    }
+
+    /// The room's most recent message, or None if it has no messages.
+    ///
+    /// This is looked up directly from most_recent_message rather than by scanning the room,
+    /// unless that message has since been deleted; then the latest one left is read instead.
+    pub async fn latest(&self) -> AnyResult<Option<(DateTime, String)>> {
+        let recent_key = pack(&Session::message_recent_key(&self.room));
+        let space = Subspace::from(&("rooms", &self.room, "messages"));
+        let msg = self
+            .db
+            .transact::<_, _, _, AnyErr>(
+                (recent_key, &space, &*self.codec),
+                |tx, (recent_key, space, codec)| {
+                    async move {
+                        if let Some(recent) = tx.get(recent_key, true).await? {
+                            let recent =
+                                from_utf8(&recent).context("Parsing most recent message")?;
+                            let key = space.pack(&recent);
+                            if let Some(value) = tx.get(&key, true).await? {
+                                return Ok(Some(Session::parse_message_with(
+                                    *codec, &key, &value,
+                                )?));
+                            }
+                        }
+                        let last = RangeOption {
+                            limit: Some(1),
+                            reverse: true,
+                            ..RangeOption::from(&**space)
+                        };
+                        match tx.get_range(&last, 1, true).await?.first() {
+                            None => Ok(None),
+                            Some(kv) => Ok(Some(Session::parse_kv_with(*codec, kv)?)),
+                        }
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(msg.map(|msg| (msg.ts, msg.text_lossy().into_owned())))
+    }
+
+    /// Everything a client shows on joining: who is here, how many messages are new since cursor
+    /// (as returned by MessageIter::cursor, or all of them if None), and when the latest was sent.
+    ///
+    /// This is read in one transaction, so the parts are consistent with each other. Counting
+    /// unread messages reads each of them, so it is slow after a long absence from a busy room.
+    pub async fn join_summary(&self, cursor: Option<&str>) -> AnyResult<JoinSummary> {
+        let users = Subspace::from(&("rooms", &self.room, "users"));
+        let (begin, end) = Subspace::from(&("rooms", &self.room, "messages")).range();
+        let begin = match cursor {
+            None => KeySelector::first_greater_or_equal(begin),
+            Some(c) => KeySelector::first_greater_than(pack(&("rooms", &self.room, "messages", c))),
+        };
+        let unread = RangeOption::from((begin, KeySelector::first_greater_or_equal(end)));
+        let recent_key = pack(&Session::message_recent_key(&self.room));
+
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (RangeOption::from(&users), unread, recent_key),
+                |tx, (users, unread_range, recent_key)| {
+                    async move {
+                        let mut roster = Vec::new();
+                        let mut ranges = tx.get_ranges(users.clone(), true);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                let (_, _, _, user): (String, String, String, String) =
+                                    unpack(kv.key()).context("Unpacking")?;
+                                roster.push(user);
+                            }
+                        }
+
+                        let mut unread = 0;
+                        let mut ranges = tx.get_ranges(unread_range.clone(), true);
+                        while let Some(kvs) = ranges.next().await {
+                            unread += kvs?.len() as u64;
+                        }
+
+                        let latest = match tx.get(recent_key, true).await? {
+                            None => None,
+                            Some(recent) => {
+                                let recent = from_utf8(&recent).context("Parsing most recent")?;
+                                let recent = chrono::DateTime::parse_from_rfc3339(recent)
+                                    .context("Parsing date")?;
+                                Some(DateTime::from(recent))
+                            }
+                        };
+
+                        Ok(JoinSummary {
+                            roster,
+                            unread,
+                            latest,
+                        })
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// The database's current read version, e.g. to pin reads to with Session::at_version.
+    pub async fn read_version(&self) -> AnyResult<i64> {
+        let version = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                (),
+                |tx, ()| tx.get_read_version().boxed_local(),
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(version)
+    }
+
+    /// A read-only view of the room as it was at the given database version (as from
+    /// Session::read_version), which stays the same however much is written meanwhile.
+    ///
+    /// The cluster only keeps about five seconds of history, so reads through the view fail with
+    /// transaction_too_old once the version is older than that. Long reads have to finish within
+    /// that window, or be split up and re-pinned to a newer version.
+    pub fn at_version(&self, version: i64) -> SessionView<'_> {
+        SessionView {
+            session: self,
+            version,
+        }
+    }
+
+    /// The read version this client sees, and how long ago the room's latest message was sent by
+    /// the local clock, for telling cluster lag apart from missed watches.
+    pub async fn lag(&self) -> AnyResult<Lag> {
+        let recent_key = pack(&Session::message_recent_key(&self.room));
+        let (read_version, recent) = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                recent_key,
+                |tx, recent_key| {
+                    async move {
+                        let read_version = tx.get_read_version().await?;
+                        let recent = tx.get(recent_key, true).await?;
+                        Ok((read_version, recent))
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        let latest = match recent {
+            None => None,
+            Some(recent) => {
+                let recent = from_utf8(&recent).context("Parsing most recent message")?;
+                let recent =
+                    chrono::DateTime::parse_from_rfc3339(recent).context("Parsing date")?;
+                Some(DateTime::from(recent))
+            }
+        };
+        Ok(Lag {
+            read_version,
+            latest,
+            since_latest: latest.map(|latest| self.clock.now() - latest),
+        })
+    }
+
+    /// How far the local clock is behind the timestamp of the room's most recent message, or None
+    /// if the room has no messages or the local clock is not behind.
+    ///
+    /// Messages are ordered by their senders' clocks, so a client whose clock is behind writes
+    /// messages that sort before ones already sent.
+    pub async fn clock_skew(&self) -> AnyResult<Option<Duration>> {
+        let recent = match self.latest_cursor().await? {
+            None => return Ok(None),
+            Some(recent) => recent,
+        };
+        let recent = chrono::DateTime::parse_from_rfc3339(&recent).context("Parsing date")?;
+        let behind = DateTime::from(recent) - self.clock.now();
+
+        Ok(behind.to_std().ok().filter(|d| *d > Duration::ZERO))
+    }
+
+    /// Record that room is used case-insensitively, under display_name as it was first typed,
+    /// unless that was already recorded.
+    pub async fn mark_case_insensitive(&self, display_name: &str) -> AnyResult<()> {
+        let key = pack(&Session::display_name_key(&self.room));
+        self.db
+            .transact::<_, _, _, FdbError>(
+                (key, display_name),
+                |tx, (key, display_name)| {
+                    async move {
+                        if tx.get(key, false).await?.is_none() {
+                            tx.set(key, display_name.as_bytes());
+                        }
+                        Ok(())
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The display name of a room used case-insensitively, or None if it is case-sensitive.
+    pub async fn case_insensitive_name(db: &dyn StoreDb, room: &str) -> AnyResult<Option<String>> {
+        let key = pack(&Session::display_name_key(room));
+        let name = db
+            .transact::<_, _, _, FdbError>(
+                key,
+                |tx, key| tx.get(key, true).boxed_local(),
+                CHAT_OPTS,
+            )
+            .await?;
+
+        match name {
+            None => Ok(None),
+            Some(name) => Ok(Some(
+                from_utf8(&name).context("Parsing room name")?.to_string(),
+            )),
+        }
+    }
+
+    fn display_name_key(room: &str) -> (&str, &str, &str) {
+        ("rooms", room, "display_name")
+    }
+
+    /// Whether the room has any messages or users, checked without joining it.
+    pub async fn room_exists(db: &dyn StoreDb, room: &str) -> AnyResult<bool> {
+        let space = Subspace::from(&("rooms", room));
+        let r = RangeOption {
+            limit: Some(1),
+            ..RangeOption::from(&space)
+        };
+
+        let exists = db
+            .transact::<_, _, _, FdbError>(
+                r,
+                |tx, r| {
+                    async move {
+                        let kvs = tx.get_range(r, 1, true).await?;
+                        Ok(!kvs.is_empty())
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(exists)
+    }
+
+    /// List every room with any messages or users, checked without joining any of them.
+    pub async fn rooms(db: &dyn StoreDb) -> AnyResult<Vec<String>> {
+        db.transact::<_, _, _, AnyErr>((), |tx, ()| Firehose::rooms(tx).boxed_local(), CHAT_OPTS)
+            .await
+    }
+
+    /// The usernames of everyone in room, in order, checked without joining it.
+    pub async fn users(db: &dyn StoreDb, room: &str) -> AnyResult<Vec<String>> {
+        let space = Subspace::from(&("rooms", room, "users"));
+        db.transact::<_, _, _, AnyErr>(
+            &space,
+            |tx, space| {
+                async move {
+                    let mut users = Vec::new();
+                    let mut ranges = tx.get_ranges(RangeOption::from(&**space), true);
+                    while let Some(kvs) = ranges.next().await {
+                        for kv in kvs?.iter() {
+                            users.push(space.unpack::<String>(kv.key()).context("Unpacking")?);
+                        }
+                    }
+                    Ok(users)
+                }
+                .boxed_local()
+            },
+            CHAT_OPTS,
+        )
+        .await
+    }
+
+    /// The rooms username is in, checked without joining any of them, by looking for the username
+    /// in every room. This is slow with many rooms; see Session::rooms_for_user_indexed.
+    pub async fn rooms_for_user(db: &dyn StoreDb, username: &str) -> AnyResult<Vec<String>> {
+        db.transact::<_, _, _, AnyErr>(
+            username,
+            |tx, username| {
+                async move {
+                    let rooms = Firehose::rooms(tx).await?;
+                    let held = futures::future::try_join_all(
+                        rooms
+                            .iter()
+                            .map(|room| tx.get(&Session::user_key_bytes(room, username), true)),
+                    )
+                    .await?;
+                    Ok(rooms
+                        .into_iter()
+                        .zip(held)
+                        .filter_map(|(room, id)| id.map(|_| room))
+                        .collect())
+                }
+                .boxed_local()
+            },
+            CHAT_OPTS,
+        )
+        .await
+    }
+
+    /// The rooms username is in, as recorded by Session::index_room, checked without joining any
+    /// of them.
+    ///
+    /// Only rooms joined by sessions that called index_room are found. Each recorded room is
+    /// checked for the username too, so rooms cleared or renamed since are left out.
+    pub async fn rooms_for_user_indexed(
+        db: &dyn StoreDb,
+        username: &str,
+    ) -> AnyResult<Vec<String>> {
+        let space = Subspace::from(&("users", username, "rooms"));
+        db.transact::<_, _, _, AnyErr>(
+            (&space, username),
+            |tx, (space, username)| {
+                async move {
+                    let mut rooms = Vec::new();
+                    let mut ranges = tx.get_ranges(RangeOption::from(&**space), true);
+                    while let Some(kvs) = ranges.next().await {
+                        for kv in kvs?.iter() {
+                            rooms.push(space.unpack::<String>(kv.key()).context("Unpacking")?);
+                        }
+                    }
+                    let held = futures::future::try_join_all(
+                        rooms
+                            .iter()
+                            .map(|room| tx.get(&Session::user_key_bytes(room, username), true)),
+                    )
+                    .await?;
+                    Ok(rooms
+                        .into_iter()
+                        .zip(held)
+                        .filter_map(|(room, id)| id.map(|_| room))
+                        .collect())
+                }
+                .boxed_local()
+            },
+            CHAT_OPTS,
+        )
+        .await
+    }
+
+    /// Move everything in room old (messages, users, stats, and config) to room new, which must
+    /// not exist yet.
+    ///
+    /// Keys are moved RENAME_BATCH_SIZE at a time, each batch in its own transaction, so rooms of
+    /// any size can be moved. The move is recorded under ("renames", old) until it is done, so if
+    /// it is interrupted, renaming old to new again picks up where it left off. Sessions in
+    /// either room while it is being moved see it partly moved, so it is best done while empty.
+    pub async fn rename_room(db: &dyn StoreDb, old: &str, new: &str) -> AnyResult<()> {
+        if old == new {
+            return Err(anyhow::format_err!("Room {} can't be renamed to itself", old).into());
+        }
+        let marker = pack(&("renames", old));
+
+        db.transact::<_, _, _, AnyErr>(
+            (&marker, old, new),
+            |tx, (marker, old, new)| {
+                async move {
+                    match tx.get(marker, false).await? {
+                        Some(target) if unpack::<String>(&target).ok().as_deref() == Some(*new) => {
+                            log::info!("Resuming renaming room {} to {}", old, new);
+                            return Ok(());
+                        }
+                        Some(_) => {
+                            return Err(anyhow::format_err!(
+                                "Room {} is already being renamed to another room",
+                                old
+                            )
+                            .into())
+                        }
+                        None => {}
+                    }
+
+                    let first_key = |room: &str| RangeOption {
+                        limit: Some(1),
+                        ..RangeOption::from(&Subspace::from(&("rooms", room)))
+                    };
+                    if !tx.get_range(&first_key(new), 1, false).await?.is_empty() {
+                        return Err(anyhow::format_err!("Room {} already exists", new).into());
+                    }
+                    if tx.get_range(&first_key(old), 1, false).await?.is_empty() {
+                        return Err(anyhow::format_err!("Room {} doesn't exist", old).into());
+                    }
+                    tx.set(marker, &pack(new));
+                    Ok(())
+                }
+                .boxed_local()
+            },
+            CHAT_OPTS,
+        )
+        .await?;
+
+        let old_space = Subspace::from(&("rooms", old));
+        let new_space = Subspace::from(&("rooms", new));
+        loop {
+            let moved = db
+                .transact::<_, _, _, FdbError>(
+                    (&old_space, &new_space),
+                    |tx, (old_space, new_space)| {
+                        async move {
+                            let r = RangeOption {
+                                limit: Some(RENAME_BATCH_SIZE),
+                                ..RangeOption::from(&**old_space)
+                            };
+                            let kvs = tx.get_range(&r, 1, false).await?;
+                            let prefix_len = old_space.bytes().len();
+                            for kv in kvs.iter() {
+                                let mut key = new_space.bytes().to_vec();
+                                key.extend_from_slice(&kv.key()[prefix_len..]);
+                                tx.set(&key, kv.value());
+                            }
+                            if let Some(last) = kvs.last() {
+                                // Up to and including the last key moved
+                                let mut end = last.key().to_vec();
+                                end.push(0);
+                                tx.clear_range(old_space.range().0.as_slice(), &end);
+                            }
+                            Ok(kvs.len())
+                        }
+                        .boxed_local()
+                    },
+                    CHAT_OPTS,
+                )
+                .await?;
+            log::debug!("Moved {} keys from room {} to {}", moved, old, new);
+            if moved == 0 {
+                break;
+            }
+        }
+
+        db.transact::<_, _, _, FdbError>(
+            &marker,
+            |tx, marker| {
+                tx.clear(marker);
+                futures::future::ready(Ok(())).boxed_local()
+            },
+            CHAT_OPTS,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn leave_tx(tx: &dyn Store, id: Uuid, room: &str, username: &str) -> AnyResult<()> {
+        let key = ("rooms", room, "users", username);
+        let keyp = pack(&key);
+        let val = tx.get(&keyp, true).await?;
+
+        let (dbid, _) = match val {
+            Some(v) => Session::parse_user_value(&v)?,
+            None => return Err(anyhow::format_err!("Key is unset somehow").into()),
+        };
+
+        if dbid != id {
+            return Err(anyhow::format_err!("Unexpected ID").into());
+        }
+
+        tx.clear(&keyp);
+        // Whether or not the room was indexed
+        tx.clear(&pack(&Session::user_room_key(username, room)));
+
+        Ok(())
+    }
+
+    /// Wait for every write made through this session to be committed, so that nothing is lost
+    /// by leaving straight afterwards.
+    ///
+    /// Each write is committed before it returns, so there is nothing to wait for; this is here
+    /// so that shutdown doesn't need to change if writes are ever buffered.
+    pub async fn flush(&self) -> AnyResult<()> {
+        Ok(())
+    }
+
+    /// Leave the chat room and close the session. Leaving an inactive session does nothing.
+    pub async fn leave(&mut self) -> AnyResult<()> {
+        let id = match self.id {
+            None => return Ok(()),
+            Some(id) => id,
+        };
+        self.db
+            .transact(
+                (self.room.as_str(), self.username.as_str(), id),
+                |tx: &dyn Store, (room, username, id)| {
+                    Session::leave_tx(tx, *id, room, username).boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+        self.id = None;
+        Event::Left {
+            room: &self.room,
+            username: &self.username,
+        }
+        .emit();
+
+        Ok(())
+    }
+
+    /// The ID under which this session holds its username, if it is still active.
+    pub fn id(&self) -> Option<Uuid> {
+        self.id
+    }
+
+    /// Whether this session still holds its username, i.e. it has not yet left the room.
+    pub fn is_active(&self) -> bool {
+        self.id.is_some()
+    }
+
+    /// Whether this session still holds its username, checked against the database: false if it
+    /// has left, or if another session has since reclaimed the username.
+    pub async fn verify_ownership(&self) -> AnyResult<bool> {
+        let id = match self.id {
+            None => return Ok(false),
+            Some(id) => id,
+        };
+        let key = pack(&Session::user_key(&self.room, &self.username));
+        let held = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                key,
+                |tx, key| tx.get(key, true).boxed_local(),
+                CHAT_OPTS,
+            )
+            .await?;
+
+        let held = held.and_then(|held| Session::parse_user_value(&held).ok());
+        Ok(held.map(|(held, _)| held) == Some(id))
+    }
+
+    /// When user joined the room, and when they last sent a message to it, or None if they aren't
+    /// in the room.
+    ///
+    /// The last message is found with Session::read_from_user, so in rooms without an index by
+    /// sender, this reads back through the room until it finds one.
+    pub async fn user_info(&self, user: &str) -> AnyResult<Option<UserInfo>> {
+        let key = pack(&Session::user_key(&self.room, user));
+        let held = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                key,
+                |tx, key| tx.get(key, true).boxed_local(),
+                CHAT_OPTS,
+            )
+            .await?;
+        let joined = match held {
+            None => return Ok(None),
+            Some(held) => Session::parse_user_value(&held)?.1,
+        };
+
+        let last_message = self.read_from_user(user, 1).await?.first().map(|m| m.ts);
+        Ok(Some(UserInfo {
+            username: user.to_string(),
+            joined,
+            last_message,
+        }))
+    }
+
+    /// Returns a SessionInactive error if this session has left its room.
+    fn ensure_active(&self) -> AnyResult<()> {
+        if self.is_active() {
+            return Ok(());
+        }
+
+        Err(anyhow::Error::from(SessionInactive {
+            room: self.room.clone(),
+            username: self.username.clone(),
+        })
+        .into())
+    }
+
+    /// The key of the message sent to room at dt, written with precision, as a tuple.
+    pub fn message_key(
+        room: &str,
+        dt: DateTime,
+        precision: KeyPrecision,
+    ) -> (&str, &str, &str, String) {
+        (
+            "rooms",
+            room,
+            "messages",
+            Session::date_string(dt, precision),
+        )
+    }
+
+    /// The packed key of the message sent to room at dt, written with precision.
+    pub fn message_key_bytes(room: &str, dt: DateTime, precision: KeyPrecision) -> Vec<u8> {
+        pack(&Session::message_key(room, dt, precision))
+    }
+
+    /// The subspace holding every message sent to room, for range scans decoded with
+    /// Session::decode_kv.
+    pub fn messages_subspace(room: &str) -> Subspace {
+        Subspace::from(&("rooms", room, "messages"))
+    }
+
+    fn message_recent_key(room: &str) -> (&str, &str, &str) {
+        ("rooms", room, "most_recent_message")
+    }
+
+    /// The subspace holding the messages sent to channel in room, or to the room itself (its
+    /// default channel) if None.
+    pub fn channel_subspace(room: &str, channel: Option<&str>) -> Subspace {
+        match channel {
+            None => Session::messages_subspace(room),
+            Some(channel) => Subspace::from(&("rooms", room, "channels", channel, "messages")),
+        }
+    }
+
+    /// The packed key holding the timestamp of the latest message sent to channel in room, or
+    /// to the room's default channel if None, watched for new messages there.
+    fn channel_recent_key(room: &str, channel: Option<&str>) -> Vec<u8> {
+        match channel {
+            None => pack(&Session::message_recent_key(room)),
+            Some(channel) => pack(&("rooms", room, "channels", channel, "most_recent_message")),
+        }
+    }
+
+    /// Set on every write to any room, to the room and timestamp of the message, so that the
+    /// firehose can watch all rooms at once.
+    fn message_recent_any_key() -> (&'static str,) {
+        ("most_recent_any",)
+    }
+
+    /// Counts every message ever written to the room, so that readers can tell whether anything
+    /// was written since they last looked. Unlike the message count in stats, deleting messages
+    /// doesn't lower it.
+    fn write_count_key(room: &str) -> (&str, &str, &str) {
+        ("rooms", room, "write_count")
+    }
+
+    /// The key counting changes to the room's reactions, which clients watch to refresh them.
+    fn reactions_version_key(room: &str) -> (&str, &str, &str) {
+        ("rooms", room, "reactions_version")
+    }
+
+    /// Bump the room's reactions version, for a reaction added or taken back.
+    fn bump_reactions_version_tx(tx: &dyn Store, room: &str) {
+        let key = pack(&Session::reactions_version_key(room));
+        tx.atomic_op(&key, &1i64.to_le_bytes(), MutationType::Add);
+    }
+
+    /// Add count to the room's write count, for count messages written.
+    fn count_writes_tx(tx: &dyn Store, room: &str, count: i64) {
+        let key = pack(&Session::write_count_key(room));
+        tx.atomic_op(&key, &count.to_le_bytes(), MutationType::Add);
+    }
+
+    fn stats_key<'a>(room: &'a str, stat: &'a str) -> (&'a str, &'a str, &'a str, &'a str) {
+        ("rooms", room, "stats", stat)
+    }
+
+    /// Add to the room's message count and total message size (or subtract, for negative values),
+    /// which are kept as atomic counters so that concurrent writers don't conflict over them.
+    fn count_messages_tx(tx: &dyn Store, room: &str, count: i64, bytes: i64) {
+        let count_key = pack(&Session::stats_key(room, "messages"));
+        let bytes_key = pack(&Session::stats_key(room, "bytes"));
+        tx.atomic_op(&count_key, &count.to_le_bytes(), MutationType::Add);
+        tx.atomic_op(&bytes_key, &bytes.to_le_bytes(), MutationType::Add);
+    }
+
+    /// Subtract deleted messages from the room's counters, if it has them. Without counters,
+    /// room_stats scans the room instead, so this doesn't start them.
+    async fn uncount_messages_tx(
+        tx: &dyn Store,
+        room: &str,
+        count: i64,
+        bytes: i64,
+    ) -> FdbResult<()> {
+        let count_key = pack(&Session::stats_key(room, "messages"));
+        if count > 0 && tx.get(&count_key, false).await?.is_some() {
+            Session::count_messages_tx(tx, room, -count, -bytes);
+        }
+        Ok(())
+    }
+
+    /// Add an entry to the activity log for each message written to room at dt_keys, and prune the
+    /// log back to about ACTIVITY_LOG_LIMIT entries.
+    ///
+    /// Entries are keyed by versionstamp, so they are in commit order across all rooms, even when
+    /// their senders' clocks disagree.
+    async fn record_activity_tx(tx: &dyn Store, room: &str, dt_keys: &[&str]) -> AnyResult<()> {
+        for (i, dt_key) in dt_keys.iter().enumerate() {
+            let user_version = u16::try_from(i).context("Too many messages in one transaction")?;
+            let key = pack_with_versionstamp(&("activity", Versionstamp::incomplete(user_version)));
+            tx.atomic_op(
+                &key,
+                &pack(&(room, *dt_key)),
+                MutationType::SetVersionstampedKey,
+            );
+        }
+
+        // Entries written above aren't visible to reads, so this keeps the older entries that
+        // still fit; a snapshot read, so that concurrent writers don't conflict over pruning
+        let (begin, end) = Subspace::from(&("activity",)).range();
+        let oldest_kept = KeySelector::new(end.into(), false, 1 - ACTIVITY_LOG_LIMIT);
+        let oldest_kept = tx.get_key(&oldest_kept, true).await?;
+        if oldest_kept > begin {
+            tx.clear_range(&begin, &oldest_kept);
+        }
+
+        Ok(())
+    }
+
+    /// The rooms and timestamps of up to the last limit messages in the activity log, oldest
+    /// first. Only messages written by sessions with activity tracking on are logged.
+    pub async fn recent_activity(
+        db: &dyn StoreDb,
+        limit: usize,
+    ) -> AnyResult<Vec<(String, DateTime)>> {
+        let space = Subspace::from(&("activity",));
+        let r = RangeOption {
+            limit: Some(limit),
+            reverse: true,
+            ..RangeOption::from(&space)
+        };
+
+        let mut activity = db
+            .transact::<_, _, _, AnyErr>(
+                r,
+                |tx, r| {
+                    async move {
+                        let mut activity = Vec::new();
+                        let mut ranges = tx.get_ranges(r.clone(), true);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                let (room, ts): (String, String) =
+                                    unpack(kv.value()).context("Unpacking activity")?;
+                                let ts = chrono::DateTime::parse_from_rfc3339(&ts)
+                                    .context("Parsing date")?;
+                                activity.push((room, DateTime::from(ts)));
+                            }
+                        }
+                        Ok(activity)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        activity.reverse();
+        Ok(activity)
+    }
+
+    pub async fn write(&self, dt: DateTime, message: &str) -> AnyResult<()> {
+        self.write_text(dt, MessageKind::Text, message).await?;
+        Ok(())
+    }
+
+    /// Write a message as Session::write does, but only if the room's latest message is still
+    /// the one sent at expected_recent (or the room is still empty, if None), e.g. as read with
+    /// Session::latest. Otherwise nothing is written, and this fails with RoomChanged.
+    ///
+    /// The check and the write are in one transaction, so a message written by someone else in
+    /// between conflicts with it, and is seen when it retries.
+    pub async fn write_if_unchanged(
+        &self,
+        expected_recent: Option<DateTime>,
+        dt: DateTime,
+        message: &str,
+    ) -> AnyResult<()> {
+        let message = DecodedMessage {
+            kind: MessageKind::Text,
+            sender: Some(self.sender.clone()),
+            encoding: Encoding::Utf8,
+            body: message.as_bytes().to_vec(),
+        };
+        self.write_decoded_if(dt, &message, Some(expected_recent), None)
+            .await?;
+        Ok(())
+    }
+
+    /// Write a message to a channel within the room, e.g. "random" for #random, as read by
+    /// MessageIter::in_channel. Each channel keeps its own messages and latest-message key, so
+    /// those reading the room itself (its default channel) don't see them.
+    pub async fn write_to_channel(
+        &self,
+        channel: &str,
+        dt: DateTime,
+        message: &str,
+    ) -> AnyResult<()> {
+        self.write_text_to(Some(channel), dt, MessageKind::Text, message)
+            .await?;
+        Ok(())
+    }
+
+    /// Write an action message, e.g. "waves" for `/me waves`.
+    pub async fn write_action(&self, dt: DateTime, action: &str) -> AnyResult<()> {
+        self.write_text(dt, MessageKind::Action, action).await?;
+        Ok(())
+    }
+
+    /// Write a message of arbitrary bytes, which need not be valid UTF-8.
+    pub async fn write_bytes(&self, dt: DateTime, body: &[u8]) -> AnyResult<()> {
+        self.write_kind(dt, MessageKind::Text, Encoding::Binary, body)
+            .await?;
+        Ok(())
+    }
+
+    /// Write several text messages in order, in a single transaction.
+    ///
+    /// Each message gets its own timestamp, at least one unit of this session's precision after
+    /// the one before, so none of them overwrite each other.
+    pub async fn write_many(&self, messages: &[&str]) -> AnyResult<()> {
+        self.write_many_at(self.now(), messages).await
+    }
+
+    /// Write several text messages in order, in a single transaction, as Session::write_many,
+    /// with the first sent at dt rather than now.
+    pub async fn write_many_at(&self, dt: DateTime, messages: &[&str]) -> AnyResult<()> {
+        self.ensure_active()?;
+
+        let mut ts = dt;
+        let mut kvs = Vec::with_capacity(messages.len());
+        for message in messages {
+            let key = Session::message_key(&self.room, ts, self.precision);
+            let value = self.codec.encode(&DecodedMessage {
+                kind: MessageKind::Text,
+                sender: Some(self.sender.clone()),
+                encoding: Encoding::Utf8,
+                body: message.as_bytes().to_vec(),
+            });
+            kvs.push((pack(&key), key.3, value));
+            ts = ts + self.precision.unit();
+        }
+        let recent_key = pack(&Session::message_recent_key(&self.room));
+        let recent_any_key = pack(&Session::message_recent_any_key());
+        let room = self.room.as_str();
+        let track_activity = self.track_activity;
+        let sender = self.sender.as_str();
+
+        let first_key = kvs.first().map_or(&[][..], |(key, _, _)| key.as_slice());
+        let span = OpSpan::start("write", room, first_key);
+        let mut attempts = 0;
+        let result = self
+            .db
+            .transact::<_, _, _, AnyErr>(
+                (kvs, recent_key, recent_any_key, room, &mut attempts),
+                move |tx, (kvs, recent_key, recent_any_key, room, attempts)| {
+                    **attempts += 1;
+                    async move {
+                        let indexed = Session::user_index_since_tx(tx, room).await?.is_some();
+                        for (message_key, dt_key, value) in kvs.iter() {
+                            tx.set(message_key, value);
+                            if indexed {
+                                tx.set(&pack(&Session::by_user_key(room, sender, dt_key)), b"");
+                            }
+                        }
+                        if let Some((_, dt_key, _)) = kvs.last() {
+                            tx.set(recent_key, dt_key.as_bytes());
+                            tx.set(recent_any_key, &pack(&(*room, dt_key)));
+                        }
+                        let bytes: usize = kvs.iter().map(|(_, _, value)| value.len()).sum();
+                        Session::count_messages_tx(tx, room, kvs.len() as i64, bytes as i64);
+                        Session::count_writes_tx(tx, room, kvs.len() as i64);
+                        if track_activity {
+                            let dt_keys: Vec<&str> =
+                                kvs.iter().map(|(_, dt_key, _)| dt_key.as_str()).collect();
+                            Session::record_activity_tx(tx, room, &dt_keys).await?;
+                        }
+                        Ok(())
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await;
+        span.end(Some(attempts), &result);
+        result?;
+
+        Ok(())
+    }
+
+    async fn write_text(
+        &self,
+        dt: DateTime,
+        kind: MessageKind,
+        text: &str,
+    ) -> AnyResult<WriteStats> {
+        self.write_kind(dt, kind, Encoding::Utf8, text.as_bytes())
+            .await
+    }
+
+    /// Write text to channel, or to the room's default channel if None.
+    async fn write_text_to(
+        &self,
+        channel: Option<&str>,
+        dt: DateTime,
+        kind: MessageKind,
+        text: &str,
+    ) -> AnyResult<WriteStats> {
+        let message = DecodedMessage {
+            kind,
+            sender: Some(self.sender.clone()),
+            encoding: Encoding::Utf8,
+            body: text.as_bytes().to_vec(),
+        };
+        self.write_decoded_if(dt, &message, None, channel).await
+    }
+
+    async fn write_kind(
+        &self,
+        dt: DateTime,
+        kind: MessageKind,
+        encoding: Encoding,
+        body: &[u8],
+    ) -> AnyResult<WriteStats> {
+        let message = DecodedMessage {
+            kind,
+            sender: Some(self.sender.clone()),
+            encoding,
+            body: body.to_vec(),
+        };
+        self.write_decoded(dt, &message).await
+    }
+
+    /// Write messages as they were read, e.g. from another room, keeping their original senders,
+    /// in a single transaction.
+    ///
+    /// Each message is written at its own timestamp (formatted with this session's precision),
+    /// replacing any message already there, so writing the same messages twice is harmless. The
+    /// room's latest message only moves forward, so importing old messages into a live room
+    /// doesn't make it look older.
+    pub async fn import(&self, messages: &[Message]) -> AnyResult<()> {
+        self.ensure_active()?;
+
+        let mut kvs = Vec::with_capacity(messages.len());
+        for message in messages {
+            let key = Session::message_key(&self.room, message.ts, self.precision);
+            let value = self.codec.encode(&DecodedMessage {
+                kind: message.kind,
+                sender: message.sender.clone(),
+                encoding: message.encoding,
+                body: message.body.clone(),
+            });
+            let index_key = message
+                .sender
+                .as_ref()
+                .map(|sender| pack(&Session::by_user_key(&self.room, sender, &key.3)));
+            kvs.push((pack(&key), key.3, value, index_key));
+        }
+        let recent_key = pack(&Session::message_recent_key(&self.room));
+        let room = self.room.as_str();
+        let track_activity = self.track_activity;
+
+        let first_key = kvs.first().map_or(&[][..], |(key, _, _, _)| key.as_slice());
+        let span = OpSpan::start("import", room, first_key);
+        let mut attempts = 0;
+        let result = self
+            .db
+            .transact::<_, _, _, AnyErr>(
+                (kvs, recent_key, room, &mut attempts),
+                move |tx, (kvs, recent_key, room, attempts)| {
+                    **attempts += 1;
+                    async move {
+                        let indexed = Session::user_index_since_tx(tx, room).await?.is_some();
+                        for (message_key, _, value, index_key) in kvs.iter() {
+                            tx.set(message_key, value);
+                            if let (true, Some(index_key)) = (indexed, index_key) {
+                                tx.set(index_key, b"");
+                            }
+                        }
+                        let latest = kvs.iter().map(|(_, dt_key, _, _)| dt_key).max();
+                        if let Some(dt_key) = latest {
+                            // Timestamps sort as bytes, so this keeps whichever is later
+                            tx.atomic_op(recent_key, dt_key.as_bytes(), MutationType::ByteMax);
+                            let recent_any_key = pack(&Session::message_recent_any_key());
+                            tx.set(&recent_any_key, &pack(&(*room, dt_key)));
+                        }
+                        let bytes: usize = kvs.iter().map(|(_, _, value, _)| value.len()).sum();
+                        Session::count_messages_tx(tx, room, kvs.len() as i64, bytes as i64);
+                        Session::count_writes_tx(tx, room, kvs.len() as i64);
+                        if track_activity {
+                            let dt_keys: Vec<&str> = kvs
+                                .iter()
+                                .map(|(_, dt_key, _, _)| dt_key.as_str())
+                                .collect();
+                            Session::record_activity_tx(tx, room, &dt_keys).await?;
+                        }
+                        Ok(())
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await;
+        span.end(Some(attempts), &result);
+        result?;
+
+        Ok(())
+    }
+
+    async fn write_decoded(&self, dt: DateTime, message: &DecodedMessage) -> AnyResult<WriteStats> {
+        self.write_decoded_if(dt, message, None, None).await
+    }
+
+    /// Write message at dt to channel, or to the room's default channel if None, first checking
+    /// that the room's latest message was sent at expected, if given, and failing with
+    /// RoomChanged if not.
+    ///
+    /// Messages sent to other channels are kept apart from the room's own: they aren't counted in
+    /// its stats, indexed by sender, or seen by the firehose.
+    async fn write_decoded_if(
+        &self,
+        dt: DateTime,
+        message: &DecodedMessage,
+        expected: Option<Option<DateTime>>,
+        channel: Option<&str>,
+    ) -> AnyResult<WriteStats> {
+        self.ensure_active()?;
+
+        let dt_key = Session::date_string(dt, self.precision);
+        let dt_key = dt_key.as_str();
+        let recent_key = Session::channel_recent_key(&self.room, channel);
+        let recent_any = match channel {
+            None => Some((
+                pack(&Session::message_recent_any_key()),
+                pack(&(self.room.as_str(), dt_key)),
+            )),
+            Some(_) => None,
+        };
+        let index_key = match (&message.sender, channel) {
+            (Some(sender), None) => Some(pack(&Session::by_user_key(&self.room, sender, dt_key))),
+            _ => None,
+        };
+        let value = self.codec.encode(message);
+
+        let message_key = Session::channel_subspace(&self.room, channel).pack(&dt_key);
+        let span = OpSpan::start("write", &self.room, &message_key);
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result = self
+            .db
+            .transact::<_, _, _, AnyErr>(
+                (
+                    message_key.as_slice(),
+                    recent_key,
+                    dt_key,
+                    value,
+                    recent_any,
+                    (self.room.as_str(), self.track_activity, index_key, expected),
+                    &mut attempts,
+                ),
+                |tx,
+                 (
+                    message_key,
+                    recent_key,
+                    dt_key,
+                    value,
+                    recent_any,
+                    (room, track_activity, index_key, expected),
+                    attempts,
+                )| {
+                    **attempts += 1;
+                    async move {
+                        if let Some(expected) = *expected {
+                            let found = match tx.get(recent_key, false).await? {
+                                None => None,
+                                Some(recent) => {
+                                    let recent =
+                                        from_utf8(&recent).context("Parsing most recent")?;
+                                    let recent = chrono::DateTime::parse_from_rfc3339(recent)
+                                        .context("Parsing date")?;
+                                    Some(DateTime::from(recent))
+                                }
+                            };
+                            if found != expected {
+                                return Err(anyhow::Error::from(RoomChanged {
+                                    room: room.to_string(),
+                                    expected,
+                                    found,
+                                })
+                                .into());
+                            }
+                        }
+                        tx.set(message_key, value);
+                        tx.set(recent_key, dt_key.as_bytes());
+                        if let Some(index_key) = index_key {
+                            if Session::user_index_since_tx(tx, room).await?.is_some() {
+                                tx.set(index_key, b"");
+                            }
+                        }
+                        // Only the default channel's messages are on the firehose and in stats
+                        if let Some((any_key, any_value)) = recent_any {
+                            tx.set(any_key, any_value);
+                            Session::count_messages_tx(tx, room, 1, value.len() as i64);
+                        }
+                        Session::count_writes_tx(tx, room, 1);
+                        if *track_activity {
+                            Session::record_activity_tx(tx, room, &[*dt_key]).await?;
+                        }
+                        Ok(())
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await;
+        span.end(Some(attempts), &result);
+        result?;
+
+        Ok(WriteStats {
+            attempts,
+            latency: start.elapsed(),
+        })
+    }
+
+    /// messages_or_watch returns a list of messages, or if none are available, a watch that will
+    /// trigger when at least one message is available. Dropping the watch cancels it.
+    ///
+    /// The watch is only returned once the transaction that armed it has committed (see
+    /// WATCH_OPTS), so it is live, and fires on the next write after the messages read.
+    ///
+    /// last: If None, start with the first message; otherwise, start after this message. The key
+    /// for this message is formatted with this session's precision.
+    /// limit: if None, returns all waiting messages; otherwise, returns up to limit messages.
+    pub async fn messages_or_watch(
+        &self,
+        last: Option<DateTime>,
+        limit: Option<usize>,
+    ) -> AnyResult<Result<Vec<(DateTime, String)>, impl Future<Output = FdbResult<()>>>> {
+        let last_key = last.map(|dt| pack(&Session::message_key(&self.room, dt, self.precision)));
+        let (_, msg_res) = self.messages_or_watch_after(last_key, limit, None).await?;
+
+        Ok(msg_res.map(|kvs| {
+            kvs.into_iter()
+                .map(|(_key, msg)| (msg.ts, msg.text_lossy().into_owned()))
+                .collect()
+        }))
+    }
+
+    /// Like messages_or_watch, but reads channel (or the room's default channel, if None),
+    /// starts after the raw key last_key, and returns messages along with their raw keys, and the
+    /// room's write count as of the read (if it has one).
+    ///
+    /// Starting from a raw key rather than a DateTime means that messages written at any
+    /// precision are neither skipped nor repeated.
+    async fn messages_or_watch_after(
+        &self,
+        last_key: Option<Vec<u8>>,
+        limit: Option<usize>,
+        channel: Option<&str>,
+    ) -> AnyResult<(
+        Option<u64>,
+        Result<Vec<(Vec<u8>, Message)>, impl Future<Output = FdbResult<()>>>,
+    )> {
+        let space = Session::channel_subspace(&self.room, channel);
+        let recent_key = Session::channel_recent_key(&self.room, channel);
+        let count_key = pack(&Session::write_count_key(&self.room));
+
+        let mut r: RangeOption = match last_key {
+            None => RangeOption::from(&space),
+            Some(last_key) => {
+                let (_begin, end) = space.range();
+                let ks = KeySelector::first_greater_than(last_key);
+                RangeOption::from((ks, KeySelector::first_greater_or_equal(end)))
+            }
+        };
+
+        r.limit = limit;
+
+        let span = OpSpan::start("read", &self.room, r.begin.key());
+        let kvs = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                (&r, recent_key, count_key),
+                |tx, (r, recent_key, count_key)| {
+                    async move {
+                        let count = tx.get(count_key, true).await?;
+                        // One chunk is enough: callers read on from its last key, and it is
+                        // only empty if the whole range is
+                        let kvs = tx.get_range(r, 1, false).await;
+                        // The watch is armed at the same read version as the range was read, so
+                        // any message too new for this read changes most_recent_message after
+                        // that version, and fires the watch as soon as it commits
+                        match kvs {
+                            Err(e) => Err(e),
+                            Ok(kv) if kv.is_empty() => Ok((count, Err(tx.watch(recent_key)))),
+                            Ok(kv) => Ok((count, Ok(kv))),
+                        }
+                    }
+                    .boxed_local()
+                },
+                WATCH_OPTS,
+            )
+            .await;
+        span.end(None, &kvs);
+        let (count, kvs): (_, Result<Vec<KeyValue>, _>) = kvs?;
+        let count = count.map(|c| Session::counter(&c)).transpose()?;
+
+        match kvs {
+            Ok(kvs) => {
+                let messages = kvs
+                    .iter()
+                    .map(|kv| Ok((kv.key().to_vec(), Session::parse_kv_with(&*self.codec, kv)?)))
+                    .collect::<AnyResult<Vec<_>>>()?;
+                Ok((count, Ok(messages)))
+            }
+            Err(w) => Ok((count, Err(w))),
+        }
+    }
+
+    /// Arm a watch that triggers when the next message is written to this room.
+    async fn watch_recent(&self) -> AnyResult<impl Future<Output = FdbResult<()>>> {
+        let recent_key = pack(&Session::message_recent_key(&self.room));
+
+        let watch = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                recent_key,
+                |tx, recent_key| futures::future::ready(Ok(tx.watch(recent_key))).boxed_local(),
+                WATCH_OPTS,
+            )
+            .await?;
+
+        Ok(watch)
+    }
+
+    /// Wait until a message is written to any of the sessions' rooms, returning the index of the
+    /// session whose room changed first.
+    ///
+    /// Each room's watch is armed in its own transaction, concurrently; the remaining watches are
+    /// cancelled once one fires. Watches only trigger on writes after they are armed, so callers
+    /// should read each room first (e.g. with messages_or_watch) to avoid missing messages.
+    pub async fn watch_any(sessions: &[Session]) -> AnyResult<usize> {
+        if sessions.is_empty() {
+            return Err(anyhow::format_err!("No sessions to watch").into());
+        }
+
+        let watches =
+            futures::future::try_join_all(sessions.iter().map(Session::watch_recent)).await?;
+        let (res, ix, _others) = futures::future::select_all(watches).await;
+        res?;
+
+        Ok(ix)
+    }
+
+    /// Summarize how many messages and users the room has and how much space its messages take.
+    ///
+    /// Counts come from counters maintained on every write; rooms last written before they
+    /// existed have no counters, and their messages are scanned instead, which is slow for large
+    /// rooms. A room with older messages and newer counters undercounts the older messages.
+    pub async fn room_stats(&self) -> AnyResult<RoomStats> {
+        let messages = Subspace::from(&("rooms", &self.room, "messages"));
+        let users = Subspace::from(&("rooms", &self.room, "users"));
+        let count_key = pack(&Session::stats_key(&self.room, "messages"));
+        let bytes_key = pack(&Session::stats_key(&self.room, "bytes"));
+
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (messages, users, count_key, bytes_key, &*self.codec),
+                |tx, (messages, users, count_key, bytes_key, codec)| {
+                    async move {
+                        let first = RangeOption {
+                            limit: Some(1),
+                            ..RangeOption::from(&*messages)
+                        };
+                        let last = RangeOption {
+                            reverse: true,
+                            ..first.clone()
+                        };
+                        let first = tx.get_range(&first, 1, true).await?;
+                        let last = tx.get_range(&last, 1, true).await?;
+                        let oldest = match first.first() {
+                            Some(kv) => Some(Session::parse_kv_with(*codec, kv)?.ts),
+                            None => None,
+                        };
+                        let newest = match last.first() {
+                            Some(kv) => Some(Session::parse_kv_with(*codec, kv)?.ts),
+                            None => None,
+                        };
+
+                        let mut user_count = 0;
+                        let mut ranges = tx.get_ranges(RangeOption::from(&*users), true);
+                        while let Some(kvs) = ranges.next().await {
+                            user_count += kvs?.len();
+                        }
+
+                        let count = tx.get(count_key, true).await?;
+                        let bytes = tx.get(bytes_key, true).await?;
+                        let (message_count, total_bytes) = match (count, bytes) {
+                            (Some(count), Some(bytes)) => {
+                                (Session::counter(&count)?, Session::counter(&bytes)?)
+                            }
+                            _ => {
+                                log::info!("No counters for room, scanning messages");
+                                let (mut count, mut bytes) = (0, 0);
+                                let mut ranges = tx.get_ranges(RangeOption::from(&*messages), true);
+                                while let Some(kvs) = ranges.next().await {
+                                    for kv in kvs?.iter() {
+                                        count += 1;
+                                        bytes += kv.value().len() as u64;
+                                    }
+                                }
+                                (count, bytes)
+                            }
+                        };
+
+                        Ok(RoomStats {
+                            message_count,
+                            total_bytes,
+                            oldest,
+                            newest,
+                            user_count,
+                        })
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// Decode a counter maintained by atomic adds.
+    fn counter(value: &[u8]) -> AnyResult<u64> {
+        let bytes = <[u8; 8]>::try_from(value)
+            .map_err(|_| anyhow::format_err!("Counter is {} bytes, not 8", value.len()))?;
+        Ok(i64::from_le_bytes(bytes).max(0) as u64)
+    }
+
+    /// Tail new messages across every room, yielding each with the name of its room.
+    ///
+    /// This starts with messages sent from now on, and waits on a single key that is bumped by
+    /// every write. That is much heavier than following one room: each time it fires, every room
+    /// is listed and read, so it is meant for an occasional global monitor, not for clients.
+    /// "Now" is read from clock.
+    pub fn firehose<'a>(
+        db: &'a dyn StoreDb,
+        clock: &dyn Clock,
+    ) -> impl Stream<Item = AnyResult<(String, DateTime, String)>> + 'a {
+        let firehose = Firehose {
+            db,
+            start: clock.now(),
+            cursors: HashMap::new(),
+            waiting: VecDeque::new(),
+            backoff: Backoff::default(),
+        };
+
+        futures::stream::unfold(firehose, |mut firehose| async move {
+            let next = firehose.next().await;
+            Some((next, firehose))
+        })
+    }
+
+    /// The message sent at exactly dt, or None if there isn't one.
+    ///
+    /// The key is formatted with this session's precision, so this only finds messages written at
+    /// the same precision (e.g. by this client).
+    pub async fn get_message(&self, dt: DateTime) -> AnyResult<Option<(DateTime, String)>> {
+        let key = pack(&Session::message_key(&self.room, dt, self.precision));
+        let value = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                key,
+                |tx, key| tx.get(key, true).boxed_local(),
+                CHAT_OPTS,
+            )
+            .await?;
+
+        let value = match value {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        let body = self.codec.decode(&value)?.body;
+        let dt = dt.trunc_subsecs(self.precision.digits());
+        Ok(Some((dt, String::from_utf8_lossy(&body).into_owned())))
+    }
+
+    /// React to the message sent at dt with emoji, returning false (and reacting to nothing) if
+    /// there is no such message. Reacting again with the same emoji changes nothing.
+    ///
+    /// As with get_message, dt is formatted with this session's precision.
+    pub async fn react(&self, dt: DateTime, emoji: &str) -> AnyResult<bool> {
+        self.ensure_active()?;
+        let ts = Session::date_string(dt, self.precision);
+        let message_key = pack(&("rooms", &self.room, "messages", &ts));
+        let key = pack(&("rooms", &self.room, "reactions", &ts, &self.username, emoji));
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (message_key, key, self.room.as_str()),
+                |tx, (message_key, key, room)| {
+                    async move {
+                        if tx.get(message_key, true).await?.is_none() {
+                            return Ok(false);
+                        }
+                        tx.set(key, b"");
+                        Session::bump_reactions_version_tx(tx, room);
+                        Ok(true)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// Take back this session's reaction with emoji to the message sent at dt, if there is one.
+    pub async fn unreact(&self, dt: DateTime, emoji: &str) -> AnyResult<()> {
+        self.ensure_active()?;
+        let ts = Session::date_string(dt, self.precision);
+        let key = pack(&("rooms", &self.room, "reactions", &ts, &self.username, emoji));
+        self.db
+            .transact::<_, _, _, FdbError>(
+                (key, self.room.as_str()),
+                |tx, (key, room)| {
+                    tx.clear(key);
+                    Session::bump_reactions_version_tx(tx, room);
+                    futures::future::ready(Ok(())).boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The room's reactions version, which changes whenever anyone reacts or takes a reaction
+    /// back, with a watch that fires on the next change. Dropping the watch cancels it.
+    ///
+    /// The version is read and the watch armed in one transaction, so no change after the read
+    /// is missed. Rooms without reactions yet are at version 0.
+    pub async fn watch_reactions(&self) -> AnyResult<(u64, impl Future<Output = FdbResult<()>>)> {
+        let key = pack(&Session::reactions_version_key(&self.room));
+        let (version, watch) = self
+            .db
+            .transact::<_, _, _, FdbError>(
+                key,
+                |tx, key| {
+                    async move {
+                        let version = tx.get(key, true).await?;
+                        Ok((version, tx.watch(key)))
+                    }
+                    .boxed_local()
+                },
+                WATCH_OPTS,
+            )
+            .await?;
+        let version = version.map(|v| Session::counter(&v)).transpose()?;
+        Ok((version.unwrap_or(0), watch))
+    }
+
+    /// Everyone's reactions to the message sent at dt, as (username, emoji) pairs in order of
+    /// username. A message that doesn't exist (e.g. because it was deleted) has none.
+    ///
+    /// As with get_message, dt is formatted with this session's precision.
+    pub async fn reactions(&self, dt: DateTime) -> AnyResult<Vec<(String, String)>> {
+        let ts = Session::date_string(dt, self.precision);
+        let message_key = pack(&("rooms", &self.room, "messages", &ts));
+        let space = Subspace::from(&("rooms", &self.room, "reactions", &ts));
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (message_key, &space),
+                |tx, (message_key, space)| {
+                    async move {
+                        let mut reactions = Vec::new();
+                        if tx.get(message_key, true).await?.is_none() {
+                            return Ok(reactions);
+                        }
+                        let mut ranges = tx.get_ranges(RangeOption::from(&**space), true);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                let reaction: (String, String) =
+                                    space.unpack(kv.key()).context("Unpacking")?;
+                                reactions.push(reaction);
+                            }
+                        }
+                        Ok(reactions)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// The usernames of everyone who has ever sent a message to the room, in order, whether or not
+    /// they are still present.
+    ///
+    /// This scans every message in the room, in batches. Messages written before senders were
+    /// recorded are skipped.
+    pub async fn historical_users(&self) -> AnyResult<Vec<String>> {
+        let space = Subspace::from(&("rooms", &self.room, "messages"));
+        let users = self
+            .db
+            .transact::<_, _, _, AnyErr>(
+                (RangeOption::from(&space), &*self.codec),
+                |tx, (range, codec)| {
+                    async move {
+                        let mut users = BTreeSet::new();
+                        let mut ranges = tx.get_ranges(range.clone(), true);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                users.extend(codec.decode(kv.value())?.sender);
+                            }
+                        }
+                        Ok(users)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(users.into_iter().collect())
+    }
+
+    fn ephemeral_key<'a>(room: &'a str, ts: &'a str) -> (&'a str, &'a str, &'a str, &'a str) {
+        ("rooms", room, "ephemeral", ts)
+    }
+
+    fn ephemeral_reads_space(room: &str, ts: &str) -> Subspace {
+        Subspace::from(&("rooms", room, "ephemeral_reads", ts))
+    }
+
+    /// Send a message that is deleted once everyone in the room has read it (as recorded by
+    /// Session::read_ephemeral), or after ttl, whichever comes first.
+    ///
+    /// The readers waited for are the room's users as it is sent. Anyone joining afterwards
+    /// isn't waited for, but can still read the message if they do so before it is deleted.
+    /// Readers that don't call read_ephemeral (e.g. without --allow-ephemeral) aren't recorded
+    /// reading it, so it lasts until ttl if any of them were in the room.
+    pub async fn write_ephemeral(&self, dt: DateTime, text: &str, ttl: Duration) -> AnyResult<()> {
+        self.ensure_active()?;
+        let ts = Session::date_string(dt, self.precision);
+        let expires = dt + chrono::Duration::from_std(ttl).context("Expiry too far away")?;
+        let key = pack(&Session::ephemeral_key(&self.room, &ts));
+        let users = Subspace::from(&("rooms", &self.room, "users"));
+
+        // Recorded before the message is written, so that no one reads it without knowing to
+        // delete it; if the write then fails, the record is swept up once it expires
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (key, RangeOption::from(&users), expires.to_rfc3339()),
+                |tx, (key, users, expires)| {
+                    async move {
+                        let mut readers = Vec::new();
+                        let mut ranges = tx.get_ranges(users.clone(), true);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                let (_, _, _, user): (String, String, String, String) =
+                                    unpack(kv.key()).context("Unpacking")?;
+                                readers.push(user);
+                            }
+                        }
+                        tx.set(key, &pack(&(expires.as_str(), readers)));
+                        Ok(())
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        self.write_text(dt, MessageKind::Text, text).await?;
+        Ok(())
+    }
+
+    /// Record that this session has read the message at cursor (as returned by
+    /// MessageIter::cursor), if it is ephemeral, and delete it if everyone it was sent to has now
+    /// read it or it has expired. Returns whether it was deleted.
+    pub async fn read_ephemeral(&self, cursor: &str) -> AnyResult<bool> {
+        self.ensure_active()?;
+        let now = self.clock.now();
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (
+                    self.room.as_str(),
+                    self.username.as_str(),
+                    cursor,
+                    &*self.codec,
+                ),
+                |tx, (room, username, cursor, codec)| {
+                    async move {
+                        let key = pack(&Session::ephemeral_key(room, cursor));
+                        let (expires, readers) = match tx.get(&key, false).await? {
+                            None => return Ok(false),
+                            Some(v) => unpack::<(String, Vec<String>)>(&v)
+                                .context("Unpacking ephemeral message")?,
+                        };
+                        let expires = chrono::DateTime::parse_from_rfc3339(&expires)
+                            .context("Parsing date")?;
+
+                        let reads = Session::ephemeral_reads_space(room, cursor);
+                        let mut read = BTreeSet::new();
+                        read.insert(username.to_string());
+                        let mut ranges = tx.get_ranges(RangeOption::from(&reads), false);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                read.insert(reads.unpack::<String>(kv.key()).context("Unpacking")?);
+                            }
+                        }
+
+                        if expires <= now || readers.iter().all(|reader| read.contains(reader)) {
+                            Session::delete_ephemeral_tx(tx, *codec, room, cursor).await?;
+                            return Ok(true);
+                        }
+                        tx.set(&reads.pack(username), b"");
+                        Ok(false)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// Delete every ephemeral message in the room that has expired, returning how many there were.
+    pub async fn sweep_ephemeral(&self) -> AnyResult<u64> {
+        let now = self.clock.now();
+        let space = Subspace::from(&("rooms", &self.room, "ephemeral"));
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (&space, self.room.as_str(), &*self.codec),
+                |tx, (space, room, codec)| {
+                    async move {
+                        let mut expired = Vec::new();
+                        let mut ranges = tx.get_ranges(RangeOption::from(&**space), false);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                let ts: String = space.unpack(kv.key()).context("Unpacking")?;
+                                let (expires, _readers) =
+                                    unpack::<(String, Vec<String>)>(kv.value())
+                                        .context("Unpacking ephemeral message")?;
+                                let expires = chrono::DateTime::parse_from_rfc3339(&expires)
+                                    .context("Parsing date")?;
+                                if expires <= now {
+                                    expired.push(ts);
+                                }
+                            }
+                        }
+                        for ts in &expired {
+                            Session::delete_ephemeral_tx(tx, *codec, room, ts).await?;
+                        }
+                        Ok(expired.len() as u64)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// Delete the ephemeral message at ts, along with its record, its reads, and its index entry.
+    async fn delete_ephemeral_tx(
+        tx: &dyn Store,
+        codec: &dyn ValueCodec,
+        room: &str,
+        ts: &str,
+    ) -> FdbResult<()> {
+        let message_key = pack(&("rooms", room, "messages", ts));
+        if let Some(value) = tx.get(&message_key, false).await? {
+            tx.clear(&message_key);
+            Session::unindex_message_tx(tx, codec, room, ts, &value);
+            Session::uncount_messages_tx(tx, room, 1, value.len() as i64).await?;
+        }
+        tx.clear(&pack(&Session::ephemeral_key(room, ts)));
+        tx.clear_subspace_range(&Session::ephemeral_reads_space(room, ts));
+        Ok(())
+    }
+
+    /// Acknowledge having processed the message at cursor (as returned by MessageIter::cursor), so
+    /// that its sender can see it was delivered.
+    ///
+    /// Acks are kept apart from messages, so they don't count towards the room's stats and don't
+    /// wake the firehose.
+    pub async fn ack(&self, cursor: &str) -> AnyResult<()> {
+        self.ensure_active()?;
+        let key = pack(&("rooms", &self.room, "acks", cursor, &self.username));
+        self.db
+            .transact::<_, _, _, FdbError>(
+                key,
+                |tx, key| {
+                    tx.set(key, b"");
+                    futures::future::ready(Ok(())).boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The usernames of everyone who has acknowledged the message sent at dt, in order.
+    ///
+    /// As with get_message, dt is formatted with this session's precision, so this finds acks of
+    /// messages written at the same precision (e.g. by this client).
+    pub async fn acks_for(&self, dt: DateTime) -> AnyResult<Vec<String>> {
+        let ts = Session::date_string(dt, self.precision);
+        let space = Subspace::from(&("rooms", &self.room, "acks", ts));
+        let users = self
+            .db
+            .transact::<_, _, _, AnyErr>(
+                &space,
+                |tx, space| {
+                    async move {
+                        let mut users = Vec::new();
+                        let mut ranges = tx.get_ranges(RangeOption::from(&**space), true);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                let (_, _, _, _, user): (String, String, String, String, String) =
+                                    unpack(kv.key()).context("Unpacking")?;
+                                users.push(user);
+                            }
+                        }
+                        Ok(users)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        Ok(users)
+    }
+
+    /// A cursor over the room's messages after at, or from the beginning if None.
+    ///
+    /// As with MessageIter::new, at is taken to be the timestamp of a message written at this
+    /// session's precision.
+    pub fn cursor(&self, at: Option<DateTime>) -> MessageCursor<'_> {
+        MessageCursor {
+            session: self,
+            last_key: at.map(|dt| pack(&Session::message_key(&self.room, dt, self.precision))),
+            position: at,
+        }
+    }
+
+    /// Read the bodies of all messages in the room as raw bytes.
+    pub async fn read_all_bytes(&self) -> AnyResult<Vec<(DateTime, Vec<u8>)>> {
+        let messages = self.read_all_detailed().await?;
+        Ok(messages.into_iter().map(|msg| (msg.ts, msg.body)).collect())
+    }
+
+    /// Read all messages in the room, along with their storage metadata.
+    pub async fn read_all_detailed(&self) -> AnyResult<Vec<Message>> {
+        let space = Subspace::from(&("rooms", &self.room, "messages"));
+        self.read_messages(RangeOption::from(&space), None).await
+    }
+
+    /// Read the messages sent at or after from, and before to.
+    ///
+    /// Either end may be None to leave it unbounded, and an inverted range (from after to) is
+    /// empty. Bounds compare correctly with keys of any precision, up to the ordering caveat for
+    /// rooms that mix precisions described on KeyPrecision.
+    pub async fn read_range(
+        &self,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+    ) -> AnyResult<Vec<(DateTime, String)>> {
+        let (begin, end) = match self.range_keys(from, to) {
+            None => return Ok(Vec::new()),
+            Some(keys) => keys,
+        };
+
+        let messages = self
+            .read_messages(RangeOption::from((begin, end)), None)
+            .await?;
+        Ok(messages
+            .into_iter()
+            .map(|msg| (msg.ts, msg.text_lossy().into_owned()))
+            .collect())
+    }
+
+    /// Read the messages sent at or after from, and before to, as Session::read_range does, but
+    /// only as many as can be read in one transaction, starting after the message at cursor if
+    /// given.
+    ///
+    /// If the range couldn't be read in full, the result has the cursor to call this with again
+    /// to read the rest; once next is None, every message has been read.
+    pub async fn read_range_paged(
+        &self,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+        cursor: Option<&str>,
+    ) -> AnyResult<ReadResult> {
+        self.read_range_paged_at(from, to, cursor, None).await
+    }
+
+    /// Session::read_range_paged, reading as of the given database version if there is one.
+    async fn read_range_paged_at(
+        &self,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+        cursor: Option<&str>,
+        version: Option<i64>,
+    ) -> AnyResult<ReadResult> {
+        let (begin, end) = match self.range_keys(from, to) {
+            None => {
+                return Ok(ReadResult {
+                    messages: Vec::new(),
+                    next: None,
+                })
+            }
+            Some(keys) => keys,
+        };
+        let begin = match cursor {
+            None => KeySelector::first_greater_or_equal(begin),
+            Some(c) => {
+                let key = pack(&("rooms", &self.room, "messages", c));
+                KeySelector::first_greater_than(key.max(begin))
+            }
+        };
+        let range = RangeOption::from((begin, KeySelector::first_greater_or_equal(end)));
+
+        let budget = self.paged_read_budget;
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (range, &*self.codec),
+                |tx, (range, codec)| {
+                    async move {
+                        if let Some(version) = version {
+                            tx.set_read_version(version);
+                        }
+                        let started = Instant::now();
+                        let mut messages = Vec::new();
+                        let mut last_key = None;
+                        let mut complete = true;
+                        let mut ranges = tx.get_ranges(range.clone(), false);
+                        while let Some(kvs) = ranges.next().await {
+                            let kvs = match kvs {
+                                Ok(kvs) => kvs,
+                                // Keep what was read, and continue in a new transaction
+                                Err(e) if e.code() == TRANSACTION_TOO_OLD && last_key.is_some() => {
+                                    complete = false;
+                                    break;
+                                }
+                                Err(e) => return Err(e.into()),
+                            };
+                            for kv in kvs.iter() {
+                                messages.push(Session::parse_kv_with(*codec, kv)?);
+                                last_key = Some(kv.key().to_vec());
+                            }
+                            if started.elapsed() >= budget {
+                                complete = false;
+                                break;
+                            }
+                        }
+
+                        let next = match last_key {
+                            Some(key) if !complete => {
+                                let (_, _, _, ts): (String, String, String, String) =
+                                    unpack(&key).context("Unpacking")?;
+                                Some(ts)
+                            }
+                            _ => None,
+                        };
+                        Ok(ReadResult { messages, next })
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// The keys bounding messages sent at or after from, and before to, or None if the range is
+    /// empty because from is after to.
+    fn range_keys(
+        &self,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        if let (Some(from), Some(to)) = (from, to) {
+            if from >= to {
+                return None;
+            }
+        }
+
+        let space = Subspace::from(&("rooms", &self.room, "messages"));
+        let (space_begin, space_end) = space.range();
+        let begin = match from {
+            None => space_begin,
+            Some(dt) => Session::time_bound_key(&self.room, dt),
+        };
+        let end = match to {
+            None => space_end,
+            Some(dt) => Session::time_bound_key(&self.room, dt),
+        };
+        Some((begin, end))
+    }
+
+    /// Stream the room's messages newest first, starting with the newest sent before the given
+    /// time (or the newest of all, if None), reading REVERSE_PAGE_SIZE messages at a time.
+    ///
+    /// Each page is read in its own transaction, ending just before the oldest key seen so far, so
+    /// scrolling back through a long history never runs into transaction limits.
+    pub fn stream_reverse(
+        &self,
+        before: Option<DateTime>,
+    ) -> impl Stream<Item = AnyResult<Message>> + '_ {
+        let space = Subspace::from(&("rooms", &self.room, "messages"));
+        let (begin, space_end) = space.range();
+        let end = match before {
+            None => space_end,
+            Some(dt) => Session::time_bound_key(&self.room, dt),
+        };
+
+        // The end of the next page to read, or None once the beginning has been reached
+        let state = (Some(end), VecDeque::new());
+        futures::stream::unfold(state, move |(mut end, mut page)| {
+            let begin = begin.clone();
+            async move {
+                loop {
+                    if let Some(msg) = page.pop_front() {
+                        return Some((Ok(msg), (end, page)));
+                    }
+                    let page_end = end.take()?;
+                    let kvs = match self.read_page_reverse(begin.clone(), page_end).await {
+                        Ok(kvs) => kvs,
+                        Err(e) => return Some((Err(e), (None, page))),
+                    };
+                    if kvs.len() == REVERSE_PAGE_SIZE {
+                        end = kvs.last().map(|(key, _)| key.clone());
+                    }
+                    page.extend(kvs.into_iter().map(|(_key, msg)| msg));
+                }
+            }
+        })
+    }
+
+    /// Read up to limit of the messages sent by user, newest first.
+    ///
+    /// Unless the room keeps an index by sender (see Session::enable_user_index), this scans back
+    /// through the room's history with Session::stream_reverse until it has found limit messages
+    /// or reached the beginning, so finding a user who rarely speaks in a busy room reads nearly
+    /// the whole room. With the index, messages since it was enabled are read from it, and only
+    /// older ones need a scan. Messages without a known sender are never included.
+    pub async fn read_from_user(&self, user: &str, limit: usize) -> AnyResult<Vec<Message>> {
+        let mut messages = Vec::new();
+        if limit == 0 {
+            return Ok(messages);
+        }
+
+        let scan_before = match self.user_index_since().await? {
+            None => None,
+            Some(since) => {
+                messages = self.read_user_index(user, since, limit).await?;
+                if messages.len() >= limit {
+                    return Ok(messages);
+                }
+                Some(since)
+            }
+        };
+        let stream = self.stream_reverse(scan_before);
+        pin_mut!(stream);
+        while let Some(msg) = stream.next().await {
+            let msg = msg?;
+            if msg.sender.as_deref() == Some(user) {
+                messages.push(msg);
+                if messages.len() >= limit {
+                    break;
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Read up to limit of the messages user sent since the index by sender was started, newest
+    /// first, by looking them up from the index.
+    ///
+    /// An entry for a message that no longer exists (e.g. if it was deleted by a client that
+    /// couldn't decode it to find the entry), or that was since replaced by someone else's, as
+    /// by an import, is skipped.
+    async fn read_user_index(
+        &self,
+        user: &str,
+        since: DateTime,
+        limit: usize,
+    ) -> AnyResult<Vec<Message>> {
+        let space = Subspace::from(&("rooms", &self.room, "by_user", user));
+        let since = Session::date_string(since, KeyPrecision::Nanos);
+        let begin = pack(&Session::by_user_key(
+            &self.room,
+            user,
+            since.trim_end_matches('Z'),
+        ));
+        let r = RangeOption {
+            limit: Some(limit),
+            reverse: true,
+            ..RangeOption::from((begin, space.range().1))
+        };
+
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (r, &space, self.room.as_str(), &*self.codec),
+                |tx, (r, space, room, codec)| {
+                    async move {
+                        let mut messages = Vec::new();
+                        let mut ranges = tx.get_ranges(r.clone(), true);
+                        while let Some(entries) = ranges.next().await {
+                            for entry in entries?.iter() {
+                                let ts: String = space.unpack(entry.key()).context("Unpacking")?;
+                                let key = pack(&("rooms", *room, "messages", ts));
+                                if let Some(value) = tx.get(&key, true).await? {
+                                    let msg = Session::parse_message_with(*codec, &key, &value)?;
+                                    if msg.sender.as_deref() == Some(user) {
+                                        messages.push(msg);
+                                    }
+                                }
+                            }
+                        }
+                        Ok(messages)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// Read up to REVERSE_PAGE_SIZE messages between begin and end, newest first, along with
+    /// their raw keys.
+    async fn read_page_reverse(
+        &self,
+        begin: Vec<u8>,
+        end: Vec<u8>,
+    ) -> AnyResult<Vec<(Vec<u8>, Message)>> {
+        let r = RangeOption {
+            limit: Some(REVERSE_PAGE_SIZE),
+            reverse: true,
+            ..RangeOption::from((begin, end))
+        };
+
+        self.db
+            .transact::<_, _, _, AnyErr>(
+                (r, &*self.codec),
+                |tx, (r, codec)| {
+                    async move {
+                        // A single get_range can return less than the limit even with more to
+                        // come, which would look like the beginning of the room
+                        let mut page = Vec::new();
+                        let mut ranges = tx.get_ranges(r.clone(), true);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                page.push((kv.key().to_vec(), Session::parse_kv_with(*codec, kv)?));
+                            }
+                        }
+                        Ok(page)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await
+    }
+
+    /// A key that sorts after the keys of all messages before dt, and before the rest.
+    ///
+    /// This is dt at nanosecond precision without the trailing "Z": any key at or after dt either
+    /// has a larger digit or continues with more digits or the "Z", all of which sort later.
+    fn time_bound_key(room: &str, dt: DateTime) -> Vec<u8> {
+        let ts = Session::date_string(dt, KeyPrecision::Nanos);
+        pack(&("rooms", room, "messages", ts.trim_end_matches('Z')))
+    }
+
+    /// Read all messages in the given range, across as many batches as needed.
+    ///
+    /// If version is given, the range is read as of that database version.
+    async fn read_messages(
+        &self,
+        range: RangeOption<'static>,
+        version: Option<i64>,
+    ) -> AnyResult<Vec<Message>> {
+        let span = OpSpan::start("read", &self.room, range.begin.key());
+        let messages = self
+            .db
+            .transact::<_, _, _, AnyErr>(
+                (range, &*self.codec),
+                |tx, (range, codec)| {
+                    async move {
+                        if let Some(version) = version {
+                            tx.set_read_version(version);
+                        }
+                        let mut messages = Vec::new();
+                        let mut ranges = tx.get_ranges(range.clone(), false);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                messages.push(Session::parse_kv_with(*codec, kv)?);
+                            }
+                        }
+                        Ok(messages)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await;
+        span.end(None, &messages);
+        messages
+    }
+
+    /// Decode a message read from this room's messages subspace, e.g. by a range scan of
+    /// Session::messages_subspace, with this session's codec, as in the test
+    /// range_scans_decode_with_the_sessions_codec.
+    pub fn decode_kv(&self, kv: &FdbKeyValue) -> AnyResult<Message> {
+        Session::parse_message_with(&*self.codec, kv.key(), kv.value())
+    }
+
+    fn parse_kv_with(codec: &dyn ValueCodec, kv: &KeyValue) -> AnyResult<Message> {
+        Session::parse_message_with(codec, kv.key(), kv.value())
+    }
+
+    fn parse_message_with(codec: &dyn ValueCodec, key: &[u8], value: &[u8]) -> AnyResult<Message> {
+        let kdt = match unpack::<(String, String, String, String)>(key) {
+            Ok((_, _, _, kdt)) => kdt,
+            // A message sent to a channel within the room
+            Err(_) => {
+                let (_, _, _, _, _, kdt): (String, String, String, String, String, String) =
+                    unpack(key).context("Unpacking")?;
+                kdt
+            }
+        };
+        let fixed_dt = chrono::DateTime::parse_from_rfc3339(&kdt).context("Parsing date")?;
+        let dt = DateTime::from(fixed_dt);
+
+        let DecodedMessage {
+            kind,
+            sender,
+            encoding,
+            body,
+        } = codec.decode(value)?;
+
+        Ok(Message {
+            ts: dt,
+            kind,
+            encoding,
+            body,
+            sender,
+            key_len: key.len(),
+            value_len: value.len(),
+        })
+    }
+}
+
+/// Messages read by Session::read_range_paged.
+#[derive(Debug)]
+pub struct ReadResult {
+    pub messages: Vec<Message>,
+    /// The cursor to read the rest of the range after, or None if it has all been read.
+    pub next: Option<String>,
+}
+
+/// A read-only view of a session's room pinned to one database version, from Session::at_version.
+pub struct SessionView<'a> {
+    session: &'a Session,
+    version: i64,
+}
+
+impl SessionView<'_> {
+    /// The database version this view reads at.
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+
+    /// Read all messages in the room as of this view's version, as Session::read_all_detailed.
+    pub async fn read_all_detailed(&self) -> AnyResult<Vec<Message>> {
+        let space = Subspace::from(&("rooms", &self.session.room, "messages"));
+        self.session
+            .read_messages(RangeOption::from(&space), Some(self.version))
+            .await
+    }
+
+    /// Read the messages sent at or after from, and before to, as of this view's version, as
+    /// Session::read_range.
+    pub async fn read_range(
+        &self,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+    ) -> AnyResult<Vec<(DateTime, String)>> {
+        let (begin, end) = match self.session.range_keys(from, to) {
+            None => return Ok(Vec::new()),
+            Some(keys) => keys,
+        };
+
+        let messages = self
+            .session
+            .read_messages(RangeOption::from((begin, end)), Some(self.version))
+            .await?;
+        Ok(messages
+            .into_iter()
+            .map(|msg| (msg.ts, msg.text_lossy().into_owned()))
+            .collect())
+    }
+
+    /// Read a page of the messages sent at or after from, and before to, as of this view's
+    /// version, as Session::read_range_paged.
+    pub async fn read_range_paged(
+        &self,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+        cursor: Option<&str>,
+    ) -> AnyResult<ReadResult> {
+        self.session
+            .read_range_paged_at(from, to, cursor, Some(self.version))
+            .await
+    }
+}
+
+/// What Session::lag found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lag {
+    pub read_version: i64,
+    /// When the room's latest message was sent, or None if it has no messages.
+    pub latest: Option<DateTime>,
+    /// How long before now that was; negative if the local clock is behind the sender's.
+    pub since_latest: Option<chrono::Duration>,
+}
+
+impl Display for Lag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Read version {}", self.read_version)?;
+        match (self.latest, self.since_latest) {
+            (Some(latest), Some(since)) => write!(
+                f,
+                "; latest message at {}, {} ms ago",
+                latest,
+                since.num_milliseconds()
+            ),
+            _ => write!(f, "; no messages yet"),
+        }
+    }
+}
+
+/// Which of a room's messages Session::clear_with deletes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClearScope {
+    /// Every message.
+    All,
+    /// Messages sent before the given time.
+    Before(DateTime),
+    /// Messages sent at or after the first time, and before the second.
+    Range(DateTime, DateTime),
+}
+
+/// What a room looks like on joining it, as returned by Session::join_summary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinSummary {
+    /// The usernames of everyone in the room, including this session's, in order.
+    pub roster: Vec<String>,
+    /// How many messages were sent after the cursor.
+    pub unread: u64,
+    /// When the most recent message was sent, or None if there are none.
+    pub latest: Option<DateTime>,
+}
+
+impl Display for JoinSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} here: {}; {} unread",
+            self.roster.len(),
+            self.roster.join(", "),
+            self.unread
+        )?;
+        match self.latest {
+            None => write!(f, "; no messages yet"),
+            Some(latest) => write!(f, "; last message at {}", latest),
+        }
+    }
+}
+
+/// How long a user has been in a room, as returned by Session::user_info.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub username: String,
+    /// When the user joined, or None if their client didn't record it.
+    pub joined: Option<DateTime>,
+    /// When the user's latest message still in the room was sent, or None if there are none.
+    pub last_message: Option<DateTime>,
+}
+
+impl Display for UserInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.joined {
+            None => write!(f, "{} is here", self.username)?,
+            Some(joined) => write!(f, "{} joined at {}", self.username, joined)?,
+        }
+        match self.last_message {
+            None => write!(f, "; no messages"),
+            Some(last) => write!(f, "; last message at {}", last),
+        }
+    }
+}
+
+/// A summary of a room's contents, as returned by Session::room_stats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomStats {
+    pub message_count: u64,
+    /// The total size of all message values, in bytes.
+    pub total_bytes: u64,
+    pub oldest: Option<DateTime>,
+    pub newest: Option<DateTime>,
+    /// How many users are currently in the room, including this session.
+    pub user_count: usize,
+}
+
+/// The state behind Session::firehose.
+struct Firehose<'a> {
+    db: &'a dyn StoreDb,
+    /// Messages sent before this in rooms without a cursor yet are skipped.
+    start: DateTime,
+    /// Key of the last message fetched from each room.
+    cursors: HashMap<String, Vec<u8>>,
+    waiting: VecDeque<(String, Message)>,
+    backoff: Backoff,
+}
+
+impl<'a> Firehose<'a> {
+    async fn next(&mut self) -> AnyResult<(String, DateTime, String)> {
+        while self.waiting.is_empty() {
+            match self.fetch().await? {
+                Ok(()) => {}
+                Err(w) => {
+                    log::info!("Firehose: Waiting");
+                    self.backoff.watch(w).await?;
+                }
+            }
+        }
+
+        let (room, msg) = self
+            .waiting
+            .pop_front()
+            .expect("Expected a message after fetching");
+        Ok((room, msg.ts, msg.text_lossy().into_owned()))
+    }
+
+    /// Read the new messages in every room, or return a watch if there are none.
+    async fn fetch(&mut self) -> AnyResult<Result<(), impl Future<Output = FdbResult<()>>>> {
+        let any_key = pack(&Session::message_recent_any_key());
+        let found = self
+            .db
+            .transact::<_, _, _, AnyErr>(
+                (&self.cursors, self.start, any_key),
+                |tx, (cursors, start, any_key)| {
+                    async move {
+                        let mut found = Vec::new();
+                        for room in Firehose::rooms(tx).await? {
+                            let (_begin, end) =
+                                Subspace::from(&("rooms", &room, "messages")).range();
+                            let begin = match cursors.get(&room) {
+                                Some(last_key) => last_key.clone(),
+                                None => Session::time_bound_key(&room, *start),
+                            };
+                            let r = RangeOption::from((
+                                KeySelector::first_greater_than(begin),
+                                KeySelector::first_greater_or_equal(end),
+                            ));
+                            let mut ranges = tx.get_ranges(r, false);
+                            while let Some(kvs) = ranges.next().await {
+                                for kv in kvs?.iter() {
+                                    let msg = Session::parse_kv_with(&TupleCodec, kv)?;
+                                    found.push((room.clone(), kv.key().to_vec(), msg));
+                                }
+                            }
+                        }
+
+                        if found.is_empty() {
+                            return Ok(Err(tx.watch(any_key)));
+                        }
+                        Ok(Ok(found))
+                    }
+                    .boxed_local()
+                },
+                WATCH_OPTS,
+            )
+            .await?;
+
+        let mut found = match found {
+            Ok(found) => found,
+            Err(w) => return Ok(Err(w)),
+        };
+        log::info!("Firehose: Got {} messages", found.len());
+        // Messages come back grouped by room, so interleave them by time
+        found.sort_by_key(|(_, _, msg)| msg.ts);
+        for (room, key, msg) in found {
+            self.cursors.insert(room.clone(), key);
+            self.waiting.push_back((room, msg));
+        }
+
+        Ok(Ok(()))
+    }
+
+    /// List every room, by skipping from each room's first key to the end of its subspace.
+    async fn rooms(tx: &dyn Store) -> AnyResult<Vec<String>> {
+        let (mut begin, end) = Subspace::from(&("rooms",)).range();
+        let mut rooms = Vec::new();
+
+        loop {
+            let key = tx
+                .get_key(&KeySelector::first_greater_or_equal(begin), true)
+                .await?;
+            if key >= end {
+                return Ok(rooms);
+            }
+            let room = match unpack::<Vec<Element>>(&key)
+                .context("Unpacking room key")?
+                .get(1)
+            {
+                Some(Element::String(room)) => room.to_string(),
+                _ => return Err(anyhow::format_err!("Unexpected key in rooms").into()),
+            };
+            begin = Subspace::from(&("rooms", &room)).range().1;
+            rooms.push(room);
+        }
+    }
+}
+
+/// Exponential backoff with jitter, for retrying after the cluster fails transiently.
+///
+/// The nth consecutive retry waits base * 2^n, capped at MAX_DELAY, and then multiplied by a
+/// random factor between 1 - jitter and 1 + jitter. Without jitter, clients that all failed on the
+/// same cluster blip would all retry at the same moments, piling on as it recovers.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    jitter: f64,
+    retries: u32,
+}
+
+impl Backoff {
+    const BASE_DELAY: Duration = Duration::from_millis(50);
+    const MAX_DELAY: Duration = Duration::from_secs(5);
+    const DEFAULT_JITTER: f64 = 0.2;
+
+    /// A backoff with the given jitter fraction, which is clamped to between 0 and 1.
+    pub fn new(jitter: f64) -> Self {
+        Backoff {
+            jitter: if jitter.is_nan() {
+                0.0
+            } else {
+                jitter.clamp(0.0, 1.0)
+            },
+            retries: 0,
+        }
+    }
+
+    /// How long to wait before the next retry.
+    fn next_delay(&mut self) -> Duration {
+        let exp = Backoff::BASE_DELAY
+            .checked_mul(1 << self.retries.min(16))
+            .unwrap_or(Backoff::MAX_DELAY)
+            .min(Backoff::MAX_DELAY);
+        self.retries += 1;
+
+        if self.jitter == 0.0 {
+            return exp;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter, 1.0 + self.jitter);
+        exp.mul_f64(factor)
+    }
+
+    /// Start over from the base delay, after a success.
+    fn reset(&mut self) {
+        self.retries = 0;
+    }
+
+    /// Wait for a watch to fire, returning whether it did.
+    ///
+    /// If the watch failed with a retryable error (e.g. future_version after a recovery), this
+    /// pauses for the next delay and returns false, and the caller should read again, which
+    /// re-arms the watch. Other errors are returned.
+    async fn watch(&mut self, watch: impl Future<Output = FdbResult<()>>) -> FdbResult<bool> {
+        match watch.await {
+            Ok(()) => {
+                self.reset();
+                Ok(true)
+            }
+            Err(e) if e.is_retryable() => {
+                let delay = self.next_delay();
+                log::warn!("Watch failed ({}), re-arming in {:?}", e, delay);
+                async_std::task::sleep(delay).await;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Backoff::DEFAULT_JITTER)
+    }
+}
+
+/// The channel within a room that a chat sends to and reads from, switched with
+/// CHANNEL_COMMAND. None is the room's default channel, where messages go without channels.
+#[derive(Default)]
+pub struct ActiveChannel {
+    name: RefCell<Option<String>>,
+    /// Told of the next switch, to wake MessageIters waiting on the old channel.
+    waiting: RefCell<Vec<futures::channel::oneshot::Sender<()>>>,
+}
+
+impl ActiveChannel {
+    /// The channel now active, or None for the default channel.
+    pub fn get(&self) -> Option<String> {
+        self.name.borrow().clone()
+    }
+
+    /// Switch to channel, or to the default channel if None.
+    pub fn switch(&self, channel: Option<String>) {
+        *self.name.borrow_mut() = channel;
+        for waiting in self.waiting.borrow_mut().drain(..) {
+            // Whoever was waiting may have stopped, which is fine
+            let _ = waiting.send(());
+        }
+    }
+
+    /// Completes on the next switch.
+    fn switched(&self) -> futures::channel::oneshot::Receiver<()> {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let mut waiting = self.waiting.borrow_mut();
+        waiting.retain(|tx| !tx.is_canceled());
+        waiting.push(tx);
+        rx
+    }
+}
+
+/// Iterates over the messages in a room, in order, waiting for new ones once caught up.
+///
+/// Every message is returned exactly once. Each read starts after the key of the last message
+/// read, so nothing is repeated across batches, however they are sized. A read that finds
+/// nothing new arms its watch in the same transaction, and the watch fires on any change to the
+/// room's latest-message key after that transaction's read version, so a message written
+/// between the empty read and the wait still wakes it. What this can't return is a message
+/// written after later ones with an earlier timestamp, as from a sender whose clock is behind;
+/// see set_reorder_window.
+///
+/// While waiting, the only FoundationDB watch is owned by the future returned by next. Dropping
+/// that future (e.g. when it loses a select) or the iterator itself drops the watch, which cancels
+/// it on the cluster, so there is nothing to close explicitly.
+pub struct MessageIter<'a> {
+    session: &'a Session,
+    /// Key of the last message fetched from the database.
+    last_key: Option<Vec<u8>>,
+    /// Key of the last message returned by next.
+    returned_key: Option<Vec<u8>>,
+    waiting: VecDeque<(Vec<u8>, Message)>,
+    /// How long to wait after a watch fires before reading, to batch up bursts of messages.
+    coalesce: Duration,
+    /// How long to wait before re-arming a watch that failed.
+    backoff: Backoff,
+    /// Whether to skip the session's own messages.
+    skip_own: bool,
+    /// How many messages to read at a time from the room's history.
+    batch_size: usize,
+    /// The shortest time between reads after the watch fires, to cap the work done in a flood.
+    min_wake: Duration,
+    /// When the watch last fired and messages were read.
+    last_wake: Option<Instant>,
+    /// How long to hold back new messages, so that late ones from lagging clocks sort into place.
+    reorder_window: Duration,
+    /// The latest timestamp returned by next, to detect messages returned out of order.
+    max_returned: Option<DateTime>,
+    /// Whether to read periodically instead of watching for new messages.
+    polling: bool,
+    /// How long to wait between reads when polling.
+    poll_interval: Duration,
+    /// Whether a read has found no more messages yet, so that all returned since are new.
+    caught_up: bool,
+    /// The room's write count as of the latest read, if it has one.
+    write_count: Option<u64>,
+    /// The channel being read, or None for the room's default channel.
+    channel: Option<String>,
+    /// If set, the channel to read is switched to follow it.
+    active_channel: Option<&'a ActiveChannel>,
+}
+
+impl<'a> MessageIter<'a> {
+    const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+    const DEFAULT_BATCH_SIZE: usize = 3;
+
+    pub fn new(session: &'a Session, last: Option<DateTime>) -> Self {
+        let last_key =
+            last.map(|dt| pack(&Session::message_key(&session.room, dt, session.precision)));
+        MessageIter::after_key(session, last_key)
+    }
+
+    /// Start after the message at cursor, as previously returned by MessageIter::cursor.
+    pub fn from_cursor(session: &'a Session, cursor: Option<&str>) -> Self {
+        let last_key = cursor.map(|c| pack(&("rooms", &session.room, "messages", c)));
+        MessageIter::after_key(session, last_key)
+    }
+
+    /// Start after the last message returned before checkpoint was taken.
+    pub fn resume(session: &'a Session, checkpoint: &Checkpoint) -> Self {
+        MessageIter::from_cursor(session, checkpoint.cursor.as_deref())
+    }
+
+    /// Start with the first message sent at or after since.
+    pub fn since(session: &'a Session, since: DateTime) -> Self {
+        MessageIter::after_key(session, Some(Session::time_bound_key(&session.room, since)))
+    }
+
+    fn after_key(session: &'a Session, last_key: Option<Vec<u8>>) -> Self {
+        MessageIter {
+            session,
+            returned_key: last_key.clone(),
+            last_key,
+            waiting: VecDeque::new(),
+            coalesce: Duration::ZERO,
+            backoff: Backoff::default(),
+            skip_own: false,
+            batch_size: MessageIter::DEFAULT_BATCH_SIZE,
+            min_wake: Duration::ZERO,
+            last_wake: None,
+            reorder_window: Duration::ZERO,
+            max_returned: None,
+            polling: false,
+            poll_interval: MessageIter::DEFAULT_POLL_INTERVAL,
+            caught_up: false,
+            write_count: None,
+            channel: None,
+            active_channel: None,
+        }
+    }
+
+    /// Read the messages sent to channel with Session::write_to_channel, starting after the one
+    /// at last (or from the first, if None), instead of the room's own.
+    pub fn in_channel(session: &'a Session, channel: &str, last: Option<DateTime>) -> Self {
+        let space = Session::channel_subspace(&session.room, Some(channel));
+        let last_key = last.map(|dt| space.pack(&Session::date_string(dt, session.precision)));
+        MessageIter {
+            channel: Some(channel.to_string()),
+            ..MessageIter::after_key(session, last_key)
+        }
+    }
+
+    /// Follow active as it is switched, reading the new channel's messages from the next one sent
+    /// to it. A switch while waiting for messages moves straight to the new channel.
+    ///
+    /// The cursor and checkpoint are then of the channel being read, as returned by
+    /// MessageIter::channel: just after a switch, they are of the new channel's latest message.
+    pub fn follow_channel(&mut self, active: &'a ActiveChannel) {
+        self.active_channel = Some(active);
+    }
+
+    /// The channel being read, or None for the room's default channel.
+    pub fn channel(&self) -> Option<&str> {
+        self.channel.as_deref()
+    }
+
+    /// Move to channel, after its latest message, unless it is the one already being read.
+    async fn switch_channel(&mut self, channel: Option<String>) -> AnyResult<()> {
+        if channel == self.channel {
+            return Ok(());
+        }
+        let latest = self.session.channel_cursor(channel.as_deref()).await?;
+        let space = Session::channel_subspace(&self.session.room, channel.as_deref());
+        self.last_key = latest.map(|c| space.pack(&c));
+        // Nothing in the new channel has been returned, so resuming from here skips its history
+        self.returned_key = self.last_key.clone();
+        self.waiting.clear();
+        self.max_returned = None;
+        self.channel = channel;
+        Ok(())
+    }
+
+    /// Set how long to wait after a watch fires before reading new messages.
+    ///
+    /// In busy rooms, a short window lets several messages arrive and be read together, trading
+    /// a little latency for far fewer transactions. The default of zero reads immediately.
+    pub fn set_coalesce(&mut self, coalesce: Duration) {
+        self.coalesce = coalesce;
+    }
+
+    /// Set how many messages are read at a time while catching up on the room's history, which is
+    /// at least 1. Larger batches catch up in fewer transactions; smaller ones start returning
+    /// messages sooner and hold fewer in memory.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// Set the shortest time between reads of new messages, however often they arrive.
+    ///
+    /// In a sustained flood the watch fires on every write; with a floor, each wake reads all
+    /// messages that arrived since the last one in a single transaction, so the rate of reads
+    /// and re-armed watches stays bounded. The default of zero reads as soon as allowed by
+    /// set_coalesce.
+    pub fn set_min_wake(&mut self, min_wake: Duration) {
+        self.min_wake = min_wake;
+    }
+
+    /// Set how long to hold back each message before returning it, so that messages from senders
+    /// whose clocks are up to that far behind are returned in order rather than skipped.
+    ///
+    /// Messages are read in timestamp order after the last one returned, so one written late with
+    /// an earlier timestamp than that would otherwise never be read. While held back, messages
+    /// aren't returned, so later reads take in any late ones that sort before them. Messages
+    /// later than the window are still missed; next logs any it does return out of order.
+    pub fn set_reorder_window(&mut self, window: Duration) {
+        self.reorder_window = window;
+    }
+
+    /// Set the backoff used when a watch fails and has to be re-armed.
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /// Set whether next skips messages sent under the session's own name. The cursor still moves
+    /// past skipped messages, so resuming from it doesn't return them either.
+    pub fn set_skip_own(&mut self, skip_own: bool) {
+        self.skip_own = skip_own;
+    }
+
+    /// Set how long to wait between reads when polling for new messages.
+    ///
+    /// The iterator polls instead of watching if watches turn out to be unsupported (e.g. they
+    /// are disabled on the cluster), or from the start after set_polling.
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    /// Poll for new messages rather than watching for them.
+    pub fn set_polling(&mut self, polling: bool) {
+        self.polling = polling;
+    }
+
+    /// The position of the last message returned by next, which can be saved and passed to
+    /// MessageIter::from_cursor to resume after it.
+    ///
+    /// This is the exact timestamp string from the message's key, so it resumes correctly
+    /// whatever precision the message was written with.
+    pub fn cursor(&self) -> Option<String> {
+        let key = self.returned_key.as_ref()?;
+        Session::channel_subspace(&self.session.room, self.channel.as_deref())
+            .unpack(key)
+            .ok()
+    }
+
+    /// Whether the iterator has read all the messages that were in the room when it started, so
+    /// that messages returned from now on are new. Until then, they are the room's history
+    /// (along with any sent while it is being read).
+    pub fn caught_up(&self) -> bool {
+        self.caught_up
+    }
+
+    /// How many messages had ever been written to the room as of the iterator's latest read, or
+    /// None before the first read, or if the room was last written before the count was kept.
+    ///
+    /// The count only ever goes up, so comparing it between reads tells how many writes came in
+    /// between, including any that landed before the iterator's position.
+    pub fn write_count(&self) -> Option<u64> {
+        self.write_count
+    }
+
+    /// The position of the iterator, to save and resume from with MessageIter::resume, so that a
+    /// consumer restarted after a crash carries on exactly after the last message it processed.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            cursor: self.cursor(),
+        }
+    }
+
+    pub async fn next(&mut self) -> AnyResult<Message> {
+        loop {
+            if self.waiting.is_empty() {
+                self.fetch().await?;
+            }
+
+            let (key, msg) = self
+                .waiting
+                .pop_front()
+                .expect("Really expected a front message after waiting for watch and extending");
+            self.returned_key = Some(key);
+
+            match self.max_returned {
+                Some(max) if msg.ts < max => log::warn!(
+                    "Message at {} arrived after one at {}; its sender's clock may be behind",
+                    msg.ts,
+                    max
+                ),
+                _ => self.max_returned = Some(msg.ts),
+            }
+
+            if self.skip_own && msg.sender.as_deref() == Some(self.session.sender.as_str()) {
+                continue;
+            }
+            return Ok(msg);
+        }
+    }
+
+    async fn fetch(&mut self) -> AnyResult<()> {
+        // None left in the past; let's see if any are waiting, and wait if they are
+        let mut limit = Some(self.batch_size);
+        // The write count from before the watch fired, once it has
+        let mut woken_from = None;
+        let messages = loop {
+            if let Some(active) = self.active_channel {
+                self.switch_channel(active.get()).await?;
+            }
+            let (write_count, msg_res) = match self
+                .session
+                .messages_or_watch_after(self.last_key.clone(), limit, self.channel.as_deref())
+                .await
+            {
+                // Out of retries reading and arming the watch, e.g. during a recovery: keep
+                // trying, backing off, rather than giving up on the room
+                Err(AnyErr::Fdb(e)) if e.is_retryable() => {
+                    let delay = self.backoff.next_delay();
+                    log::warn!("Reading messages failed ({}), retrying in {:?}", e, delay);
+                    async_std::task::sleep(delay).await;
+                    continue;
+                }
+                msg_res => msg_res?,
+            };
+            if let Some(before) = woken_from.take() {
+                match (before, write_count) {
+                    (Some(before), Some(now)) if now == before => {
+                        log::info!("MessageIter: Woken without any new writes")
+                    }
+                    (Some(before), Some(now)) => {
+                        log::info!(
+                            "MessageIter: Woken by {} writes",
+                            now.saturating_sub(before)
+                        )
+                    }
+                    _ => {}
+                }
+            }
+            self.write_count = write_count;
+            match msg_res {
+                Ok(v) if self.reorder_window > Duration::ZERO => {
+                    let window = chrono::Duration::from_std(self.reorder_window)
+                        .context("Reorder window too long")?;
+                    let cutoff = self.session.now() - window;
+                    let first_ts = v.first().expect("Messages expected").1.ts;
+                    let ready: Vec<_> = v.into_iter().take_while(|(_, m)| m.ts <= cutoff).collect();
+                    if !ready.is_empty() {
+                        log::info!("MessageIter: Got {} messages", ready.len());
+                        break ready;
+                    }
+                    // Read again once the first is old enough, taking in any late messages
+                    let wait = (first_ts - cutoff).to_std().unwrap_or(Duration::ZERO);
+                    log::info!("MessageIter: Holding back messages for {:?}", wait);
+                    async_std::task::sleep(wait).await;
+                }
+                Ok(v) => {
+                    log::info!("MessageIter: Got {} messages", v.len());
+                    break v;
+                }
+                // Dropping the watch unused cancels it
+                Err(_) if self.polling => {
+                    self.caught_up = true;
+                    log::info!("MessageIter: Polling");
+                    async_std::task::sleep(self.poll_interval).await;
+                }
+                Err(w) => {
+                    self.caught_up = true;
+                    log::info!("MessageIter: Waiting");
+                    let recent_key =
+                        Session::channel_recent_key(&self.session.room, self.channel.as_deref());
+                    let span = OpSpan::start("watch", &self.session.room, &recent_key);
+                    let recovering = self.backoff.retries > 0;
+                    let fired = match self.active_channel {
+                        None => self.backoff.watch(w).await,
+                        Some(active) => {
+                            let switched = active.switched();
+                            let watch = self.backoff.watch(w);
+                            pin_mut!(watch);
+                            match select(watch, switched).await {
+                                Either::Left((fired, _)) => fired,
+                                // Dropping the watch cancels it; the next read is of the new
+                                // channel
+                                Either::Right(_) => continue,
+                            }
+                        }
+                    };
+                    span.end(None, &fired);
+                    let fired = match fired {
+                        Err(e) if e.code() == WATCHES_DISABLED || e.code() == TOO_MANY_WATCHES => {
+                            log::warn!(
+                                "Can't watch for messages ({}), polling every {:?} instead",
+                                e,
+                                self.poll_interval
+                            );
+                            self.polling = true;
+                            false
+                        }
+                        fired => fired?,
+                    };
+                    if fired {
+                        let room = self.session.room.as_str();
+                        if recovering {
+                            Event::Reconnected { room }.emit();
+                        }
+                        Event::WatchFired { room }.emit();
+                        // However often the watch fires, wake at most once per min_wake
+                        let since_wake = self.last_wake.map(|wake| wake.elapsed());
+                        let throttle = since_wake
+                            .map_or(Duration::ZERO, |since| self.min_wake.saturating_sub(since));
+                        let pause = self.coalesce.max(throttle);
+                        if pause > Duration::ZERO {
+                            async_std::task::sleep(pause).await;
+                        }
+                        self.last_wake = Some(Instant::now());
+                        woken_from = Some(self.write_count);
+                        // Read everything that arrived meanwhile, so the watch is re-armed once.
+                        // The read is a new transaction, whose read version is taken after the
+                        // watch fired, and so after the write that fired it committed: whatever
+                        // fired the watch is in this read
+                        limit = None;
+                    }
+                }
+            }
+        };
+        let (last_key, _) = messages.last().expect("Messages expected after watch");
+        self.last_key = Some(last_key.clone());
+        self.waiting.extend(messages);
+
+        Ok(())
+    }
+}
+
+/// Where a MessageIter was, as returned by MessageIter::checkpoint.
+///
+/// Messages read ahead into the iterator's buffer but not yet returned aren't part of this:
+/// they are still in the room, so a resumed iterator reads them again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Checkpoint {
+    /// The cursor of the last message returned, or None if none were.
+    pub cursor: Option<String>,
+}
+
+/// Reads a room's messages in batches, for callers that drive reading themselves, as returned by
+/// Session::cursor.
+///
+/// Unlike MessageIter, this never waits for new messages: once caught up, it returns empty
+/// batches until more are written.
+pub struct MessageCursor<'a> {
+    session: &'a Session,
+    /// Key of the last message returned.
+    last_key: Option<Vec<u8>>,
+    position: Option<DateTime>,
+}
+
+impl<'a> MessageCursor<'a> {
+    /// Read up to limit of the messages after the position, moving past them.
+    pub async fn next_batch(&mut self, limit: usize) -> AnyResult<Vec<Message>> {
+        let space = Subspace::from(&("rooms", &self.session.room, "messages"));
+        let (begin, end) = space.range();
+        let begin = match &self.last_key {
+            None => KeySelector::first_greater_or_equal(begin),
+            Some(last_key) => KeySelector::first_greater_than(last_key.clone()),
+        };
+        let r = RangeOption {
+            // A limit of 0 means no limit to FoundationDB
+            limit: Some(limit.max(1)),
+            ..RangeOption::from((begin, KeySelector::first_greater_or_equal(end)))
+        };
+
+        let batch = self
+            .session
+            .db
+            .transact::<_, _, _, AnyErr>(
+                (r, &*self.session.codec),
+                |tx, (r, codec)| {
+                    async move {
+                        let mut batch = Vec::new();
+                        let mut ranges = tx.get_ranges(r.clone(), true);
+                        while let Some(kvs) = ranges.next().await {
+                            for kv in kvs?.iter() {
+                                let msg = Session::parse_kv_with(*codec, kv)?;
+                                batch.push((kv.key().to_vec(), msg));
+                            }
+                        }
+                        Ok(batch)
+                    }
+                    .boxed_local()
+                },
+                CHAT_OPTS,
+            )
+            .await?;
+
+        if let Some((key, msg)) = batch.last() {
+            self.last_key = Some(key.clone());
+            self.position = Some(msg.ts);
+        }
+        Ok(batch.into_iter().map(|(_key, msg)| msg).collect())
+    }
+
+    /// The timestamp of the last message returned, or of the starting point if none have been
+    /// returned yet. Passing it to Session::cursor resumes after it.
+    pub fn position(&self) -> Option<DateTime> {
+        self.position
+    }
+}
+
+/// Client state saved between runs, so that a restarted client keeps its identity and its place in
+/// the room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct State {
+    id: Uuid,
+    room: String,
+    username: String,
+    /// The cursor of the last message printed from the room's default channel, as returned by
+    /// MessageIter::cursor. Other channels aren't saved: a restarted chat starts in the default
+    /// channel, after the last of its messages printed, even if another channel was active.
+    cursor: Option<String>,
+}
+
+impl State {
+    /// The state of an active session whose last printed message is at cursor.
+    fn of(session: &Session, cursor: Option<String>) -> Option<State> {
+        Some(State {
+            id: session.id()?,
+            room: session.room.clone(),
+            username: session.username.clone(),
+            cursor,
+        })
+    }
+
+    fn default_path() -> anyhow::Result<PathBuf> {
+        let home = std::env::var_os("HOME").context("HOME is not set")?;
+        Ok(Path::new(&home).join(".fdbchat").join("state.json"))
+    }
+
+    /// Load the state at path, or None if there is no state saved there yet.
+    async fn load(path: &Path) -> anyhow::Result<Option<State>> {
+        let contents = match async_std::fs::read(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Reading state file {}", path.display()))
+            }
+        };
+        let state = serde_json::from_slice(&contents)
+            .with_context(|| format!("Parsing state file {}", path.display()))?;
+
+        Ok(Some(state))
+    }
+
+    /// Save the state to path, replacing it atomically so a crash can't leave a partial file.
+    async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            async_std::fs::create_dir_all(dir).await?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        async_std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?).await?;
+        async_std::fs::rename(&tmp_path, path)
+            .await
+            .with_context(|| format!("Writing state file {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Defaults for options, loaded from a TOML file with keys named like the options (e.g.
+/// cluster-file). Options given on the command line or in the environment take precedence.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct Config {
+    room: Option<String>,
+    username: Option<String>,
+    cluster_file: Option<PathBuf>,
+    prompt: Option<String>,
+    wrap: Option<usize>,
+    state_file: Option<PathBuf>,
+    /// Keys that aren't options here, which are warned about and otherwise ignored.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, serde::de::IgnoredAny>,
+}
+
+impl Config {
+    fn default_path() -> anyhow::Result<PathBuf> {
+        let home = std::env::var_os("HOME").context("HOME is not set")?;
+        Ok(Path::new(&home).join(".fdbchat").join("config.toml"))
+    }
+
+    /// Load the config at path, or None if there is no file there.
+    async fn load(path: &Path) -> anyhow::Result<Option<Config>> {
+        let contents = match async_std::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Reading config file {}", path.display()))
+            }
+        };
+        let config: Config = toml::from_str(&contents)
+            .with_context(|| format!("Parsing config file {}", path.display()))?;
+        for key in config.unknown.keys() {
+            log::warn!("Ignoring unknown key {:?} in {}", key, path.display());
+        }
+
+        Ok(Some(config))
+    }
+
+    /// Fill in the options not already given in args, or in command for the options that belong
+    /// to it.
+    fn apply(self, args: &mut Args, command: &mut Command) {
+        args.room = args.room.take().or(self.room);
+        args.username = args.username.take().or(self.username);
+        args.cluster_file = args.cluster_file.take().or(self.cluster_file);
+        match command {
+            Command::Chat(chat) => {
+                chat.prompt = chat.prompt.take().or(self.prompt.map(Some));
+                chat.follow.wrap = chat.follow.wrap.or(self.wrap);
+                chat.state_file = chat.state_file.take().or(self.state_file.map(Some));
+            }
+            Command::Tail(tail) => tail.follow.wrap = tail.follow.wrap.or(self.wrap),
+            Command::Page { wrap } => *wrap = wrap.or(self.wrap),
+            _ => {}
+        }
+    }
+}
+
+/// How many messages to collect, or for how long, before committing them together.
+#[derive(Debug, Clone, Copy)]
+struct CommitWindow {
+    max_messages: usize,
+    max_wait: Duration,
+}
+
+/// Parse a commit window given as "<messages>,<milliseconds>", where messages is at least 1.
+fn parse_commit_window(s: &str) -> Result<CommitWindow, String> {
+    let (messages, ms) = s
+        .split_once(',')
+        .ok_or_else(|| format!("commit window {:?} should be <messages>,<milliseconds>", s))?;
+    let max_messages = parse_batch_size(messages)?;
+    let ms: u64 = ms
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid commit window time {:?}: {}", ms, e))?;
+    Ok(CommitWindow {
+        max_messages,
+        max_wait: Duration::from_millis(ms),
+    })
+}
+
+/// Parse a rate per second, which must be positive.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid rate {:?}: {}", s, e))?;
+    // Also rejects NaN, and rates too small to pause between messages for
+    if rate.is_nan() || rate < 1e-6 || rate.is_infinite() {
+        return Err(format!("rate {:?} should be a positive number", s));
+    }
+    Ok(rate)
+}
+
+/// Parse a batch size, which must be at least 1.
+fn parse_batch_size(s: &str) -> Result<usize, String> {
+    match s.trim().parse() {
+        Ok(0) => Err("batch size must be at least 1".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("invalid batch size {:?}: {}", s, e)),
+    }
+}
+
+/// Parse a ring size, which must be at least 1.
+fn parse_ring_size(s: &str) -> Result<usize, String> {
+    match s.trim().parse() {
+        Ok(0) => Err("ring size must be at least 1".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("invalid ring size {:?}: {}", s, e)),
+    }
+}
+
+/// Parse a duration made of whole numbers of days, hours, minutes, and seconds, like "1h30m".
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let mut total = Duration::ZERO;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err("empty duration".to_string());
+    }
+
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, unit_rest) = rest.split_at(digits);
+        let number: u64 = number
+            .parse()
+            .map_err(|_| format!("expected a number in duration {:?}", s))?;
+        let unit_len = unit_rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(unit_rest.len());
+        let (unit, next) = unit_rest.split_at(unit_len);
+        let seconds = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(format!("unknown unit {:?} in duration {:?}", unit, s)),
+        };
+        let part = number
+            .checked_mul(seconds)
+            .ok_or_else(|| format!("duration {:?} is too long", s))?;
+        total += Duration::from_secs(part);
+        rest = next;
+    }
+
+    Ok(total)
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+#[clap(trailing_var_arg = true)]
+struct Args {
+    #[clap(short, long, global = true, env = "FDBCHAT_USERNAME")]
+    username: Option<String>,
+
+    #[clap(short, long, global = true, env = "FDBCHAT_ROOM")]
+    room: Option<String>,
+
+    /// Connect using this cluster file, rather than FoundationDB's default
+    #[clap(long, global = true, env = "FDBCHAT_CLUSTER_FILE")]
+    cluster_file: Option<PathBuf>,
+
+    /// Read defaults for the room, username, cluster file, prompt, wrapping, and state file from
+    /// this TOML file, instead of ~/.fdbchat/config.toml if it exists
+    #[clap(long, global = true, env = "FDBCHAT_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[clap(short, long, global = true, parse(from_occurrences))]
+    debug: usize,
+
+    /// Write lifecycle events (joining, leaving, messages, watches firing, and errors) to stderr
+    /// as lines of JSON, for programs wrapping this one
+    #[clap(long, global = true, env = "FDBCHAT_JSON_EVENTS")]
+    json_events: bool,
+
+    /// Time each write, read, and watch, and print their latency percentiles to stderr on exit
+    #[clap(long, global = true, env = "FDBCHAT_PROFILE")]
+    profile: bool,
+
+    /// Only print chat messages to stdout, with no prompt; notices go to stderr, and diagnostics
+    /// are limited to errors
+    #[clap(
+        short,
+        long,
+        global = true,
+        conflicts_with = "debug",
+        env = "FDBCHAT_QUIET"
+    )]
+    quiet: bool,
+
+    /// How long to wait for the FoundationDB cluster when joining or checking it, in milliseconds
+    #[clap(
+        long,
+        global = true,
+        alias = "timeout-ms",
+        default_value_t = DEFAULT_INIT_TIMEOUT_MS,
+        env = "FDBCHAT_INIT_TIMEOUT_MS"
+    )]
+    init_timeout_ms: u64,
+
+    /// Warn if your clock is more than this many milliseconds behind the room's latest message
+    #[clap(
+        long,
+        global = true,
+        default_value_t = DEFAULT_CLOCK_SKEW_WARNING_MS,
+        env = "FDBCHAT_CLOCK_SKEW_WARNING_MS"
+    )]
+    clock_skew_warning_ms: u64,
+
+    /// Treat room names and usernames that differ only in case (or Unicode normalization) as the
+    /// same. Everyone in a room should use this or not, consistently
+    #[clap(long, global = true, env = "FDBCHAT_CASE_INSENSITIVE")]
+    case_insensitive: bool,
+
+    /// Sub-second precision of the timestamps on messages you send
+    #[clap(
+        long,
+        global = true,
+        arg_enum,
+        default_value = "millis",
+        env = "FDBCHAT_PRECISION"
+    )]
+    precision: KeyPrecision,
+
+    /// Record each message you send in the global activity log, for cross-room dashboards
+    #[clap(long, global = true, env = "FDBCHAT_TRACK_ACTIVITY")]
+    track_activity: bool,
+
+    /// Start keeping an index of the room's messages by sender, which every client joining the
+    /// room afterwards also keeps up to date
+    #[clap(long, global = true, env = "FDBCHAT_INDEX_BY_USER")]
+    index_by_user: bool,
+
+    /// Record each room you join under your username, and find a user's rooms from those records
+    /// with `rooms --user` rather than by checking every room
+    #[clap(long, global = true, env = "FDBCHAT_INDEX_ROOMS")]
+    index_rooms: bool,
+
+    /// Start each message printed with its room's name in brackets, and include the room in
+    /// exported JSON, e.g. for logs from several rooms in one file
+    #[clap(long, global = true, env = "FDBCHAT_SHOW_ROOM")]
+    show_room: bool,
+
+    /// Export a trace span for each database operation to the OTLP collector at this URL
+    #[cfg(feature = "otel")]
+    #[clap(long, global = true, env = "FDBCHAT_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    // Without a subcommand, chat as with the chat subcommand
+    #[clap(flatten)]
+    chat: ChatArgs,
+
+    // The rest predate subcommands, and are kept working for existing scripts
+    #[clap(long, hide = true, env = "FDBCHAT_INFO")]
+    info: bool,
+
+    #[clap(long, hide = true, conflicts_with = "info", env = "FDBCHAT_HEALTH")]
+    health: bool,
+
+    #[clap(
+        long,
+        hide = true,
+        conflicts_with_all = &["info", "health"],
+        env = "FDBCHAT_FIREHOSE"
+    )]
+    firehose: bool,
+
+    #[clap(long, hide = true, env = "FDBCHAT_RENAME_TO")]
+    rename_to: Option<String>,
+
+    #[clap(
+        long,
+        hide = true,
+        parse(try_from_str = parse_duration),
+        env = "FDBCHAT_CLEAR_BEFORE"
+    )]
+    clear_before: Option<Duration>,
+
+    #[clap(
+        long,
+        hide = true,
+        conflicts_with_all = &["replay", "messages"],
+        env = "FDBCHAT_STATS"
+    )]
+    stats: bool,
+
+    #[clap(long, hide = true, conflicts_with = "messages", env = "FDBCHAT_REPLAY")]
+    replay: Option<Option<f64>>,
+
+    #[clap(hide = true)]
+    messages: Vec<String>,
+}
+
+impl Args {
+    /// The command to run: the subcommand given, or else the one chosen by the flags that predate
+    /// subcommands, which is to chat if there are none.
+    fn take_command(&mut self) -> Command {
+        if let Some(command) = self.command.take() {
+            return command;
+        }
+        if self.info {
+            Command::Info
+        } else if self.health {
+            Command::Health
+        } else if self.firehose {
+            Command::Tail(TailArgs {
+                firehose: true,
+                ring: None,
+                refresh: Duration::from_secs(1),
+                follow: self.chat.follow.clone(),
+            })
+        } else if let Some(new_name) = self.rename_to.take() {
+            Command::Rename { new_name }
+        } else if let Some(before) = self.clear_before {
+            Command::Clear {
+                before: Some(before),
+            }
+        } else if self.stats {
+            Command::Stats
+        } else if let Some(speed) = self.replay {
+            Command::Replay {
+                speed: speed.unwrap_or(1.0),
+            }
+        } else if !self.messages.is_empty() {
+            Command::Send {
+                at: None,
+                messages: std::mem::take(&mut self.messages),
+            }
+        } else {
+            Command::Chat(self.chat.clone())
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Join the room and chat; this is what runs without a subcommand
+    Chat(ChatArgs),
+
+    /// Send these messages, in order, then leave
+    Send {
+        /// Send the first message at this time, e.g. 2022-02-27T20:37:57Z, instead of now (and
+        /// each after it a moment later), for backfilling or testing
+        #[clap(long, parse(try_from_str = parse_rfc3339), env = "FDBCHAT_AT")]
+        at: Option<DateTime>,
+
+        #[clap(required = true)]
+        messages: Vec<String>,
+    },
+
+    /// Print the room's messages as they arrive, without sending any
+    Tail(TailArgs),
+
+    /// Stay in the room, listed among its users, without reading or sending messages, until
+    /// interrupted
+    Presence,
+
+    /// Print the room's messages as JSON, one per line
+    Export {
+        /// Read every page as of the same database version, so messages written meanwhile are
+        /// left out. The whole export then has to finish within about five seconds.
+        #[clap(long, env = "FDBCHAT_SNAPSHOT")]
+        snapshot: bool,
+    },
+
+    /// Add the messages read from stdin, as printed by export, keeping their senders and times
+    Import {
+        /// Commit messages in batches of up to this many, or whatever has arrived within this
+        /// many milliseconds of the first in the batch, e.g. "100,1000"
+        #[clap(
+            long,
+            default_value = "100,1000",
+            parse(try_from_str = parse_commit_window),
+            env = "FDBCHAT_COMMIT_WINDOW"
+        )]
+        commit_window: CommitWindow,
+    },
+
+    /// Delete the room, with its messages and users, after confirming if run at a terminal
+    Clear {
+        /// Only delete the room's messages sent more than this long ago, e.g. 30d
+        #[clap(long, parse(try_from_str = parse_duration))]
+        before: Option<Duration>,
+    },
+
+    /// List every room
+    Rooms {
+        /// Only list the rooms this user is in
+        #[clap(long, env = "FDBCHAT_USER")]
+        user: Option<String>,
+    },
+
+    /// List the users in the room
+    Users,
+
+    /// Check that the cluster can be written to and read from, print a one-line status, and exit
+    /// non-zero if it can't
+    Health,
+
+    /// Print how many messages and users the room has and how much space it takes
+    Stats,
+
+    /// Print the FoundationDB API version, cluster file, and whether the cluster is reachable
+    Info,
+
+    /// Rename the room, moving its messages and users. The new name must not be in use already;
+    /// if renaming is interrupted, running it again finishes it
+    Rename { new_name: String },
+
+    /// Print the room's history with the original pauses between messages
+    Replay {
+        /// How many times faster than real time to replay
+        #[clap(default_value_t = 1.0)]
+        speed: f64,
+    },
+
+    /// Browse the room's history a screenful at a time, like less: space for the next page,
+    /// enter for the next message, and q to quit
+    Page {
+        /// Wrap messages to this many columns, instead of the terminal's width; 0 turns wrapping
+        /// off
+        #[clap(long, env = "FDBCHAT_WRAP")]
+        wrap: Option<usize>,
+    },
+}
+
+// Options for following the room's messages as they arrive. Without a doc comment here, which
+// clap would take as the program's description.
+#[derive(clap::Args, Debug, Clone)]
+struct FollowArgs {
+    /// Only show messages sent within this long before joining, e.g. 30m, 1h, or 2d12h, instead
+    /// of the whole history. Takes precedence over the position in the state file
+    #[clap(long, parse(try_from_str = parse_duration), env = "FDBCHAT_SINCE")]
+    since: Option<Duration>,
+
+    /// Only show messages sent after joining, skipping the room's history and the position in
+    /// the state file
+    #[clap(long, conflicts_with = "since", env = "FDBCHAT_NO_HISTORY")]
+    no_history: bool,
+
+    /// After being notified of new messages, wait this long before reading them, to batch up
+    /// bursts in busy rooms
+    #[clap(long, default_value_t = 0, env = "FDBCHAT_COALESCE_MS")]
+    coalesce_ms: u64,
+
+    /// Hold back new messages for this many milliseconds, so that messages from senders whose
+    /// clocks are behind by up to that much are shown in order instead of being missed
+    #[clap(long, default_value_t = 0, env = "FDBCHAT_REORDER_WINDOW")]
+    reorder_window: u64,
+
+    /// How many messages to read at a time while catching up on the room's history
+    #[clap(
+        long,
+        default_value_t = MessageIter::DEFAULT_BATCH_SIZE,
+        parse(try_from_str = parse_batch_size),
+        env = "FDBCHAT_BATCH_SIZE"
+    )]
+    batch_size: usize,
+
+    /// Read new messages at most once per this many milliseconds, however fast they arrive, to
+    /// cap the load from busy rooms
+    #[clap(long, default_value_t = 0, env = "FDBCHAT_MIN_WAKE_MS")]
+    min_wake_ms: u64,
+
+    /// When re-arming a failed watch, randomly vary each backoff delay by up to this fraction (0
+    /// to 1), so that clients recovering together don't retry together
+    #[clap(long, default_value_t = Backoff::DEFAULT_JITTER, env = "FDBCHAT_JITTER")]
+    jitter: f64,
+
+    /// Check for new messages periodically instead of watching for them, as is done anyway if
+    /// watches turn out to be unsupported
+    #[clap(long, env = "FDBCHAT_NO_WATCH")]
+    no_watch: bool,
+
+    /// How often to check for new messages when polling, e.g. "5s"
+    #[clap(
+        long,
+        parse(try_from_str = parse_duration),
+        default_value = "1s",
+        env = "FDBCHAT_POLL_INTERVAL"
+    )]
+    poll_interval: Duration,
+
+    /// Wrap messages to this many columns, instead of the terminal's width; 0 turns wrapping off.
+    /// Output that isn't going to a terminal is never wrapped
+    #[clap(long, env = "FDBCHAT_WRAP")]
+    wrap: Option<usize>,
+
+    /// Also append each message received to this file, flushing it after each one
+    #[clap(long, env = "FDBCHAT_OUTPUT_FILE")]
+    output_file: Option<PathBuf>,
+
+    /// Print the timestamp once for consecutive messages sent at the same time, indenting the
+    /// rest beneath it
+    #[clap(long, env = "FDBCHAT_GROUP_BY_TIME")]
+    group_by_time: bool,
+
+    /// Ring the terminal bell and highlight messages matching this regular expression; may be
+    /// given more than once
+    #[clap(
+        long = "alert",
+        value_name = "REGEX",
+        parse(try_from_str = regex::Regex::new),
+        multiple_occurrences = true,
+        env = "FDBCHAT_ALERT"
+    )]
+    alerts: Vec<regex::Regex>,
+
+    /// Also run this shell command for each alert, with the matched text in
+    /// FDBCHAT_ALERT_MATCH, and the message and its sender in FDBCHAT_ALERT_MESSAGE and
+    /// FDBCHAT_ALERT_SENDER
+    #[clap(long, env = "FDBCHAT_ALERT_COMMAND")]
+    alert_command: Option<String>,
+
+    /// Print the room's earlier messages at most this many per second, so that joining a busy
+    /// room doesn't flood the terminal. New messages are always printed as they arrive
+    #[clap(long, parse(try_from_str = parse_rate), env = "FDBCHAT_CATCHUP_RATE")]
+    catchup_rate: Option<f64>,
+
+    /// Print recently shown messages again, with their reactions, as people react to them
+    /// (except with --ring)
+    #[clap(long, env = "FDBCHAT_LIVE_REACTIONS")]
+    live_reactions: bool,
+}
+
+impl FollowArgs {
+    /// How long to pause before printing each of the room's earlier messages.
+    fn catchup_pause(&self) -> Option<Duration> {
+        self.catchup_rate
+            .map(|rate| Duration::from_secs_f64(1.0 / rate))
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ChatArgs {
+    #[clap(flatten)]
+    follow: FollowArgs,
+
+    /// Delete the room, with its messages and users, before joining it
+    #[clap(long, env = "FDBCHAT_CLEAR")]
+    clear: bool,
+
+    /// Limit the room to this many users at once, for everyone joining it from now on
+    #[clap(long, env = "FDBCHAT_MAX_USERS")]
+    max_users: Option<u64>,
+
+    /// Enable the /lag command, which prints the cluster read version and how long ago the
+    /// latest message was sent, for telling cluster lag apart from missed notifications
+    #[clap(long, env = "FDBCHAT_SHOW_LAG")]
+    show_lag: bool,
+
+    /// Save your session and read position to this file (default ~/.fdbchat/state.json), and
+    /// resume from it on restart
+    #[clap(long, env = "FDBCHAT_STATE_FILE")]
+    state_file: Option<Option<PathBuf>>,
+
+    /// Slow down reading input while the database is struggling to keep up with writes
+    #[clap(long, env = "FDBCHAT_ADAPTIVE_BACKPRESSURE")]
+    adaptive_backpressure: bool,
+
+    /// Show a prompt while waiting for input (default "{username}> "), where {username} and {room}
+    /// are replaced with yours. Ignored unless stdin and stdout are terminals
+    #[clap(long, env = "FDBCHAT_PROMPT")]
+    prompt: Option<Option<String>>,
+
+    /// Print your messages as soon as you send them, marked as pending until they reach the room
+    #[clap(long, env = "FDBCHAT_LOCAL_ECHO")]
+    local_echo: bool,
+
+    /// Acknowledge each message from others once it is printed, so senders can check with
+    /// "/acks <timestamp>" who has seen it. This costs a write per message
+    #[clap(long, env = "FDBCHAT_ACKS")]
+    acks: bool,
+
+    /// Take part in ephemeral messages: record reading them so they can be deleted once everyone
+    /// has, delete expired ones on joining, and send them with "/ephemeral <message>"
+    #[clap(long, env = "FDBCHAT_ALLOW_EPHEMERAL")]
+    allow_ephemeral: bool,
+
+    /// How long ephemeral messages you send last if not everyone reads them, e.g. 1h
+    #[clap(
+        long,
+        parse(try_from_str = parse_duration),
+        default_value = "1d",
+        env = "FDBCHAT_EPHEMERAL_TTL"
+    )]
+    ephemeral_ttl: Duration,
+
+    /// Don't print your own messages when they reach the room
+    #[clap(long, conflicts_with = "local-echo", env = "FDBCHAT_NO_SELF_ECHO")]
+    no_self_echo: bool,
+
+    /// Send lines exactly as typed, only dropping the line ending, instead of trimming the spaces
+    /// around them, e.g. to keep code or ASCII art intact
+    #[clap(long, env = "FDBCHAT_NO_TRIM")]
+    no_trim: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct TailArgs {
+    /// Print new messages from every room, prefixed with the room's name, instead of following
+    /// one. This is much heavier on the database than following a single room
+    #[clap(long, env = "FDBCHAT_FIREHOSE")]
+    firehose: bool,
+
+    /// Show only the latest this many messages, redrawing the screen as new ones arrive, e.g. for
+    /// a dashboard. Output that isn't going to a terminal is printed as usual
+    #[clap(
+        long,
+        value_name = "MESSAGES",
+        parse(try_from_str = parse_ring_size),
+        env = "FDBCHAT_RING"
+    )]
+    ring: Option<usize>,
+
+    /// With --ring, redraw at most once per this long, however fast messages arrive
+    #[clap(
+        long,
+        parse(try_from_str = parse_duration),
+        default_value = "1s",
+        env = "FDBCHAT_REFRESH"
+    )]
+    refresh: Duration,
+
+    #[clap(flatten)]
+    follow: FollowArgs,
+}
+
+/// Below this many columns left for the text after a message's prefix, messages aren't wrapped.
+const MIN_WRAP_WIDTH: usize = 20;
+
+/// Format a message as it is printed in the chat, indenting any lines after the first to line up
+/// under it.
+///
+/// If wrap is given, lines are also wrapped to fit in that many columns.
+fn format_message(
+    ts: DateTime,
+    kind: MessageKind,
+    sender: Option<&str>,
+    body: &str,
+    wrap: Option<usize>,
+) -> String {
+    let prefix = match kind {
+        MessageKind::Text => format!("{}: ", ts),
+        MessageKind::Action => format!("{}: * {} ", ts, sender.unwrap_or("someone")),
+    };
+    let prefix_width = prefix.chars().count();
+    let indent = format!("\n{:width$}", "", width = prefix_width);
+    let width = wrap
+        .map(|cols| cols.saturating_sub(prefix_width))
+        .filter(|&width| width >= MIN_WRAP_WIDTH);
+    let body = match width {
+        None => body.replace('\n', &indent),
+        Some(width) => body
+            .split('\n')
+            .flat_map(|line| wrap_line(line, width))
+            .collect::<Vec<_>>()
+            .join(&indent),
+    };
+    prefix + &body
+}
+
+/// Replace the timestamp at the start of line, a message formatted with format_message, with as
+/// many spaces, so that it lines up under the message before it.
+fn blank_timestamp(line: String, ts: DateTime) -> String {
+    let ts = ts.to_string();
+    match line.strip_prefix(ts.as_str()) {
+        Some(rest) => format!("{:width$}{}", "", rest, width = ts.chars().count()),
+        None => line,
+    }
+}
+
+/// Split line into pieces of at most width characters, breaking between words where possible,
+/// and within words too long for a line of their own (e.g. URLs).
+fn wrap_line(line: &str, width: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    let mut current_width = 0;
+    for word in line.split(' ') {
+        let word_width = word.chars().count();
+        if current_width > 0 && current_width + 1 + word_width <= width {
+            current.push(' ');
+            current.push_str(word);
+            current_width += 1 + word_width;
+            continue;
+        }
+
+        if current_width > 0 {
+            pieces.push(std::mem::take(&mut current));
+        }
+        let chars: Vec<char> = word.chars().collect();
+        let mut chunks: Vec<String> = chars.chunks(width).map(|c| c.iter().collect()).collect();
+        current = chunks.pop().unwrap_or_default();
+        current_width = current.chars().count();
+        pieces.extend(chunks);
+    }
+    pieces.push(current);
+    pieces
+}
+
+/// The size of the terminal on stdout, or None if it isn't a terminal.
+fn terminal_size() -> Option<libc::winsize> {
+    // Safety: winsize is plain data, and is filled in by the ioctl before it is read
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) != 0 {
+            return None;
+        }
+        Some(size)
+    }
+}
+
+/// The width of the terminal on stdout in columns, or None if it isn't a terminal.
+fn terminal_width() -> Option<usize> {
+    terminal_size()
+        .filter(|size| size.ws_col > 0)
+        .map(|size| usize::from(size.ws_col))
+}
+
+/// The height of the terminal on stdout in rows, or None if it isn't a terminal.
+fn terminal_height() -> Option<usize> {
+    terminal_size()
+        .filter(|size| size.ws_row > 0)
+        .map(|size| usize::from(size.ws_row))
+}
+
+/// Parse a timestamp as messages are printed (e.g. "2022-03-01 12:00:00.123 UTC"), or in RFC 3339.
+fn parse_timestamp(s: &str) -> AnyResult<DateTime> {
+    let s = s.trim();
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.with_timezone(&chrono::Utc));
+    }
+    let naive = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f UTC")
+        .with_context(|| format!("Parsing timestamp {:?}", s))?;
+    Ok(chrono::DateTime::from_utc(naive, chrono::Utc))
+}
+
+/// Parse an RFC 3339 timestamp, e.g. 2022-02-27T20:37:57.034Z.
+fn parse_rfc3339(s: &str) -> Result<DateTime, String> {
+    chrono::DateTime::parse_from_rfc3339(s.trim())
+        .map(|dt| dt.with_timezone(&chrono::Utc))
+        .map_err(|e| format!("invalid timestamp {:?}: {}", s, e))
+}
+
+/// Warn if messages sent at dt would be out of place among the room's messages, as described by
+/// send_time_warnings.
+async fn warn_send_time(session: &Session, dt: DateTime) -> anyhow::Result<()> {
+    let latest = session.latest().await?.map(|(latest, _)| latest);
+    for warning in send_time_warnings(dt, session.now(), latest) {
+        log::warn!("{}", warning);
+    }
+    Ok(())
+}
+
+/// What's out of place about sending at dt, given the time now and the room's latest message: if
+/// dt is in the future, or before the latest message, which followers reading on from it would
+/// miss.
+fn send_time_warnings(dt: DateTime, now: DateTime, latest: Option<DateTime>) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if dt > now {
+        warnings.push(format!("Sending at {}, which is in the future", dt));
+    }
+    if let Some(latest) = latest {
+        if dt < latest {
+            warnings.push(format!(
+                "Sending at {}, before the room's latest message at {}; anyone already following \
+                 the room won't see it",
+                dt, latest
+            ));
+        }
+    }
+    warnings
+}
+
+/// Summarize reactions given as (username, emoji) pairs, one line per emoji in order, e.g.
+/// "👍 x3 (alice, bob, carol)".
+fn format_reactions(reactions: &[(String, String)]) -> Vec<String> {
+    let mut by_emoji: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (user, emoji) in reactions {
+        by_emoji.entry(emoji).or_default().push(user);
+    }
+    by_emoji
+        .into_iter()
+        .map(|(emoji, users)| format!("{} x{} ({})", emoji, users.len(), users.join(", ")))
+        .collect()
+}
+
+/// The text of a quoted message, with "> " before each line.
+fn quote_text(body: &str) -> String {
+    body.lines().map(|line| format!("> {}\n", line)).collect()
+}
+
+/// Messages printed locally as soon as they are sent (with --local-echo), before they are read
+/// back from the room.
+///
+/// The sender adds each message before writing it, and removes it again if the write fails; the
+/// receiver removes it when it reads back a message from this user with the same timestamp, kind,
+/// and text, and skips printing that message a second time.
+#[derive(Debug, Default)]
+struct LocalEcho {
+    pending: RefCell<Vec<(DateTime, MessageKind, String)>>,
+}
+
+impl LocalEcho {
+    fn add(&self, ts: DateTime, kind: MessageKind, text: &str) {
+        self.pending.borrow_mut().push((ts, kind, text.to_string()));
+    }
+
+    /// Remove a pending message, returning whether it was pending.
+    fn remove(&self, ts: DateTime, kind: MessageKind, text: &str) -> bool {
+        let mut pending = self.pending.borrow_mut();
+        match pending
+            .iter()
+            .position(|(p_ts, p_kind, p_text)| *p_ts == ts && *p_kind == kind && p_text == text)
+        {
+            Some(ix) => {
+                pending.remove(ix);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether msg is the read-back copy of a message already printed locally.
+    fn reconcile(&self, session: &Session, msg: &Message) -> bool {
+        if msg.sender.as_deref() != Some(session.sender.as_str()) {
+            return false;
+        }
+        match msg.text() {
+            Some(text) => self.remove(msg.ts, msg.kind, text),
+            None => false,
+        }
+    }
+}
+
+/// How many of the latest messages printed have their reactions kept up to date, about a
+/// screenful.
+const LIVE_REACTIONS_SHOWN: usize = 25;
+
+/// The messages printed most recently, as with --live-reactions, along with the reactions last
+/// shown for each.
+///
+/// The receiver adds each message as it prints it, without its reactions; reactions_loop prints
+/// it again whenever its reactions change.
+#[derive(Debug, Default)]
+struct LiveReactions {
+    shown: RefCell<VecDeque<ShownMessage>>,
+}
+
+/// A message printed, as kept by LiveReactions.
+#[derive(Debug)]
+struct ShownMessage {
+    ts: DateTime,
+    /// The message as printed.
+    line: String,
+    /// The reactions last shown, as (username, emoji) pairs.
+    reactions: Vec<(String, String)>,
+}
+
+impl LiveReactions {
+    fn add(&self, ts: DateTime, line: String) {
+        let mut shown = self.shown.borrow_mut();
+        shown.push_back(ShownMessage {
+            ts,
+            line,
+            reactions: Vec::new(),
+        });
+        if shown.len() > LIVE_REACTIONS_SHOWN {
+            shown.pop_front();
+        }
+    }
+
+    /// The timestamps of the messages shown, oldest first.
+    fn timestamps(&self) -> Vec<DateTime> {
+        self.shown.borrow().iter().map(|shown| shown.ts).collect()
+    }
+
+    /// Record reactions as those of the message shown at ts, returning its line with them added
+    /// if they changed, to print again.
+    fn update(&self, ts: DateTime, reactions: Vec<(String, String)>) -> Option<String> {
+        let mut shown = self.shown.borrow_mut();
+        let shown = shown.iter_mut().find(|shown| shown.ts == ts)?;
+        if shown.reactions == reactions {
+            return None;
+        }
+        shown.reactions = reactions;
+        Some(with_reactions(&shown.line, &shown.reactions))
+    }
+}
+
+/// A printed message with its reactions after it, summarized as by format_reactions, e.g.
+/// "2022-03-01 12:00:00.123 UTC: hi [👍 x2 (alice, bob)]".
+fn with_reactions(line: &str, reactions: &[(String, String)]) -> String {
+    match reactions {
+        [] => format!("{} [no reactions]", line),
+        reactions => format!("{} [{}]", line, format_reactions(reactions).join("; ")),
+    }
+}
+
+/// Print the messages in live again as their reactions change, watching the room's reactions
+/// version, or reading it every poll_interval if polling.
+async fn reactions_loop(
+    session: &Session,
+    terminal: &Terminal,
+    live: &LiveReactions,
+    mut polling: bool,
+    poll_interval: Duration,
+) -> anyhow::Result<()> {
+    let mut backoff = Backoff::default();
+    let mut last_version = None;
+    loop {
+        let (version, watch) = session.watch_reactions().await?;
+        if last_version.is_some_and(|last| last != version) {
+            for ts in live.timestamps() {
+                let reactions = session.reactions(ts).await?;
+                if let Some(line) = live.update(ts, reactions) {
+                    terminal.print(&line)?;
+                }
+            }
+        }
+        last_version = Some(version);
+
+        if polling {
+            // Dropping the watch unused cancels it
+            drop(watch);
+            async_std::task::sleep(poll_interval).await;
+            continue;
+        }
+        match backoff.watch(watch).await {
+            Ok(_) => {}
+            Err(e) if e.code() == WATCHES_DISABLED || e.code() == TOO_MANY_WATCHES => {
+                log::warn!(
+                    "Can't watch for reactions ({}), polling every {:?} instead",
+                    e,
+                    poll_interval
+                );
+                polling = true;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// A file each received message is also appended to, as with --output-file.
+struct OutputFile {
+    path: PathBuf,
+    file: std::fs::File,
+}
+
+impl OutputFile {
+    /// Open path for appending, creating it if need be.
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Opening output file {}", path.display()))?;
+        Ok(OutputFile {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Append line, flushing it straight away. Failing to is only warned about, so that a full
+    /// disk doesn't end the chat.
+    fn append(&mut self, line: &str) {
+        if let Err(e) = writeln!(self.file, "{}", line).and_then(|()| self.file.flush()) {
+            log::warn!(
+                "Couldn't write to output file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// What message_print_loop does along with printing each message.
+#[derive(Debug, Clone, Copy, Default)]
+struct PrintOptions<'a> {
+    /// Acknowledge others' messages once printed.
+    acks: bool,
+    /// Record reading ephemeral messages, so they can be deleted once everyone has.
+    allow_ephemeral: bool,
+    /// How long to pause before printing each message from the room's history.
+    catchup_pause: Option<Duration>,
+    /// Start each message with the name of its room.
+    show_room: bool,
+    /// The cursor of the last message read in an earlier session, to mark where the room's
+    /// history turns to messages not seen before.
+    seen_cursor: Option<&'a str>,
+    /// Patterns that others' messages are highlighted for matching.
+    alerts: &'a [regex::Regex],
+    /// A shell command to run for each message matching one of alerts.
+    alert_command: Option<&'a str>,
+    /// Print the timestamp only on the first of consecutive messages sent at the same time.
+    group_by_time: bool,
+    /// Keep the reactions to the messages printed up to date.
+    live_reactions: Option<&'a LiveReactions>,
+    /// Print only messages, without NEW_MESSAGES_DIVIDER (for --quiet).
+    quiet: bool,
+}
+
+/// Alert the user to msg if it matches any of alerts, with line (msg as printed) highlighted and
+/// the terminal bell, returning the line to print in its place.
+///
+/// If alert_command is given, it is started for the alert in the background; failing to start
+/// it is only warned about.
+fn alert(
+    msg: &Message,
+    line: String,
+    alerts: &[regex::Regex],
+    alert_command: Option<&str>,
+) -> String {
+    let body = msg.display_body();
+    let found = match alerts.iter().find_map(|re| re.find(&body)) {
+        None => return line,
+        Some(found) => found,
+    };
+    if let Some(command) = alert_command {
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("FDBCHAT_ALERT_MATCH", found.as_str())
+            .env("FDBCHAT_ALERT_MESSAGE", body.as_ref())
+            .env("FDBCHAT_ALERT_SENDER", msg.sender.as_deref().unwrap_or(""))
+            .stdin(std::process::Stdio::null())
+            .spawn();
+        match child {
+            // Waited for in the background, so it doesn't linger once done
+            Ok(mut child) => drop(std::thread::spawn(move || child.wait())),
+            Err(e) => log::warn!("Couldn't run alert command {:?}: {}", command, e),
+        }
+    }
+    if std::io::stdout().is_terminal() {
+        // The bell, then the line in reverse video
+        format!("\x07\x1b[7m{}\x1b[0m", line)
+    } else {
+        format!("[alert] {}", line)
+    }
+}
+
+/// Printed between messages already seen and new ones, when rejoining a room.
+const NEW_MESSAGES_DIVIDER: &str = "─── new messages ───";
+
+async fn message_print_loop(
+    session: &Session,
+    mut iter: MessageIter<'_>,
+    terminal: &Terminal,
+    state_file: Option<&Path>,
+    echo: Option<&LocalEcho>,
+    mut output: Option<OutputFile>,
+    options: PrintOptions<'_>,
+) -> anyhow::Result<()> {
+    // The divider only goes in the history, before its first message past seen_cursor
+    let mut divided = options.seen_cursor.is_none();
+    let mut printed_ts = None;
+    loop {
+        let msg = iter.next().await?;
+        if !divided {
+            if iter.caught_up() {
+                divided = true;
+            } else if let (Some(cursor), Some(seen)) = (iter.cursor(), options.seen_cursor) {
+                if cursor.as_str() > seen {
+                    if !options.quiet {
+                        terminal.print(NEW_MESSAGES_DIVIDER)?;
+                    }
+                    divided = true;
+                }
+            }
+        }
+        if let (Some(pause), false) = (options.catchup_pause, iter.caught_up()) {
+            async_std::task::sleep(pause).await;
+        }
+        let room_prefix = match options.show_room {
+            true => format!("[{}] ", session.room),
+            false => String::new(),
+        };
+        let echoed = echo.is_some_and(|echo| echo.reconcile(session, &msg));
+        // Only the room's own messages can be reacted to
+        if let (Some(live), None) = (options.live_reactions, iter.channel()) {
+            live.add(
+                msg.ts,
+                format!("{}{}", room_prefix, msg.format(terminal.wrap)),
+            );
+        }
+        if !echoed {
+            let mut line = msg.format(terminal.wrap);
+            if options.group_by_time && printed_ts == Some(msg.ts) {
+                line = blank_timestamp(line, msg.ts);
+            }
+            printed_ts = Some(msg.ts);
+            let line = format!("{}{}", room_prefix, line);
+            let line = match msg.sender.as_deref() == Some(session.sender.as_str()) {
+                true => line,
+                false => alert(&msg, line, options.alerts, options.alert_command),
+            };
+            terminal.print(&line)?;
+        }
+        // Messages already shown by local echo are logged too, as they are still received
+        if let Some(output) = &mut output {
+            output.append(&format!("{}{}", room_prefix, msg.format(None)));
+        }
+        Event::Message {
+            room: &session.room,
+            cursor: iter.cursor().as_deref(),
+            sender: msg.sender.as_deref(),
+        }
+        .emit();
+        // Ephemeral messages, acks, and the state file are all of the room's default channel
+        if iter.channel().is_some() {
+            continue;
+        }
+        if options.allow_ephemeral {
+            if let Some(cursor) = iter.cursor() {
+                session.read_ephemeral(&cursor).await?;
+            }
+        }
+        if options.acks && msg.sender.as_deref() != Some(session.sender.as_str()) {
+            if let Some(cursor) = iter.cursor() {
+                session.ack(&cursor).await?;
+            }
+        }
+
+        if let (Some(path), Some(state)) = (state_file, State::of(session, iter.cursor())) {
+            state.save(path).await?;
+        }
+    }
+}
+
+/// How ring_print_loop shows messages, as set by --ring and --refresh.
+struct Ring {
+    /// How many of the latest messages to show.
+    size: usize,
+    /// The shortest time between redraws.
+    refresh: Duration,
+}
+
+/// Show the latest ring.size messages, clearing the screen and redrawing them as new ones
+/// arrive, at most once per ring.refresh.
+///
+/// Each message is checked for alerts and appended to output as it arrives, as in
+/// message_print_loop; options.catchup_pause doesn't apply, as the history is shown at once.
+async fn ring_print_loop(
+    session: &Session,
+    mut iter: MessageIter<'_>,
+    terminal: &Terminal,
+    mut output: Option<OutputFile>,
+    options: PrintOptions<'_>,
+    ring: Ring,
+) -> anyhow::Result<()> {
+    let mut lines = VecDeque::with_capacity(ring.size);
+    let mut last_draw: Option<Instant> = None;
+    let mut dirty = false;
+    loop {
+        let msg = if dirty {
+            // Take in whatever else arrives before the next redraw is due
+            let due =
+                last_draw.map_or(Duration::ZERO, |t| ring.refresh.saturating_sub(t.elapsed()));
+            match async_std::future::timeout(due, iter.next()).await {
+                Ok(msg) => Some(msg?),
+                Err(_) => None,
+            }
+        } else {
+            Some(iter.next().await?)
+        };
+
+        if let Some(msg) = msg {
+            let room_prefix = match options.show_room {
+                true => format!("[{}] ", session.room),
+                false => String::new(),
+            };
+            if let Some(output) = &mut output {
+                output.append(&format!("{}{}", room_prefix, msg.format(None)));
+            }
+            let line = format!("{}{}", room_prefix, msg.format(terminal.wrap));
+            let line = match msg.sender.as_deref() == Some(session.sender.as_str()) {
+                true => line,
+                false => alert(&msg, line, options.alerts, options.alert_command),
+            };
+            if lines.len() == ring.size {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+            dirty = true;
+        }
+
+        if dirty && last_draw.map_or(true, |t| t.elapsed() >= ring.refresh) {
+            // Move to the top left and clear the screen
+            let mut screen = String::from("\x1b[H\x1b[2J");
+            for line in &lines {
+                screen.push_str(line);
+                screen.push('\n');
+            }
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            out.write_all(screen.as_bytes())?;
+            out.flush()?;
+            last_draw = Some(Instant::now());
+            dirty = false;
+        }
+    }
+}
+
+async fn firehose_print_loop(db: &dyn StoreDb) -> anyhow::Result<()> {
+    let messages = Session::firehose(db, &SystemClock);
+    pin_mut!(messages);
+
+    while let Some(msg) = messages.next().await {
+        let (room, ts, text) = msg?;
+        println!("[{}] {}: {}", room, ts, text);
+    }
+
+    Ok(())
+}
+
+/// Adaptive backpressure for sending, to avoid piling onto an overloaded database.
+///
+/// After each write, the sender pauses for `delay` before accepting more input. A write that
+/// needed retries or took longer than SLOW_WRITE counts as a sign the database is struggling and
+/// doubles the delay (starting at MIN_DELAY, up to MAX_DELAY); a healthy write halves it, and a
+/// delay under MIN_DELAY drops back to zero.
+#[derive(Debug, Default)]
+struct Backpressure {
+    delay: Duration,
+}
+
+impl Backpressure {
+    const SLOW_WRITE: Duration = Duration::from_millis(250);
+    const MIN_DELAY: Duration = Duration::from_millis(10);
+    const MAX_DELAY: Duration = Duration::from_secs(2);
+
+    fn record(&mut self, stats: WriteStats) {
+        if stats.attempts > 1 || stats.latency > Backpressure::SLOW_WRITE {
+            self.delay = (self.delay * 2)
+                .max(Backpressure::MIN_DELAY)
+                .min(Backpressure::MAX_DELAY);
+            log::info!(
+                "Backpressure: write took {} attempts in {:?}, delaying input by {:?}",
+                stats.attempts,
+                stats.latency,
+                self.delay
+            );
+        } else {
+            self.delay /= 2;
+            if self.delay < Backpressure::MIN_DELAY {
+                self.delay = Duration::ZERO;
+            }
+        }
+    }
+}
+
+/// How send_loop reads and sends each line.
+#[derive(Debug, Clone, Copy, Default)]
+struct SendOptions {
+    /// Slow down sending while writes are slow or conflicting.
+    adaptive_backpressure: bool,
+    /// Answer LAG_COMMAND.
+    show_lag: bool,
+    /// If set, EPHEMERAL_COMMAND sends messages that expire after this long.
+    ephemeral_ttl: Option<Duration>,
+    /// Trim whitespace from the ends of each line.
+    trim: bool,
+}
+
+/// The message to send for what was read from input: a line with its line ending removed, or
+/// the joined lines of a multi-line message, trimmed as set by SendOptions::trim. None if it is
+/// blank.
+fn message_text(line: &str, multiline: bool, trim: bool) -> Option<&str> {
+    let text = match (multiline, trim) {
+        // Leading whitespace is kept in multi-line messages, for code and pastes
+        (true, true) => line.trim_end(),
+        (true, false) => line,
+        (false, true) => line.trim(),
+        (false, false) => line.strip_suffix('\n').unwrap_or(line),
+    };
+    // Lines of only spaces are skipped, even untrimmed
+    Some(text).filter(|text| !text.trim().is_empty())
+}
+
+/// Read lines from input and send them to the room, or to the channel within it that is active,
+/// until the input ends.
+///
+/// With echo, each line is printed immediately, marked as pending until it is read back from the
+/// room; a line that fails to send is marked as failed, and sending continues.
+async fn send_loop(
+    session: &Session,
+    terminal: &Terminal,
+    mut input: Input<'_>,
+    echo: Option<&LocalEcho>,
+    channel: &ActiveChannel,
+    options: SendOptions,
+) -> anyhow::Result<()> {
+    let SendOptions {
+        adaptive_backpressure,
+        show_lag,
+        ephemeral_ttl,
+        trim,
+    } = options;
+    let mut backpressure = Backpressure::default();
+    // Set by /quote, and added to the start of the next message sent
+    let mut quote: Option<String> = None;
+
+    loop {
+        if adaptive_backpressure && backpressure.delay > Duration::ZERO {
+            async_std::task::sleep(backpressure.delay).await;
+        }
+
+        terminal.show_prompt()?;
+        let line = match input.next().await.context("Failed getting input line")? {
+            None => return Ok(()),
+            Some(line) => line,
+        };
+        let (line, multiline) = if line.trim() == MULTILINE_START {
+            let lines = input
+                .next_multiline()
+                .await
+                .context("Failed getting input lines")?;
+            (lines, true)
+        } else {
+            (line, false)
+        };
+        let line = match message_text(&line, multiline, trim) {
+            None => continue,
+            Some(line) => line,
+        };
+        if show_lag && line == LAG_COMMAND {
+            terminal.print(&session.lag().await?.to_string())?;
+            continue;
+        }
+        if let Some(name) = command_args(line, CHANNEL_COMMAND) {
+            let status = match name.trim().trim_start_matches('#') {
+                "" => {
+                    channel.switch(None);
+                    "Switched to the room's default channel".to_string()
+                }
+                name => {
+                    channel.switch(Some(name.to_string()));
+                    format!("Switched to #{}", name)
+                }
+            };
+            terminal.print(&status)?;
+            continue;
+        }
+        if let (Some(text), Some(ttl)) = (command_args(line, EPHEMERAL_COMMAND), ephemeral_ttl) {
+            match text.trim() {
+                "" => terminal.print(&format!("Usage: {} <message>", EPHEMERAL_COMMAND))?,
+                text => session.write_ephemeral(session.now(), text, ttl).await?,
+            }
+            continue;
+        }
+        if let Some(user) = command_args(line, SEEN_COMMAND) {
+            let user = user.trim();
+            if user.is_empty() {
+                terminal.print(&format!("Usage: {} <username>", SEEN_COMMAND))?;
+                continue;
+            }
+            let status = match session.user_info(user).await? {
+                Some(info) => info.to_string(),
+                None => match session.read_from_user(user, 1).await?.first() {
+                    None => format!("{} is not in the room", user),
+                    Some(last) => {
+                        format!("{} is not in the room; last message at {}", user, last.ts)
+                    }
+                },
+            };
+            terminal.print(&status)?;
+            continue;
+        }
+        if let Some(ts) = command_args(line, ACKS_COMMAND) {
+            let status = match parse_timestamp(ts) {
+                _ if ts.trim().is_empty() => format!("Usage: {} <timestamp>", ACKS_COMMAND),
+                Ok(dt) => match session.acks_for(dt).await? {
+                    users if users.is_empty() => format!("No acks for {}", ts.trim()),
+                    users => format!("Acked by {}", users.join(", ")),
+                },
+                Err(e) => format!("Can't list acks for {}: {:#}", ts.trim(), e),
+            };
+            terminal.print(&status)?;
+            continue;
+        }
+        if let Some(ts) = command_args(line, REACTIONS_COMMAND) {
+            let lines = match parse_timestamp(ts) {
+                _ if ts.trim().is_empty() => {
+                    vec![format!("Usage: {} <timestamp>", REACTIONS_COMMAND)]
+                }
+                Ok(dt) => match session.reactions(dt).await? {
+                    reactions if reactions.is_empty() => {
+                        vec![format!("No reactions to {}", ts.trim())]
+                    }
+                    reactions => format_reactions(&reactions),
+                },
+                Err(e) => vec![format!("Can't list reactions to {}: {:#}", ts.trim(), e)],
+            };
+            for line in lines {
+                terminal.print(&line)?;
+            }
+            continue;
+        }
+        let reaction = command_args(line, REACT_COMMAND)
+            .map(|rest| (true, rest))
+            .or_else(|| command_args(line, UNREACT_COMMAND).map(|rest| (false, rest)));
+        if let Some((add, rest)) = reaction {
+            let status = match rest.trim().rsplit_once(' ') {
+                None => Some("Give a message's timestamp and an emoji to react with".to_string()),
+                Some((ts, emoji)) => match parse_timestamp(ts) {
+                    Err(e) => Some(format!("Can't react to {}: {:#}", ts.trim(), e)),
+                    Ok(dt) if add => {
+                        if session.react(dt, emoji).await? {
+                            None
+                        } else {
+                            Some(format!("No message at {} to react to", ts.trim()))
+                        }
+                    }
+                    Ok(dt) => {
+                        session.unreact(dt, emoji).await?;
+                        None
+                    }
+                },
+            };
+            if let Some(status) = status {
+                terminal.print(&status)?;
+            }
+            continue;
+        }
+        if let Some(ts) = command_args(line, QUOTE_COMMAND) {
+            let found = match parse_timestamp(ts) {
+                _ if ts.trim().is_empty() => {
+                    terminal.print(&format!("Usage: {} <timestamp>", QUOTE_COMMAND))?;
+                    continue;
+                }
+                Ok(dt) => session.get_message(dt).await?,
+                Err(e) => {
+                    terminal.print(&format!("Can't quote {}: {:#}", ts.trim(), e))?;
+                    continue;
+                }
+            };
+            match found {
+                None => terminal.print(&format!("No message at {} to quote", ts.trim()))?,
+                Some((_, body)) => quote = Some(quote_text(&body)),
+            }
+            continue;
+        }
+        let now = session.now();
+        let (kind, text) = match line.strip_prefix("/me ") {
+            Some(action) => (MessageKind::Action, action.trim()),
+            None => (MessageKind::Text, line),
+        };
+        let quoted;
+        let text = match quote.take() {
+            Some(quote) => {
+                quoted = quote + text;
+                quoted.as_str()
+            }
+            None => text,
+        };
+
+        let echo = match echo {
+            None => {
+                let stats = session
+                    .write_text_to(channel.get().as_deref(), now, kind, text)
+                    .await?;
+                backpressure.record(stats);
+                continue;
+            }
+            Some(echo) => echo,
+        };
+
+        let line = format_message(now, kind, Some(&session.sender), text, terminal.wrap);
+        echo.add(now, kind, text);
+        terminal.print(&format!("{} (pending)", line))?;
+        match session
+            .write_text_to(channel.get().as_deref(), now, kind, text)
+            .await
+        {
+            Ok(stats) => backpressure.record(stats),
+            Err(e) => {
+                echo.remove(now, kind, text);
+                terminal.print(&format!("{} (failed: {})", line, e))?;
+            }
+        }
+    }
+}
+
+/// Shown below each page of history while paging.
+const PAGE_PROMPT: &str = "--More-- (space: next page, enter: next message, q: quit)";
+
+/// Print the room's history a screenful of messages at a time, waiting for a key between pages:
+/// space for the next page, enter for one more message, and q (or the end of input) to quit.
+///
+/// Without a terminal to read keys from and print to, the whole history is printed at once.
+async fn page(session: &Session, wrap: Option<usize>) -> anyhow::Result<()> {
+    let interactive = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    // Restored when dropped, however paging ends
+    let _raw_mode = match interactive {
+        true => Some(RawMode::enable().context("Setting up the terminal for paging")?),
+        false => None,
+    };
+    // Leave a row for the prompt
+    let page_size = terminal_height().map_or(REVERSE_PAGE_SIZE, |rows| rows.max(2) - 1);
+
+    let mut cursor = session.cursor(None);
+    let mut stdin = io::stdin();
+    let mut want = page_size;
+    loop {
+        let batch = cursor.next_batch(want).await?;
+        for msg in &batch {
+            println!("{}", msg.format(wrap));
+        }
+        if batch.len() < want {
+            return Ok(());
+        }
+        if !interactive {
+            continue;
+        }
+
+        print!("{}", PAGE_PROMPT);
+        std::io::stdout().flush()?;
+        let mut key = [0];
+        want = loop {
+            if stdin.read(&mut key).await? == 0 {
+                break 0;
+            }
+            match key[0] {
+                b' ' | b'f' => break page_size,
+                b'\n' | b'\r' | b'j' => break 1,
+                b'q' | b'Q' => break 0,
+                _ => {}
+            }
+        };
+        // Clear the prompt, so the next page starts on its line
+        print!("\r\x1b[2K");
+        std::io::stdout().flush()?;
+        if want == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// The longest pause between two messages when replaying, however far apart they were sent.
+const MAX_REPLAY_DELAY: Duration = Duration::from_secs(5);
+
+/// Print the room's history, pausing between messages for as long as they were originally
+/// apart, divided by speed and clamped to MAX_REPLAY_DELAY.
+async fn replay(session: &Session, speed: f64) -> anyhow::Result<()> {
+    if !(speed > 0.0 && speed.is_finite()) {
+        return Err(anyhow::format_err!(
+            "Replay speed must be positive, not {}",
+            speed
+        ));
+    }
+
+    let mut previous: Option<DateTime> = None;
+    for msg in session.read_all_detailed().await? {
+        if let Some(gap) = previous.and_then(|prev| (msg.ts - prev).to_std().ok()) {
+            let delay = gap.div_f64(speed).min(MAX_REPLAY_DELAY);
+            async_std::task::sleep(delay).await;
+        }
+        previous = Some(msg.ts);
+
+        println!("{}", msg);
+    }
+
+    Ok(())
+}
+
+/// How often a running session checks that its username hasn't been reclaimed elsewhere.
+const OWNERSHIP_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Returns once another session has taken over this session's username, saying so on stdout,
+/// or on stderr if quiet.
+async fn ownership_loop(session: &Session, terminal: &Terminal, quiet: bool) -> anyhow::Result<()> {
+    const TAKEN: &str = "Session taken over elsewhere, exiting.";
+    loop {
+        async_std::task::sleep(OWNERSHIP_CHECK_INTERVAL).await;
+        if !session.verify_ownership().await? {
+            match quiet {
+                true => eprintln!("{}", TAKEN),
+                false => terminal.print(TAKEN)?,
+            }
+            return Ok(());
+        }
+    }
+}
+
+async fn signal_loop() -> anyhow::Result<()> {
+    let mut signals = Signals::new([SIGHUP, SIGTERM, SIGINT, SIGQUIT])?;
+    let handle = signals.handle();
+
+    if let Some(signal) = signals.next().await {
+        match signal {
+            SIGHUP => log::warn!("Received SIGHUP, exiting."),
+            SIGTERM => log::warn!("Received SIGTERM, exiting."),
+            SIGINT => log::info!("Received SIGINT, exiting."),
+            SIGQUIT => log::warn!("Received SIGQUIT, exiting."),
+            _ => unreachable!(),
+        }
+    }
+
+    handle.close();
+    Ok(())
+}
+
+/// The cluster file the client uses: explicit if given, or else following FoundationDB's own
+/// search order.
+fn cluster_file_path(explicit: Option<&Path>) -> PathBuf {
+    if let Some(path) = explicit {
+        return path.to_path_buf();
+    }
+    if let Some(path) = std::env::var_os("FDB_CLUSTER_FILE") {
+        return PathBuf::from(path);
+    }
+    let local = Path::new("fdb.cluster");
+    if local.exists() {
+        return local.to_path_buf();
+    }
+    PathBuf::from(foundationdb::default_config_path())
+}
+
+/// Get the cluster's current read version, failing if the cluster can't be reached in time.
+async fn read_version(db: &Database, timeout: Duration) -> AnyResult<i64> {
+    db.transact_boxed_local::<_, _, _, AnyErr>(
+        (),
+        |tx, ()| {
+            async move {
+                set_timeout(tx, timeout)?;
+                Ok(tx.get_read_version().await?)
+            }
+            .boxed_local()
+        },
+        foundationdb::TransactOption {
+            time_out: Some(timeout),
+            ..CHAT_OPTS
+        },
+    )
+    .await
+    .map_err(|e| unreachable_cluster_error(e, timeout))
+}
+
+/// Print diagnostics about the FoundationDB client and cluster, for debugging version or
+/// configuration mismatches.
+async fn print_info(db: &Database, cluster_file: Option<&Path>, timeout: Duration) {
+    println!(
+        "FoundationDB API version: {} (client library supports up to {})",
+        foundationdb::api::FdbApiBuilder::default().runtime_version(),
+        foundationdb::api::get_max_api_version()
+    );
+    println!(
+        "Cluster file: {}",
+        cluster_file_path(cluster_file).display()
+    );
+    match read_version(db, timeout).await {
+        Ok(version) => println!("Cluster reachable: yes (read version {})", version),
+        Err(e) => println!("Cluster reachable: no ({})", e),
+    }
+}
+
+/// Write a scratch key, then read it back and clear it, in separate transactions, returning how
+/// long the round trip took.
+async fn health_check(db: &Database, timeout: Duration) -> AnyResult<Duration> {
+    let opts = foundationdb::TransactOption {
+        time_out: Some(timeout),
+        ..CHAT_OPTS
+    };
+    let token = Uuid::new_v4();
+    let key = pack(&("health", token));
+    let value = pack(&token);
+    let start = Instant::now();
+
+    db.transact_boxed_local::<_, _, _, AnyErr>(
+        (key.as_slice(), value.as_slice()),
+        |tx, (key, value)| {
+            async move {
+                set_timeout(tx, timeout)?;
+                tx.set(key, value);
+                Ok(())
+            }
+            .boxed_local()
+        },
+        opts.clone(),
+    )
+    .await
+    .map_err(|e| unreachable_cluster_error(e, timeout))?;
+
+    let read = db
+        .transact_boxed_local::<_, _, _, AnyErr>(
+            key.as_slice(),
+            |tx, key| {
+                async move {
+                    set_timeout(tx, timeout)?;
+                    let read = tx.get(key, false).await?;
+                    tx.clear(key);
+                    Ok(read)
+                }
+                .boxed_local()
+            },
+            opts,
+        )
+        .await
+        .map_err(|e| unreachable_cluster_error(e, timeout))?;
+
+    if read.as_deref() != Some(value.as_slice()) {
+        return Err(anyhow::format_err!("Scratch key read back a different value").into());
+    }
+
+    Ok(start.elapsed())
+}
+
+fn print_stats(stats: &RoomStats) {
+    let ts = |dt: Option<DateTime>| dt.map_or_else(|| "none".to_string(), |dt| dt.to_string());
+    println!("Messages: {}", stats.message_count);
+    println!("Total size: {} bytes", stats.total_bytes);
+    println!("Oldest message: {}", ts(stats.oldest));
+    println!("Newest message: {}", ts(stats.newest));
+    println!("Users: {}", stats.user_count);
+}
+
+/// Ask a yes or no question at the terminal, returning whether the answer was yes. Without a
+/// terminal to ask at, e.g. in a script, the answer is always yes.
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Ask at the terminal which room to join, for chatting without --room: one of the rooms there
+/// are, by number, or any other name for a new one.
+async fn pick_room(db: &Database) -> anyhow::Result<String> {
+    let rooms = Session::rooms(db).await?;
+    for (i, room) in rooms.iter().enumerate() {
+        println!("{:>3}. {}", i + 1, room);
+    }
+    loop {
+        match rooms.is_empty() {
+            true => print!("Name a room to start: "),
+            false => print!("Room to join (a number above, or a new name): "),
+        }
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Err(anyhow::format_err!("No room chosen"));
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            continue;
+        }
+        // Numbers not on the list are taken as names
+        return Ok(match answer.parse::<usize>() {
+            Ok(n) if (1..=rooms.len()).contains(&n) => rooms[n - 1].clone(),
+            _ => answer.to_string(),
+        });
+    }
+}
+
+async fn main_loop(network: &NetworkGuard) -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    let mut command = args.take_command();
+    let mut builder = env_logger::Builder::from_env("LOGLEVEL");
+    builder.target(env_logger::Target::Stderr);
+    if args.quiet {
+        builder.filter_level(log::LevelFilter::Error);
+    }
+    match args.debug {
+        0 => {}
+        1 => {
+            builder.filter_level(log::LevelFilter::Info);
+        }
+        _ => {
+            builder.filter_level(log::LevelFilter::Debug);
+        }
+    }
+    builder.init();
+
+    let config = match &args.config {
+        Some(path) => Some(
+            Config::load(path)
+                .await?
+                .with_context(|| format!("Config file {} not found", path.display()))?,
+        ),
+        None => match Config::default_path() {
+            Ok(path) => Config::load(&path).await?,
+            Err(_) => None,
+        },
+    };
+    if let Some(config) = config {
+        config.apply(&mut args, &mut command);
+    }
+
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &args.otlp_endpoint {
+        init_tracing(endpoint)?;
+    }
+    JSON_EVENTS.store(args.json_events, Ordering::Relaxed);
+    let profile = args.profile;
+    if profile {
+        enable_profile();
+    }
+    let room = args.room.clone();
+    let result = run(args, command, network).await;
+    if profile {
+        print_profile();
+    }
+    if let Err(e) = &result {
+        Event::Error {
+            room: room.as_deref(),
+            error: format!("{:#}", e),
+        }
+        .emit();
+    }
+    #[cfg(feature = "otel")]
+    shutdown_tracing();
+
+    result
+}
+
+async fn run(args: Args, command: Command, network: &NetworkGuard) -> anyhow::Result<()> {
+    let manager = SessionManager::new(network, args.cluster_file.as_deref())?;
+    let db = manager.database();
+    let init_timeout = Duration::from_millis(args.init_timeout_ms);
+    match &command {
+        Command::Info => {
+            print_info(db, args.cluster_file.as_deref(), init_timeout).await;
+            return Ok(());
+        }
+        Command::Health => {
+            return match health_check(db, init_timeout).await {
+                Ok(latency) => {
+                    println!("status=ok latency_ms={}", latency.as_millis());
+                    Ok(())
+                }
+                Err(e) => {
+                    println!("status=error reason={:?}", e.to_string());
+                    Err(anyhow::format_err!("Health check failed: {}", e))
+                }
+            };
+        }
+        Command::Tail(tail) if tail.firehose => return firehose_print_loop(db).await,
+        Command::Rooms { user: Some(user) } => {
+            let user = if args.case_insensitive {
+                canonical_name(user)
+            } else {
+                user.clone()
+            };
+            let rooms = if args.index_rooms {
+                Session::rooms_for_user_indexed(db, &user).await?
+            } else {
+                Session::rooms_for_user(db, &user).await?
+            };
+            for room in rooms {
+                println!("{}", room);
+            }
+            return Ok(());
+        }
+        Command::Rooms { user: None } => {
+            for room in Session::rooms(db).await? {
+                println!("{}", room);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let interactive = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    let room = match args.room {
+        Some(room) => room,
+        None if interactive && matches!(command, Command::Chat(_)) => pick_room(db).await?,
+        None => return Err(anyhow::format_err!("--room is required")),
+    };
+    let display_room = room.clone();
+    let room = if args.case_insensitive {
+        canonical_name(&room)
+    } else {
+        let canonical = canonical_name(&room);
+        // Joining "General" case-sensitively would split it off from the "general" room
+        if canonical != room
+            && Session::case_insensitive_name(db, &canonical)
+                .await?
+                .is_some()
+        {
+            log::warn!(
+                "Room {} is used case-insensitively by others; use --case-insensitive to join them",
+                room
+            );
+        }
+        room
+    };
+    match &command {
+        Command::Rename { new_name } => {
+            let new_room = if args.case_insensitive {
+                canonical_name(new_name)
+            } else {
+                new_name.clone()
+            };
+            Session::rename_room(db, &room, &new_room).await?;
+            println!("Renamed room {} to {}", room, new_room);
+            return Ok(());
+        }
+        Command::Users => {
+            for user in Session::users(db, &room).await? {
+                println!("{}", user);
+            }
+            return Ok(());
+        }
+        Command::Clear { before: None } => {
+            if confirm(&format!(
+                "Delete room {} with its messages and users?",
+                room
+            ))? {
+                Session::clear(db, &room).await?;
+            }
+            return Ok(());
+        }
+        Command::Chat(chat) => {
+            if chat.clear {
+                Session::clear(db, &room).await?;
+            }
+            if let Some(max_users) = chat.max_users {
+                Session::set_max_users(db, &room, max_users).await?;
+            }
+        }
+        _ => {}
+    }
+
+    let username = args.username.context("--username is required")?;
+    let display_username = username.clone();
+    let username = if args.case_insensitive {
+        canonical_name(&username)
+    } else {
+        username
+    };
+
+    let state_file = match &command {
+        Command::Chat(ChatArgs {
+            state_file: Some(None),
+            ..
+        }) => Some(State::default_path()?),
+        Command::Chat(ChatArgs {
+            state_file: Some(Some(path)),
+            ..
+        }) => Some(path.clone()),
+        _ => None,
+    };
+    let state = match &state_file {
+        None => None,
+        Some(path) => State::load(path).await?,
+    };
+    // Saved state is only resumed for the same identity
+    let state = state.filter(|s| s.room == room && s.username == username);
+    let previous_id = state.as_ref().map(|s| s.id);
+    let cursor = state.and_then(|s| s.cursor);
+
+    let mut session = manager
+        .join_with(
+            &room,
+            &username,
+            init_timeout,
+            previous_id,
+            Box::new(SystemClock),
+        )
+        .await?;
+    session.set_precision(args.precision);
+    session.set_track_activity(args.track_activity);
+    if args.case_insensitive {
+        session.set_sender(display_username);
+        session.mark_case_insensitive(&display_room).await?;
+    }
+    if args.index_by_user {
+        session.enable_user_index().await?;
+    }
+    if args.index_rooms {
+        session.index_room().await?;
+    }
+
+    if let Some(skew) = session.clock_skew().await? {
+        if skew > Duration::from_millis(args.clock_skew_warning_ms) {
+            log::warn!(
+                "Your clock is {:?} behind the latest message in this room, so your messages may \
+                 appear out of order; consider syncing your clock",
+                skew
+            );
+        }
+    }
+
+    let result = match command {
+        Command::Chat(chat) => {
+            let taken = chat_loop(
+                &session,
+                chat,
+                state_file.as_deref(),
+                cursor,
+                args.show_room,
+                args.quiet,
+            )
+            .await;
+            match taken {
+                // Someone else holds the username now, so it isn't ours to release
+                Ok(true) => return Ok(()),
+                taken => taken.map(|_| ()),
+            }
+        }
+        Command::Send { at, messages } => {
+            let messages: Vec<&str> = messages
+                .iter()
+                .map(|m| m.trim())
+                .filter(|m| !m.is_empty())
+                .collect();
+            if messages.is_empty() {
+                log::warn!("All messages given were empty, so nothing was sent");
+                Ok(())
+            } else {
+                match at {
+                    None => session.write_many(&messages).await.map_err(Into::into),
+                    Some(at) => {
+                        async {
+                            warn_send_time(&session, at).await?;
+                            Ok(session.write_many_at(at, &messages).await?)
+                        }
+                        .await
+                    }
+                }
+            }
+        }
+        Command::Tail(tail) => tail_loop(&session, &tail, args.show_room).await,
+        Command::Presence => match presence_loop(&session, args.quiet).await {
+            // As with chatting, a username taken over elsewhere isn't ours to release
+            Ok(true) => return Ok(()),
+            taken => taken.map(|_| ()),
+        },
+        Command::Export { snapshot } => export(&session, snapshot, args.show_room).await,
+        Command::Import { commit_window } => import(&session, commit_window).await,
+        Command::Clear { before: Some(age) } => {
+            async {
+                let before = session.now() - chrono::Duration::from_std(age)?;
+                let question = format!(
+                    "Delete messages in {} sent before {}?",
+                    session.room, before
+                );
+                if confirm(&question)? {
+                    let count = session.clear_with(ClearScope::Before(before)).await?;
+                    println!("Deleted {} messages", count);
+                }
+                Ok(())
+            }
+            .await
+        }
+        Command::Stats => session
+            .room_stats()
+            .await
+            .map(|stats| print_stats(&stats))
+            .map_err(Into::into),
+        Command::Replay { speed } => replay(&session, speed).await,
+        Command::Page { wrap } => {
+            let pager = page(&session, wrap_width(wrap));
+            let signals = signal_loop();
+            pin_mut!(pager);
+            pin_mut!(signals);
+            select(signals, pager).await.factor_first().0
+        }
+        Command::Info
+        | Command::Health
+        | Command::Rooms { .. }
+        | Command::Users
+        | Command::Rename { .. }
+        | Command::Clear { before: None } => unreachable!("handled before joining"),
+    };
+
+    shut_down(&mut session, result).await
+}
+
+/// Flush the session's writes and leave the room, once the command given result has finished.
+async fn shut_down(session: &mut Session, result: anyhow::Result<()>) -> anyhow::Result<()> {
+    // Anything the command wrote must be committed before the session goes away
+    let flushed = session.flush().await.map_err(Into::into);
+    let result = after_cleanup(
+        result,
+        flushed,
+        &format!("flush writes to {}", session.room),
+    );
+    let left = session.leave().await.map_err(Into::into);
+    after_cleanup(result, left, &format!("leave room {}", session.room))
+}
+
+/// Combine the result of a command with that of cleaning up after it, e.g. by leaving the room.
+///
+/// If the command failed, that is what ended the session, so a failure to clean up afterwards is
+/// only logged (as a failure to do what) rather than hiding it.
+fn after_cleanup(
+    result: anyhow::Result<()>,
+    cleanup: anyhow::Result<()>,
+    what: &str,
+) -> anyhow::Result<()> {
+    match (result, cleanup) {
+        (Err(e), Err(cleanup_err)) => {
+            log::error!("Couldn't {}: {:#}", what, cleanup_err);
+            Err(e)
+        }
+        (result, cleanup) => result.and(cleanup),
+    }
+}
+
+/// Start reading the room's messages where follow says to: from since, after the latest
+/// message, or after cursor (from the beginning, if None).
+async fn follow_iter<'a>(
+    session: &'a Session,
+    follow: &FollowArgs,
+    cursor: Option<&str>,
+) -> anyhow::Result<MessageIter<'a>> {
+    // A cutoff before the start of time (or of the room) just shows everything
+    let since = follow.since.and_then(|since| {
+        let since = chrono::Duration::from_std(since).ok()?;
+        session.now().checked_sub_signed(since)
+    });
+
+    let mut iter = match since {
+        Some(since) => MessageIter::since(session, since),
+        // In an empty room, starting from the beginning just waits for the first message
+        None if follow.no_history => {
+            MessageIter::from_cursor(session, session.latest_cursor().await?.as_deref())
+        }
+        None => MessageIter::from_cursor(session, cursor),
+    };
+    iter.set_coalesce(Duration::from_millis(follow.coalesce_ms));
+    iter.set_min_wake(Duration::from_millis(follow.min_wake_ms));
+    iter.set_batch_size(follow.batch_size);
+    iter.set_reorder_window(Duration::from_millis(follow.reorder_window));
+    iter.set_backoff(Backoff::new(follow.jitter));
+    iter.set_polling(follow.no_watch);
+    iter.set_poll_interval(follow.poll_interval);
+    Ok(iter)
+}
+
+/// How many columns to wrap messages to, given the --wrap option.
+fn wrap_width(wrap: Option<usize>) -> Option<usize> {
+    // Wrapping only makes sense when the output is going straight to a terminal
+    match wrap {
+        _ if !std::io::stdout().is_terminal() => None,
+        Some(0) => None,
+        Some(cols) => Some(cols),
+        None => terminal_width(),
+    }
+}
+
+/// Chat in the room until a signal arrives or input ends, returning whether someone else took
+/// over the username in the meantime.
+///
+/// If quiet, only messages are printed to stdout: there is no prompt, and notices such as the
+/// join summary go to stderr.
+async fn chat_loop(
+    session: &Session,
+    args: ChatArgs,
+    state_file: Option<&Path>,
+    cursor: Option<String>,
+    show_room: bool,
+    quiet: bool,
+) -> anyhow::Result<bool> {
+    if args.allow_ephemeral {
+        let swept = session.sweep_ephemeral().await?;
+        if swept > 0 {
+            log::info!("Deleted {} expired ephemeral messages", swept);
+        }
+    }
+
+    if let (Some(path), Some(state)) = (state_file, State::of(session, cursor.clone())) {
+        state.save(path).await?;
+    }
+
+    // Only show the summary when someone is reading it: with the chat on stdout, or else alone
+    // on stderr
+    if quiet {
+        if std::io::stderr().is_terminal() {
+            eprintln!("{}", session.join_summary(cursor.as_deref()).await?);
+        }
+    } else if std::io::stdout().is_terminal() {
+        println!("{}", session.join_summary(cursor.as_deref()).await?);
+    }
+
+    let echo = LocalEcho::default();
+    let echo = if args.local_echo { Some(&echo) } else { None };
+    // A prompt only makes sense when someone is typing at a terminal and reading from it, and
+    // line editing echoes what is typed to stdout
+    let interactive = !quiet && std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    let prompt = match &args.prompt {
+        Some(prompt) if interactive => Some(
+            prompt
+                .as_deref()
+                .unwrap_or(DEFAULT_PROMPT)
+                .replace("{username}", &session.username)
+                .replace("{room}", &session.room),
+        ),
+        _ => None,
+    };
+    // Reading a key at a time lets incoming messages be printed without breaking up a line
+    // that is half typed
+    let raw_mode = if interactive {
+        RawMode::enable()
+            .map_err(|e| log::warn!("Failed to set up line editing: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    let editing = raw_mode.as_ref().map(|_| RefCell::default());
+    let terminal = Terminal {
+        prompt,
+        editing,
+        wrap: wrap_width(args.follow.wrap),
+    };
+
+    let output = args
+        .follow
+        .output_file
+        .as_deref()
+        .map(OutputFile::open)
+        .transpose()?;
+    let channel = ActiveChannel::default();
+    let live = LiveReactions::default();
+    let mut iter = follow_iter(session, &args.follow, cursor.as_deref()).await?;
+    iter.set_skip_own(args.no_self_echo);
+    iter.follow_channel(&channel);
+
+    let sender = send_loop(
+        session,
+        &terminal,
+        Input::new(&terminal),
+        echo,
+        &channel,
+        SendOptions {
+            adaptive_backpressure: args.adaptive_backpressure,
+            show_lag: args.show_lag,
+            ephemeral_ttl: if args.allow_ephemeral {
+                Some(args.ephemeral_ttl)
+            } else {
+                None
+            },
+            trim: !args.no_trim,
+        },
+    );
+    let receiver = message_print_loop(
+        session,
+        iter,
+        &terminal,
+        state_file,
+        echo,
+        output,
+        PrintOptions {
+            acks: args.acks,
+            allow_ephemeral: args.allow_ephemeral,
+            catchup_pause: args.follow.catchup_pause(),
+            show_room,
+            seen_cursor: cursor.as_deref(),
+            alerts: &args.follow.alerts,
+            alert_command: args.follow.alert_command.as_deref(),
+            group_by_time: args.follow.group_by_time,
+            live_reactions: Some(&live).filter(|_| args.follow.live_reactions),
+            quiet,
+        },
+    );
+    let reactions = follow_reactions(session, &terminal, &live, &args.follow);
+    let receiver = async {
+        futures::future::try_join(receiver, reactions)
+            .await
+            .map(|_| ())
+    };
+    let signals = signal_loop();
+    let ownership = ownership_loop(session, &terminal, quiet);
+    pin_mut!(sender);
+    pin_mut!(receiver);
+    pin_mut!(signals);
+    pin_mut!(ownership);
+
+    match select(signals, select(ownership, select(sender, receiver))).await {
+        // Got a signal, so we're done
+        Either::Left((signal_result, _other_future)) => {
+            signal_result?;
+            Ok(false)
+        }
+        Either::Right((Either::Left((ownership_result, _)), _other_future)) => {
+            ownership_result?;
+            Ok(true)
+        }
+        // Either sender or receiver returned, so we take the first of the
+        // two and short-circuit on the error
+        Either::Right((Either::Right((inner, _)), _other_future)) => {
+            inner.factor_first().0?;
+            Ok(false)
+        }
+    }
+}
+
+/// Print the room's messages as they arrive until a signal arrives.
+async fn tail_loop(session: &Session, tail: &TailArgs, show_room: bool) -> anyhow::Result<()> {
+    let follow = &tail.follow;
+    let terminal = Terminal {
+        prompt: None,
+        editing: None,
+        wrap: wrap_width(follow.wrap),
+    };
+    let output = follow
+        .output_file
+        .as_deref()
+        .map(OutputFile::open)
+        .transpose()?;
+    let iter = follow_iter(session, follow, None).await?;
+    let live = LiveReactions::default();
+    let options = PrintOptions {
+        catchup_pause: follow.catchup_pause(),
+        show_room,
+        alerts: &follow.alerts,
+        alert_command: follow.alert_command.as_deref(),
+        group_by_time: follow.group_by_time,
+        ..PrintOptions::default()
+    };
+    let receiver = match tail.ring {
+        Some(size) if std::io::stdout().is_terminal() => {
+            let ring = Ring {
+                size,
+                refresh: tail.refresh,
+            };
+            ring_print_loop(session, iter, &terminal, output, options, ring).boxed_local()
+        }
+        _ => {
+            let options = PrintOptions {
+                live_reactions: Some(&live).filter(|_| follow.live_reactions),
+                ..options
+            };
+            let printer = message_print_loop(session, iter, &terminal, None, None, output, options);
+            let reactions = follow_reactions(session, &terminal, &live, follow);
+            async {
+                futures::future::try_join(printer, reactions)
+                    .await
+                    .map(|_| ())
+            }
+            .boxed_local()
+        }
+    };
+    let signals = signal_loop();
+    pin_mut!(receiver);
+    pin_mut!(signals);
+
+    select(signals, receiver).await.factor_first().0
+}
+
+/// With --live-reactions, print live's messages again as their reactions change, as
+/// reactions_loop does; otherwise, never finish.
+async fn follow_reactions(
+    session: &Session,
+    terminal: &Terminal,
+    live: &LiveReactions,
+    follow: &FollowArgs,
+) -> anyhow::Result<()> {
+    if !follow.live_reactions {
+        return futures::future::pending().await;
+    }
+    reactions_loop(
+        session,
+        terminal,
+        live,
+        follow.no_watch,
+        follow.poll_interval,
+    )
+    .await
+}
+
+/// Stay in the room until a signal arrives, returning whether someone else took over the username
+/// in the meantime.
+///
+/// No messages are read and no watches are armed; the only load on the database is the periodic
+/// check that the username is still ours.
+async fn presence_loop(session: &Session, quiet: bool) -> anyhow::Result<bool> {
+    let terminal = Terminal {
+        prompt: None,
+        editing: None,
+        wrap: None,
+    };
+    let signals = signal_loop();
+    let ownership = ownership_loop(session, &terminal, quiet);
+    pin_mut!(signals);
+    pin_mut!(ownership);
+
+    match select(signals, ownership).await {
+        Either::Left((signal_result, _)) => {
+            signal_result?;
+            Ok(false)
+        }
+        Either::Right((ownership_result, _)) => {
+            ownership_result?;
+            Ok(true)
+        }
+    }
+}
+
+/// Print the room's messages as JSON, one per line, a page at a time.
+async fn export(session: &Session, snapshot: bool, show_room: bool) -> anyhow::Result<()> {
+    let view = match snapshot {
+        true => Some(session.at_version(session.read_version().await?)),
+        false => None,
+    };
+    let mut cursor = None;
+    loop {
+        let page = match &view {
+            Some(view) => view.read_range_paged(None, None, cursor.as_deref()).await?,
+            None => {
+                session
+                    .read_range_paged(None, None, cursor.as_deref())
+                    .await?
+            }
+        };
+        for msg in page.messages {
+            let mut record = MessageRecord::from(msg);
+            if show_room {
+                record.room = Some(session.room.clone());
+            }
+            println!("{}", serde_json::to_string(&record)?);
+        }
+        cursor = match page.next {
+            None => return Ok(()),
+            next => next,
+        };
+    }
+}
+
+/// Add the messages on stdin, in JSON as printed by export, one per line.
+///
+/// Messages are committed in batches, each ending once it has window.max_messages messages or
+/// window.max_wait has passed since its first arrived. Whatever is left is committed at the end
+/// of the input or on a signal.
+async fn import(session: &Session, window: CommitWindow) -> anyhow::Result<()> {
+    use async_std::io::prelude::BufReadExt;
+
+    // What ends each wait for input
+    enum Input {
+        Line(String),
+        Deadline,
+        Done,
+    }
+
+    let mut lines = io::BufReader::new(io::stdin()).lines();
+    let signals = signal_loop();
+    pin_mut!(signals);
+    let mut batch = Vec::new();
+    let mut batch_started = None;
+    let mut line_number = 0;
+    let mut count = 0;
+    loop {
+        let deadline = batch_started.map(|t: Instant| window.max_wait.saturating_sub(t.elapsed()));
+        let next_line = async {
+            let line = match deadline {
+                None => lines.next().await,
+                Some(wait) => match async_std::future::timeout(wait, lines.next()).await {
+                    Ok(line) => line,
+                    Err(_) => return Ok(Input::Deadline),
+                },
+            };
+            match line {
+                None => Ok(Input::Done),
+                Some(line) => line.map(Input::Line),
+            }
+        };
+        pin_mut!(next_line);
+        let input = match select(signals.as_mut(), next_line).await {
+            Either::Left((signal_result, _)) => {
+                signal_result?;
+                Input::Done
+            }
+            Either::Right((line, _)) => line?,
+        };
+
+        let (done, expired) = match input {
+            Input::Line(line) => {
+                line_number += 1;
+                // As with chat input, a file from Windows may start with a byte order mark
+                let line = match line_number {
+                    1 => line.trim_start_matches('\u{feff}'),
+                    _ => &line,
+                };
+                if !line.trim().is_empty() {
+                    let msg: Message = serde_json::from_str(line)
+                        .with_context(|| format!("Parsing line {}", line_number))?;
+                    batch.push(msg);
+                    batch_started.get_or_insert_with(Instant::now);
+                }
+                (false, false)
+            }
+            Input::Deadline => (false, true),
+            Input::Done => (true, false),
+        };
+        if done || expired || batch.len() >= window.max_messages {
+            if !batch.is_empty() {
+                session.import(&batch).await?;
+                count += batch.len();
+                batch.clear();
+            }
+            batch_started = None;
+        }
+        if done {
+            break;
+        }
+    }
+    log::info!("Imported {} messages", count);
+    Ok(())
+}
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    let network = boot()?;
+
+    let result = main_loop(&network).await;
+
+    drop(network);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use std::cell::Cell;
+    use std::ops::Bound;
+    use std::rc::Rc;
+    use std::sync::OnceLock;
+
+    /// The network, started by the first test that needs a cluster and left running until the
+    /// test process exits.
+    static NETWORK: OnceLock<NetworkGuard> = OnceLock::new();
+
+    /// Held while a test changes environment variables, which every test process shares.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Parse args as given on the command line, with env as the only FDBCHAT_ environment
+    /// variables set.
+    fn parse_args(env: &[(&str, &str)], args: &[&str]) -> clap::Result<Args> {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, _) in std::env::vars_os() {
+            if name.to_string_lossy().starts_with("FDBCHAT_") {
+                std::env::remove_var(name);
+            }
+        }
+        for (name, value) in env {
+            std::env::set_var(name, value);
+        }
+        let args = Args::try_parse_from(std::iter::once("fdbchat").chain(args.iter().copied()));
+        for (name, _) in env {
+            std::env::remove_var(name);
+        }
+        args
+    }
+
+    /// A room of its own for one test, on the cluster in the default cluster file.
+    ///
+    /// Tests that use one are ignored by default, as they need a running cluster; run them with
+    /// `cargo test -- --ignored`. Each room has a random name, so tests run in parallel (or
+    /// against a cluster with other data) don't see each other's messages.
+    struct TestRoom {
+        manager: SessionManager<'static>,
+        room: String,
+    }
+
+    impl TestRoom {
+        fn new() -> TestRoom {
+            let network = NETWORK.get_or_init(|| boot().expect("Starting the network"));
+            TestRoom {
+                manager: SessionManager::new(network, None).expect("Opening the database"),
+                room: format!("test-{}", Uuid::new_v4()),
+            }
+        }
+
+        fn db(&self) -> &Database {
+            self.manager.database()
+        }
+
+        async fn join(&self, username: &str) -> Session {
+            self.manager
+                .join(&self.room, username)
+                .await
+                .expect("Joining the test room")
+        }
+
+        /// Join as username, with timestamps from clock.
+        async fn join_with_clock(&self, username: &str, clock: &TestClock) -> Session {
+            let timeout = Duration::from_millis(DEFAULT_INIT_TIMEOUT_MS);
+            let clock = Box::new(clock.clone());
+            self.manager
+                .join_with(&self.room, username, timeout, None, clock)
+                .await
+                .expect("Joining the test room")
+        }
+
+        /// Delete the room and everything in it.
+        async fn clear(self) {
+            Session::clear(self.db(), &self.room)
+                .await
+                .expect("Clearing the test room");
+        }
+    }
+
+    /// A StoreDb kept in memory, for tests that don't need a cluster.
+    ///
+    /// Each transaction runs once, with no conflicts to retry: tests run their sessions on one
+    /// thread, and the store's futures are always ready, so transactions never interleave.
+    /// Writes go straight into the map and are undone if the transaction fails, except for
+    /// versionstamped keys, which as in FoundationDB only appear once it commits. Only the latest
+    /// version is kept, so reads at any other fail.
+    #[derive(Default)]
+    struct MemoryStore {
+        data: RefCell<BTreeMap<Vec<u8>, Vec<u8>>>,
+        /// Bumped by each transaction that writes anything.
+        version: Cell<i64>,
+        watches: RefCell<Vec<MemoryWatch>>,
+    }
+
+    /// A watch armed in a MemoryStore, woken once the value at key is no longer seen.
+    struct MemoryWatch {
+        key: Vec<u8>,
+        seen: Option<Vec<u8>>,
+        wake: oneshot::Sender<()>,
+    }
+
+    impl MemoryStore {
+        /// Add what tx leaves for its commit, then wake the watches whose values have changed.
+        fn commit(&self, tx: MemoryTransaction<'_>) {
+            let mut data = self.data.borrow_mut();
+            data.extend(tx.versionstamped.into_inner());
+            if tx.wrote.get() {
+                self.version.set(self.version.get() + 1);
+            }
+
+            let mut watches = self.watches.borrow_mut();
+            let armed = tx.watches.into_inner().into_iter().map(|(key, wake)| {
+                let seen = data.get(&key).cloned();
+                MemoryWatch { key, seen, wake }
+            });
+            let waiting: Vec<_> = watches.drain(..).chain(armed).collect();
+            for watch in waiting {
+                if data.get(&watch.key) != watch.seen.as_ref() {
+                    let _ = watch.wake.send(());
+                } else if !watch.wake.is_canceled() {
+                    watches.push(watch);
+                }
+            }
+        }
+    }
+
+    impl StoreDb for MemoryStore {
+        fn run_transaction<'a>(
+            &'a self,
+            body: &'a mut dyn TransactionBody,
+            _options: foundationdb::TransactOption,
+        ) -> LocalBoxFuture<'a, AnyResult<()>> {
+            async move {
+                let before = self.data.borrow().clone();
+                let tx = MemoryTransaction {
+                    store: self,
+                    read_version: Cell::new(None),
+                    wrote: Cell::new(false),
+                    versionstamped: RefCell::default(),
+                    watches: RefCell::default(),
+                };
+                let result = body.run(&tx).await;
+                match result {
+                    Ok(()) => self.commit(tx),
+                    Err(_) => *self.data.borrow_mut() = before,
+                }
+                result
+            }
+            .boxed_local()
+        }
+    }
+
+    struct MemoryTransaction<'a> {
+        store: &'a MemoryStore,
+        read_version: Cell<Option<i64>>,
+        wrote: Cell<bool>,
+        /// Keys and values set with SetVersionstampedKey, with their versionstamps filled in.
+        versionstamped: RefCell<Vec<(Vec<u8>, Vec<u8>)>>,
+        /// Watches armed by this transaction, which only start watching once it commits.
+        watches: RefCell<Vec<(Vec<u8>, oneshot::Sender<()>)>>,
+    }
+
+    impl MemoryTransaction<'_> {
+        /// Fail reads at a version other than the latest, the only one the store keeps.
+        fn check_version(&self) -> FdbResult<()> {
+            match self.read_version.get() {
+                // transaction_too_old
+                Some(version) if version < self.store.version.get() => {
+                    Err(FdbError::from_code(1007))
+                }
+                // future_version
+                Some(version) if version > self.store.version.get() => {
+                    Err(FdbError::from_code(1009))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        /// The index of the key selector picks, which is before the first key or after the last
+        /// when it falls off either end.
+        fn position(&self, selector: &KeySelector<'_>) -> i64 {
+            let bound = if selector.or_equal() {
+                Bound::Included(selector.key())
+            } else {
+                Bound::Excluded(selector.key())
+            };
+            let data = self.store.data.borrow();
+            let before = data.range::<[u8], _>((Bound::Unbounded, bound)).count();
+            before as i64 - 1 + i64::from(selector.offset())
+        }
+
+        fn read_range(&self, range: &RangeOption<'_>) -> FdbResult<Vec<KeyValue>> {
+            self.check_version()?;
+            let len = self.store.data.borrow().len() as i64;
+            let begin = self.position(&range.begin).clamp(0, len) as usize;
+            let end = self.position(&range.end).clamp(0, len) as usize;
+            let mut kvs: Vec<KeyValue> = self
+                .store
+                .data
+                .borrow()
+                .iter()
+                .skip(begin)
+                .take(end.saturating_sub(begin))
+                .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+                .collect();
+            if range.reverse {
+                kvs.reverse();
+            }
+            if let Some(limit) = range.limit.filter(|&limit| limit > 0) {
+                kvs.truncate(limit);
+            }
+            Ok(kvs)
+        }
+
+        fn write(&self) -> std::cell::RefMut<'_, BTreeMap<Vec<u8>, Vec<u8>>> {
+            self.wrote.set(true);
+            self.store.data.borrow_mut()
+        }
+    }
+
+    impl Store for MemoryTransaction<'_> {
+        fn get(
+            &self,
+            key: &[u8],
+            _snapshot: bool,
+        ) -> LocalBoxFuture<'_, FdbResult<Option<Vec<u8>>>> {
+            let value = self
+                .check_version()
+                .map(|()| self.store.data.borrow().get(key).cloned());
+            futures::future::ready(value).boxed_local()
+        }
+
+        fn get_key(
+            &self,
+            selector: &KeySelector<'_>,
+            _snapshot: bool,
+        ) -> LocalBoxFuture<'_, FdbResult<Vec<u8>>> {
+            let key = self.check_version().map(|()| {
+                let position = self.position(selector);
+                let data = self.store.data.borrow();
+                match usize::try_from(position) {
+                    Err(_) => Vec::new(),
+                    Ok(i) => data.keys().nth(i).cloned().unwrap_or_else(|| vec![0xff]),
+                }
+            });
+            futures::future::ready(key).boxed_local()
+        }
+
+        fn get_range(
+            &self,
+            range: &RangeOption<'_>,
+            _iteration: usize,
+            _snapshot: bool,
+        ) -> LocalBoxFuture<'_, FdbResult<Vec<KeyValue>>> {
+            futures::future::ready(self.read_range(range)).boxed_local()
+        }
+
+        fn get_ranges<'a>(
+            &'a self,
+            range: RangeOption<'a>,
+            _snapshot: bool,
+        ) -> LocalBoxStream<'a, FdbResult<Vec<KeyValue>>> {
+            futures::stream::once(futures::future::ready(self.read_range(&range))).boxed_local()
+        }
+
+        fn set(&self, key: &[u8], value: &[u8]) {
+            self.write().insert(key.to_vec(), value.to_vec());
+        }
+
+        fn clear(&self, key: &[u8]) {
+            self.write().remove(key);
+        }
+
+        fn clear_range(&self, begin: &[u8], end: &[u8]) {
+            let mut data = self.write();
+            let cleared: Vec<Vec<u8>> = data
+                .range::<[u8], _>((Bound::Included(begin), Bound::Excluded(end)))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in cleared {
+                data.remove(&key);
+            }
+        }
+
+        fn atomic_op(&self, key: &[u8], param: &[u8], op: MutationType) {
+            match op {
+                MutationType::Add => {
+                    let mut data = self.write();
+                    let mut sum = data.get(key).cloned().unwrap_or_default();
+                    sum.resize(param.len(), 0);
+                    let mut carry = 0;
+                    for (byte, add) in sum.iter_mut().zip(param) {
+                        let total = u16::from(*byte) + u16::from(*add) + carry;
+                        *byte = total as u8;
+                        carry = total >> 8;
+                    }
+                    data.insert(key.to_vec(), sum);
+                }
+                MutationType::ByteMax => {
+                    let mut data = self.write();
+                    if data.get(key).map_or(true, |value| value.as_slice() < param) {
+                        data.insert(key.to_vec(), param.to_vec());
+                    }
+                }
+                MutationType::SetVersionstampedKey => {
+                    // The key ends with the offset of its versionstamp, which is the version
+                    // the transaction commits at and its order within that version
+                    let (key, offset) = key.split_at(key.len() - 4);
+                    let offset = u32::from_le_bytes(<[u8; 4]>::try_from(offset).unwrap()) as usize;
+                    let mut key = key.to_vec();
+                    let version = (self.store.version.get() + 1).to_be_bytes();
+                    key[offset..offset + 8].copy_from_slice(&version);
+                    key[offset + 8..offset + 10].copy_from_slice(&[0, 0]);
+                    self.wrote.set(true);
+                    self.versionstamped.borrow_mut().push((key, param.to_vec()));
+                }
+                op => unimplemented!("{:?} in a MemoryStore", op),
+            }
+        }
+
+        fn watch(&self, key: &[u8]) -> LocalBoxFuture<'static, FdbResult<()>> {
+            let (wake, woken) = oneshot::channel();
+            self.watches.borrow_mut().push((key.to_vec(), wake));
+            // operation_cancelled, if the transaction fails or the store is dropped
+            woken.map_err(|_| FdbError::from_code(1101)).boxed_local()
+        }
+
+        fn get_read_version(&self) -> LocalBoxFuture<'_, FdbResult<i64>> {
+            let version = self.read_version.get().unwrap_or(self.store.version.get());
+            futures::future::ready(Ok(version)).boxed_local()
+        }
+
+        fn set_read_version(&self, version: i64) {
+            self.read_version.set(Some(version));
+        }
+
+        fn set_option(&self, _option: TransactionOption) -> FdbResult<()> {
+            Ok(())
+        }
+    }
+
+    /// A room of its own for one test, in a MemoryStore rather than on a cluster, so that tests
+    /// using one run by default.
+    struct MemoryRoom {
+        db: Arc<MemoryStore>,
+        room: String,
+    }
+
+    impl MemoryRoom {
+        fn new() -> MemoryRoom {
+            MemoryRoom {
+                db: Arc::default(),
+                room: "test".to_string(),
+            }
+        }
+
+        async fn join(&self, username: &str) -> Session {
+            self.join_with_clock(username, &TestClock::new(SystemClock.now()))
+                .await
+        }
+
+        /// Join as username, with timestamps from clock.
+        async fn join_with_clock(&self, username: &str, clock: &TestClock) -> Session {
+            let timeout = Duration::from_millis(DEFAULT_INIT_TIMEOUT_MS);
+            Session::init(
+                self.db.clone(),
+                self.room.clone(),
+                username.to_string(),
+                timeout,
+                None,
+                Box::new(clock.clone()),
+            )
+            .await
+            .expect("Joining the test room")
+        }
+    }
+
+    /// A clock that starts at a fixed time and moves on by step each time it is read, so that
+    /// every timestamp it gives out is distinct. Clones share the same time.
+    #[derive(Clone)]
+    struct TestClock {
+        now: Rc<Cell<DateTime>>,
+        step: chrono::Duration,
+    }
+
+    impl TestClock {
+        fn new(start: DateTime) -> TestClock {
+            TestClock {
+                now: Rc::new(Cell::new(start)),
+                step: chrono::Duration::milliseconds(1),
+            }
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> DateTime {
+            let now = self.now.get();
+            self.now.set(now + self.step);
+            now
+        }
+    }
+
+    fn at(s: &str) -> DateTime {
+        parse_rfc3339(s).expect("Parsing test time")
+    }
+
+    /// Write count messages from each of writers sessions at once, each numbered by its sender
+    /// ("w0-0", "w0-1", ...), returning every session's messages in the order they were sent.
+    async fn write_concurrently(
+        room: &TestRoom,
+        clock: &TestClock,
+        writers: usize,
+        count: usize,
+    ) -> Vec<Vec<String>> {
+        let mut sessions = Vec::new();
+        for w in 0..writers {
+            sessions.push(room.join_with_clock(&format!("w{}", w), clock).await);
+        }
+        let sent: Vec<Vec<String>> = (0..writers)
+            .map(|w| (0..count).map(|i| format!("w{}-{}", w, i)).collect())
+            .collect();
+        futures::future::try_join_all(sessions.iter().zip(&sent).map(
+            |(session, texts)| async move {
+                for text in texts {
+                    session.write(session.now(), text).await?;
+                }
+                Ok::<_, AnyErr>(())
+            },
+        ))
+        .await
+        .expect("Writing concurrently");
+        for mut session in sessions {
+            session.leave().await.expect("Leaving");
+        }
+        sent
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn concurrent_writers_lose_nothing_and_read_in_order() {
+        let room = TestRoom::new();
+        let clock = TestClock::new(at("2022-03-01T12:00:00Z"));
+        let sent = write_concurrently(&room, &clock, 4, 25).await;
+
+        let reader = room.join("reader").await;
+        let read = reader.read_all_detailed().await.unwrap();
+        assert_eq!(read.len(), 100);
+        assert!(read.windows(2).all(|w| w[0].ts < w[1].ts));
+        for (w, texts) in sent.iter().enumerate() {
+            let theirs: Vec<_> = read
+                .iter()
+                .filter(|m| m.sender.as_deref() == Some(format!("w{}", w).as_str()))
+                .map(|m| m.text().unwrap().to_string())
+                .collect();
+            assert_eq!(&theirs, texts);
+        }
+        room.clear().await;
+    }
+
+    fn text_message(sender: &str, text: &str) -> DecodedMessage {
+        DecodedMessage {
+            kind: MessageKind::Text,
+            sender: Some(sender.to_string()),
+            encoding: Encoding::Utf8,
+            body: text.as_bytes().to_vec(),
+        }
+    }
+
+    const PRECISIONS: [KeyPrecision; 3] = [
+        KeyPrecision::Millis,
+        KeyPrecision::Micros,
+        KeyPrecision::Nanos,
+    ];
+
+    #[test]
+    fn keys_parse_back_at_every_precision() {
+        let dt = at("2022-03-01T12:00:00.123456789Z");
+        for precision in PRECISIONS {
+            let key = Session::message_key_bytes("room", dt, precision);
+            let value = TupleCodec.encode(&text_message("alice", "hi"));
+            let msg = Session::parse_message_with(&TupleCodec, &key, &value).unwrap();
+            assert_eq!(
+                msg.ts,
+                dt.trunc_subsecs(precision.digits()),
+                "{:?}",
+                precision
+            );
+        }
+    }
+
+    #[test]
+    fn keys_at_mixed_precisions_sort_by_time() {
+        let start = at("2022-03-01T12:00:00.999Z");
+        // A millisecond apart, crossing a second boundary, at each precision in turn
+        let keys: Vec<_> = (0..9)
+            .map(|i| {
+                let dt = start + chrono::Duration::milliseconds(i);
+                Session::message_key_bytes("room", dt, PRECISIONS[i as usize % 3])
+            })
+            .collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(sorted, keys);
+    }
+
+    #[test]
+    fn finer_precisions_sort_within_a_millisecond() {
+        let dt = at("2022-03-01T12:00:00.123Z");
+        let later = dt + chrono::Duration::microseconds(1);
+        for precision in [KeyPrecision::Micros, KeyPrecision::Nanos] {
+            assert!(
+                Session::message_key_bytes("room", dt, precision)
+                    < Session::message_key_bytes("room", later, precision)
+            );
+        }
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn writing_after_leaving_fails_as_inactive() {
+        let room = TestRoom::new();
+        let mut session = room.join("alice").await;
+        assert!(session.is_active());
+        session.leave().await.unwrap();
+        assert!(!session.is_active());
+        assert_eq!(session.id(), None);
+
+        let err = session.write(session.now(), "too late").await.unwrap_err();
+        let inactive = match &err {
+            AnyErr::Any(e) => e.downcast_ref::<SessionInactive>(),
+            AnyErr::Fdb(_) => None,
+        };
+        assert!(inactive.is_some(), "unexpected error: {}", err);
+        // Leaving again does nothing, and nothing was written
+        session.leave().await.unwrap();
+        let reader = room.join("bob").await;
+        assert!(reader.read_all_detailed().await.unwrap().is_empty());
+        room.clear().await;
+    }
+
+    #[test]
+    fn actions_round_trip_and_render_as_emotes() {
+        let dt = at("2022-03-01T12:00:00.123Z");
+        let action = DecodedMessage {
+            kind: MessageKind::Action,
+            ..text_message("alice", "waves")
+        };
+        let key = Session::message_key_bytes("room", dt, KeyPrecision::Millis);
+        let value = TupleCodec.encode(&action);
+        let msg = Session::parse_message_with(&TupleCodec, &key, &value).unwrap();
+        assert_eq!(msg.kind, MessageKind::Action);
+        assert_eq!(msg.to_string(), format!("{}: * alice waves", dt));
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn actions_reach_other_readers_as_actions() {
+        let room = TestRoom::new();
+        let alice = room.join("alice").await;
+        let dt = alice.now();
+        alice.write_action(dt, "waves").await.unwrap();
+
+        let bob = room.join("bob").await;
+        let read = bob.read_all_detailed().await.unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].kind, MessageKind::Action);
+        assert_eq!(read[0].to_string(), format!("{}: * alice waves", dt));
+        room.clear().await;
+    }
+
+    /// Write texts one after another, starting after delay, e.g. once a reader is waiting.
+    async fn write_after(session: &Session, delay: Duration, texts: &[String]) {
+        async_std::task::sleep(delay).await;
+        for text in texts {
+            session.write(session.now(), text).await.unwrap();
+        }
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn coalescing_reads_a_burst_in_one_transaction() {
+        let room = TestRoom::new();
+        let clock = TestClock::new(at("2022-03-01T12:00:00Z"));
+        let writer = room.join_with_clock("alice", &clock).await;
+        let reader = room.join("bob").await;
+        let burst: Vec<String> = (0..10).map(|i| format!("burst {}", i)).collect();
+
+        let mut iter = MessageIter::new(&reader, None);
+        iter.set_coalesce(Duration::from_secs(2));
+        let (first, ()) = futures::join!(
+            iter.next(),
+            write_after(&writer, Duration::from_millis(200), &burst)
+        );
+        assert_eq!(first.unwrap().text(), Some("burst 0"));
+        // The rest came in the same read, with nothing left to fetch
+        assert_eq!(iter.waiting.len(), burst.len() - 1);
+        for text in &burst[1..] {
+            assert_eq!(iter.next().await.unwrap().text(), Some(text.as_str()));
+        }
+        room.clear().await;
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn clock_skew_is_how_far_behind_the_latest_message() {
+        let room = TestRoom::new();
+        let sent = at("2022-03-01T12:00:00Z");
+        let writer = room.join_with_clock("alice", &TestClock::new(sent)).await;
+        let behind = TestClock::new(sent - chrono::Duration::seconds(10));
+        let lagging = room.join_with_clock("bob", &behind).await;
+        let ahead = TestClock::new(sent + chrono::Duration::seconds(1));
+        let current = room.join_with_clock("carol", &ahead).await;
+        assert_eq!(lagging.clock_skew().await.unwrap(), None);
+
+        writer.write(writer.now(), "hi").await.unwrap();
+        assert_eq!(
+            lagging.clock_skew().await.unwrap(),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(current.clock_skew().await.unwrap(), None);
+        room.clear().await;
+    }
+
+    fn message(
+        ts: &str,
+        sender: &str,
+        kind: MessageKind,
+        encoding: Encoding,
+        body: &[u8],
+    ) -> Message {
+        Message {
+            ts: at(ts),
+            kind,
+            encoding,
+            body: body.to_vec(),
+            sender: Some(sender.to_string()),
+            key_len: 0,
+            value_len: 0,
+        }
+    }
+
+    #[test]
+    fn messages_round_trip_through_json() {
+        let messages = [
+            message(
+                "2022-03-01T12:00:00.123Z",
+                "alice",
+                MessageKind::Text,
+                Encoding::Utf8,
+                b"hi",
+            ),
+            message(
+                "2022-03-01T12:00:01Z",
+                "bob",
+                MessageKind::Action,
+                Encoding::Utf8,
+                b"waves",
+            ),
+            message(
+                "2022-03-01T12:00:02Z",
+                "carol",
+                MessageKind::Text,
+                Encoding::Binary,
+                &[0, 159, 146, 150],
+            ),
+        ];
+        for msg in messages {
+            let json = serde_json::to_string(&msg).unwrap();
+            let back: Message = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, msg, "{}", json);
+        }
+    }
+
+    #[test]
+    fn messages_serialize_with_the_export_field_names() {
+        let msg = message(
+            "2022-03-01T12:00:00.123Z",
+            "alice",
+            MessageKind::Text,
+            Encoding::Utf8,
+            b"hi",
+        );
+        assert_eq!(
+            serde_json::to_value(&msg).unwrap(),
+            serde_json::json!({
+                "ts": "2022-03-01T12:00:00.123Z",
+                "sender": "alice",
+                "kind": "text",
+                "text": "hi",
+            })
+        );
+        let binary = message(
+            "2022-03-01T12:00:00Z",
+            "bob",
+            MessageKind::Text,
+            Encoding::Binary,
+            &[0xff],
+        );
+        let value = serde_json::to_value(&binary).unwrap();
+        assert_eq!(value["base64"], "/w==");
+        assert_eq!(value.get("text"), None);
+    }
+
+    #[test]
+    fn messages_display_as_printed_in_the_chat() {
+        let msg = message(
+            "2022-03-01T12:00:00.123Z",
+            "alice",
+            MessageKind::Text,
+            Encoding::Utf8,
+            b"hi",
+        );
+        assert_eq!(msg.to_string(), "2022-03-01 12:00:00.123 UTC: hi");
+        let binary = message(
+            "2022-03-01T12:00:00Z",
+            "bob",
+            MessageKind::Text,
+            Encoding::Binary,
+            &[0xff],
+        );
+        assert_eq!(
+            binary.to_string(),
+            "2022-03-01 12:00:00 UTC: [1 bytes] /w=="
+        );
+    }
+
+    /// future_version, which a watch fails with after a recovery.
+    const FUTURE_VERSION: i32 = 1009;
+
+    #[async_std::test]
+    async fn retryable_watch_errors_ask_to_re_arm() {
+        let mut backoff = Backoff::new(0.0);
+        let failed = futures::future::ready(Err(FdbError::from_code(FUTURE_VERSION)));
+        assert!(!backoff.watch(failed).await.unwrap());
+        let failed = futures::future::ready(Err(FdbError::from_code(FUTURE_VERSION)));
+        assert!(!backoff.watch(failed).await.unwrap());
+        assert_eq!(backoff.retries, 2);
+
+        // Once a re-armed watch fires, the backoff starts over
+        assert!(backoff.watch(futures::future::ready(Ok(()))).await.unwrap());
+        assert_eq!(backoff.retries, 0);
+    }
+
+    #[async_std::test]
+    async fn fatal_watch_errors_are_returned() {
+        let mut backoff = Backoff::new(0.0);
+        let failed = futures::future::ready(Err(FdbError::from_code(WATCHES_DISABLED)));
+        let err = backoff.watch(failed).await.unwrap_err();
+        assert_eq!(err.code(), WATCHES_DISABLED);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_its_cap() {
+        let mut backoff = Backoff::new(0.0);
+        let delays: Vec<_> = (0..4).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, [50, 100, 200, 400].map(Duration::from_millis));
+        for _ in 0..20 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), Backoff::MAX_DELAY);
+
+        let mut jittered = Backoff::new(0.5);
+        let delay = jittered.next_delay();
+        assert!(delay >= Duration::from_millis(25) && delay <= Duration::from_millis(75));
+    }
+
+    /// Write the messages "0", "1", ... up to count, a millisecond apart from start, in one
+    /// transaction.
+    async fn write_numbered(session: &Session, start: DateTime, count: usize) {
+        let texts: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        session.write_many_at(start, &texts).await.unwrap();
+    }
+
+    fn texts(messages: &[Message]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|m| m.text_lossy().into_owned())
+            .collect()
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn reverse_stream_pages_back_across_batches() {
+        let room = TestRoom::new();
+        let session = room.join("alice").await;
+        let start = at("2022-03-01T12:00:00Z");
+        // Two and a half pages
+        let count = REVERSE_PAGE_SIZE * 5 / 2;
+        write_numbered(&session, start, count).await;
+
+        let all: Vec<Message> = session.stream_reverse(None).try_collect().await.unwrap();
+        let expected: Vec<String> = (0..count).rev().map(|i| i.to_string()).collect();
+        assert_eq!(texts(&all), expected);
+
+        // Starting before a message on a page boundary skips it and everything after
+        let before = start + chrono::Duration::milliseconds(REVERSE_PAGE_SIZE as i64);
+        let older: Vec<Message> = session
+            .stream_reverse(Some(before))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(texts(&older), expected[count - REVERSE_PAGE_SIZE..]);
+        room.clear().await;
+    }
+
+    /// Stores messages as TupleCodec does, with every byte flipped, standing in for an encrypting
+    /// codec.
+    struct FlipCodec;
+
+    impl ValueCodec for FlipCodec {
+        fn encode(&self, msg: &DecodedMessage) -> Vec<u8> {
+            TupleCodec.encode(msg).iter().map(|b| !b).collect()
+        }
+
+        fn decode(&self, value: &[u8]) -> AnyResult<DecodedMessage> {
+            let value: Vec<u8> = value.iter().map(|b| !b).collect();
+            TupleCodec.decode(&value)
+        }
+    }
+
+    #[test]
+    fn codecs_decode_what_they_encode() {
+        let msg = text_message("alice", "secret");
+        let codecs: [&dyn ValueCodec; 2] = [&TupleCodec, &FlipCodec];
+        for codec in codecs {
+            assert_eq!(codec.decode(&codec.encode(&msg)).unwrap(), msg);
+        }
+        assert_ne!(FlipCodec.encode(&msg), TupleCodec.encode(&msg));
+        assert!(TupleCodec.decode(&FlipCodec.encode(&msg)).is_err());
+    }
+
+    #[test]
+    fn tuple_codec_reads_older_formats() {
+        let old = pack(&(1i64, "alice", "waves"));
+        let decoded = TupleCodec.decode(&old).unwrap();
+        assert_eq!(decoded.kind, MessageKind::Action);
+        assert_eq!(decoded.sender.as_deref(), Some("alice"));
+        assert_eq!(decoded.body, b"waves");
+
+        let oldest = TupleCodec.decode(b"just text").unwrap();
+        assert_eq!(oldest.sender, None);
+        assert_eq!(oldest.body, b"just text");
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn sessions_with_a_swapped_codec_read_each_other() {
+        let room = TestRoom::new();
+        let mut alice = room.join("alice").await;
+        alice.set_codec(Box::new(FlipCodec));
+        alice.write(alice.now(), "secret").await.unwrap();
+
+        let mut bob = room.join("bob").await;
+        bob.set_codec(Box::new(FlipCodec));
+        assert_eq!(texts(&bob.read_all_detailed().await.unwrap()), ["secret"]);
+        let carol = room.join("carol").await;
+        assert!(carol.read_all_detailed().await.is_err());
+        room.clear().await;
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn one_manager_joins_several_rooms() {
+        let room = TestRoom::new();
+        let names: Vec<String> = (0..3).map(|i| format!("{}-{}", room.room, i)).collect();
+        let mut sessions = Vec::new();
+        for name in &names {
+            let session = room.manager.join(name, "alice").await.unwrap();
+            session.write(session.now(), name).await.unwrap();
+            sessions.push(session);
+        }
+        // The same username can be in every room at once, and each room has only its own messages
+        for (session, name) in sessions.iter().zip(&names) {
+            assert!(Arc::ptr_eq(&session.db, &sessions[0].db));
+            assert_eq!(
+                texts(&session.read_all_detailed().await.unwrap()),
+                [name.as_str()]
+            );
+        }
+        for mut session in sessions {
+            session.leave().await.unwrap();
+        }
+        for name in &names {
+            Session::clear(room.db(), name).await.unwrap();
+        }
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn sessions_are_done_with_before_the_network() {
+        // The network is shared by every test in the process, so this one can't stop it
+        let network = NETWORK.get_or_init(|| boot().expect("Starting the network"));
+        let manager = SessionManager::new(network, None).unwrap();
+        let room = format!("test-{}", Uuid::new_v4());
+        let mut session = manager.join(&room, "alice").await.unwrap();
+        session.write(session.now(), "hello").await.unwrap();
+        session.leave().await.unwrap();
+        Session::clear(manager.database(), &room).await.unwrap();
+        // Each session holds the database, so leaving (or dropping) them all comes first
+        drop(session);
+        drop(manager);
+    }
+
+    #[test]
+    fn the_network_can_only_be_started_once() {
+        NETWORK.get_or_init(|| boot().expect("Starting the network"));
+        let err = boot().map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("already started"), "{}", err);
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn paged_reads_continue_where_each_page_stopped() {
+        let room = TestRoom::new();
+        let mut session = room.join("alice").await;
+        // Short enough that reading a few thousand messages takes several pages
+        session.set_paged_read_budget(Duration::from_millis(1));
+        let count = 5000;
+        write_numbered(&session, at("2022-03-01T12:00:00Z"), count).await;
+
+        let mut read = Vec::new();
+        let mut pages = 0;
+        let mut cursor = None;
+        loop {
+            let page = session
+                .read_range_paged(None, None, cursor.as_deref())
+                .await
+                .unwrap();
+            pages += 1;
+            read.extend(page.messages);
+            cursor = match page.next {
+                None => break,
+                next => next,
+            };
+        }
+        assert!(pages > 1, "read in one page");
+        let expected: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+        assert_eq!(texts(&read), expected);
+        room.clear().await;
+    }
+
+    /// Write each (sender, text) in order, one timestamp apart, from the senders' sessions.
+    async fn write_turns(sessions: &[&Session], turns: &[(usize, &str)]) {
+        for (sender, text) in turns {
+            let session = sessions[*sender];
+            session.write(session.now(), text).await.unwrap();
+        }
+    }
+
+    #[async_std::test]
+    async fn skipping_own_messages_keeps_everyone_elses() {
+        let room = MemoryRoom::new();
+        let clock = TestClock::new(at("2022-03-01T12:00:00Z"));
+        let alice = room.join_with_clock("alice", &clock).await;
+        let bob = room.join_with_clock("bob", &clock).await;
+        let turns = [
+            (0, "a1"),
+            (1, "b1"),
+            (0, "a2"),
+            (0, "a3"),
+            (1, "b2"),
+            (0, "a4"),
+        ];
+        write_turns(&[&alice, &bob], &turns).await;
+
+        let mut iter = MessageIter::new(&alice, None);
+        iter.set_skip_own(true);
+        assert_eq!(iter.next().await.unwrap().text(), Some("b1"));
+        let checkpoint = iter.checkpoint();
+        assert_eq!(iter.next().await.unwrap().text(), Some("b2"));
+
+        // Resuming after b1 skips alice's messages in between, but not b2
+        let mut resumed = MessageIter::resume(&alice, &checkpoint);
+        resumed.set_skip_own(true);
+        assert_eq!(resumed.next().await.unwrap().text(), Some("b2"));
+        // Without skipping, alice still sees her own
+        let mut all = MessageIter::resume(&alice, &checkpoint);
+        assert_eq!(all.next().await.unwrap().text(), Some("a2"));
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn polling_finds_new_messages_without_a_watch() {
+        let room = TestRoom::new();
+        let writer = room.join("alice").await;
+        let reader = room.join("bob").await;
+        let mut iter = MessageIter::new(&reader, None);
+        iter.set_polling(true);
+        iter.set_poll_interval(Duration::from_millis(100));
+
+        let later = ["one".to_string(), "two".to_string()];
+        let (first, ()) = futures::join!(
+            iter.next(),
+            write_after(&writer, Duration::from_millis(350), &later)
+        );
+        assert_eq!(first.unwrap().text(), Some("one"));
+        assert_eq!(iter.next().await.unwrap().text(), Some("two"));
+        assert!(iter.polling);
+        room.clear().await;
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn min_wake_caps_reads_under_a_flood() {
+        let room = TestRoom::new();
+        let writer = room.join("alice").await;
+        let reader = room.join("bob").await;
+        let count = 60;
+        let mut iter = MessageIter::new(&reader, None);
+        iter.set_min_wake(Duration::from_millis(300));
+
+        let flood = async {
+            for i in 0..count {
+                writer.write(writer.now(), &i.to_string()).await.unwrap();
+                async_std::task::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let read = async {
+            let (mut received, mut reads) = (Vec::new(), 0);
+            while received.len() < count {
+                if iter.waiting.is_empty() {
+                    reads += 1;
+                }
+                received.push(iter.next().await.unwrap().text_lossy().into_owned());
+            }
+            (received, reads)
+        };
+        let ((), (received, reads)) = futures::join!(flood, read);
+        let expected: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+        assert_eq!(received, expected);
+        // About 1.2 seconds of writes, read at most every 300ms, rather than once per write
+        assert!(reads <= 8, "{} reads", reads);
+        room.clear().await;
+    }
+
+    #[async_std::test]
+    async fn clearing_removes_only_the_scope() {
+        let room = MemoryRoom::new();
+        let session = room.join("alice").await;
+        let start = at("2022-03-01T12:00:00Z");
+        let ms = |i: i64| start + chrono::Duration::milliseconds(i);
+        write_numbered(&session, start, 10).await;
+
+        // From is included and to is not
+        assert_eq!(
+            session
+                .clear_with(ClearScope::Range(ms(2), ms(5)))
+                .await
+                .unwrap(),
+            3
+        );
+        let left = texts(&session.read_all_detailed().await.unwrap());
+        assert_eq!(left, ["0", "1", "5", "6", "7", "8", "9"]);
+        // An empty range deletes nothing
+        assert_eq!(
+            session
+                .clear_with(ClearScope::Range(ms(8), ms(8)))
+                .await
+                .unwrap(),
+            0
+        );
+
+        assert_eq!(
+            session.clear_with(ClearScope::Before(ms(6))).await.unwrap(),
+            3
+        );
+        let left = texts(&session.read_all_detailed().await.unwrap());
+        assert_eq!(left, ["6", "7", "8", "9"]);
+        assert_eq!(session.room_stats().await.unwrap().message_count, 4);
+
+        assert_eq!(session.clear_with(ClearScope::All).await.unwrap(), 4);
+        assert!(session.read_all_detailed().await.unwrap().is_empty());
+        assert_eq!(session.room_stats().await.unwrap().message_count, 0);
+    }
 
-impl Input {
-    fn new() -> Input {
-        Input {
-            stdin: io::stdin(),
-            line: String::new(),
+    /// The tuple-layer encoding of a tuple of strings: each is 0x02, its bytes, and 0x00.
+    fn packed_strings(parts: &[&str]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for part in parts {
+            bytes.push(0x02);
+            bytes.extend_from_slice(part.as_bytes());
+            bytes.push(0x00);
         }
+        bytes
     }
 
-    async fn next(&mut self) -> io::Result<String> {
-        self.stdin.read_line(&mut self.line).await?;
-        let line = std::mem::take(&mut self.line);
-        Ok(line)
+    #[test]
+    fn key_bytes_keep_their_layout() {
+        let dt = at("2022-03-01T12:00:00.123456Z");
+        assert_eq!(
+            Session::message_key_bytes("general", dt, KeyPrecision::Millis),
+            packed_strings(&["rooms", "general", "messages", "2022-03-01T12:00:00.123Z"])
+        );
+        assert_eq!(
+            Session::message_key_bytes("general", dt, KeyPrecision::Micros),
+            packed_strings(&[
+                "rooms",
+                "general",
+                "messages",
+                "2022-03-01T12:00:00.123456Z"
+            ])
+        );
+        assert_eq!(
+            Session::user_key_bytes("general", "alice"),
+            packed_strings(&["rooms", "general", "users", "alice"])
+        );
+        assert_eq!(
+            Session::messages_subspace("general").bytes(),
+            &packed_strings(&["rooms", "general", "messages"])[..]
+        );
     }
-}
 
-pub struct Session {
-    db: foundationdb::Database,
-    room: String,
-    username: String,
-    id: Option<Uuid>,
-}
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn join_time_comes_from_the_clock() {
+        let room = TestRoom::new();
+        let clock = TestClock::new(at("2022-03-01T12:00:00Z"));
+        let session = room.join_with_clock("alice", &clock).await;
 
-impl Session {
-    fn user_key<'a>(room: &'a str, username: &'a str) -> (&'a str, &'a str, &'a str, &'a str) {
-        ("rooms", room, "users", username)
-    }
+        let info = session.user_info("alice").await.unwrap().unwrap();
+        assert_eq!(info.joined, Some(at("2022-03-01T12:00:00Z")));
 
-    fn date_string(dt: DateTime) -> String {
-        dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        room.clear().await;
     }
 
-    async fn init_tx(tx: &Transaction, room: &str, username: &str, uuid: Uuid) -> AnyResult<()> {
-        let key = Session::user_key(room, username);
-        let val = tx.get(&pack(&key), false).await?;
-
-        if let Some(_taken_id) = val {
-            return Err(anyhow::format_err!(
-                "Username {} already taken in room {}!",
-                username,
-                room
-            )
-            .into());
-        };
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn firehose_starts_at_the_clock() {
+        let room = TestRoom::new();
+        let writer = room.join("alice").await;
+        // Long before the system time, so a firehose started then would skip this message
+        writer
+            .write(at("2021-06-01T00:00:00Z"), "from the past")
+            .await
+            .unwrap();
 
-        tx.set(&pack(&key), &pack(&uuid));
+        let clock = TestClock::new(at("2021-01-01T00:00:00Z"));
+        {
+            let messages = Session::firehose(room.db(), &clock)
+                .try_filter(|(name, _, _)| futures::future::ready(*name == room.room));
+            pin_mut!(messages);
+            let found = async_std::future::timeout(Duration::from_secs(10), messages.next())
+                .await
+                .expect("Waiting for the firehose")
+                .unwrap()
+                .unwrap();
+            assert_eq!(found.2, "from the past");
+        }
 
-        Ok(())
+        room.clear().await;
     }
 
-    async fn init(db: Database, room: String, username: String) -> AnyResult<Self> {
-        let id = Uuid::new_v4();
+    /// Write "on time" now, then after delay "late", stamped 50ms before it, as from a sender
+    /// whose clock is behind.
+    async fn write_late(session: &Session, delay: Duration) {
+        let on_time = session.now();
+        session.write(on_time, "on time").await.unwrap();
+        async_std::task::sleep(delay).await;
+        let late = on_time - chrono::Duration::milliseconds(50);
+        session.write(late, "late").await.unwrap();
+    }
 
-        db.transact_boxed_local(
-            (room.as_ref(), username.as_ref()),
-            move |tx: &Transaction, (room, username)| {
-                Session::init_tx(tx, room, username, id).boxed_local()
-            },
-            CHAT_OPTS,
-        )
-        .await?;
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn reorder_window_sorts_late_messages_into_place() {
+        let room = TestRoom::new();
+        let writer = room.join("alice").await;
+        let reader = room.join("bob").await;
+        let mut iter = MessageIter::new(&reader, None);
+        iter.set_reorder_window(Duration::from_millis(500));
 
-        Ok(Session {
-            db,
-            room,
-            username,
-            id: Some(id),
-        })
+        let (first, ()) =
+            futures::join!(iter.next(), write_late(&writer, Duration::from_millis(100)));
+        assert_eq!(first.unwrap().text(), Some("late"));
+        assert_eq!(iter.next().await.unwrap().text(), Some("on time"));
+        room.clear().await;
     }
 
-    pub async fn clear(db: &Database, room: &str) -> FdbResult<()> {
-        let space = Subspace::from(&("rooms", &room));
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn late_messages_are_missed_without_a_reorder_window() {
+        let room = TestRoom::new();
+        let writer = room.join("alice").await;
+        let reader = room.join("bob").await;
+        let mut iter = MessageIter::new(&reader, None);
 
-        db.transact_boxed_local(
-            space,
-            |tx, space| {
-                tx.clear_subspace_range(space);
-                futures::future::ready(Ok(())).boxed_local()
-            },
-            CHAT_OPTS,
-        )
-        .await
+        let (first, ()) =
+            futures::join!(iter.next(), write_late(&writer, Duration::from_millis(100)));
+        assert_eq!(first.unwrap().text(), Some("on time"));
+        let next = async_std::future::timeout(Duration::from_millis(500), iter.next()).await;
+        assert!(next.is_err(), "Late message returned after a later one");
+        room.clear().await;
     }
 
-    async fn leave_tx(tx: &Transaction, id: Uuid, room: &str, username: &str) -> AnyResult<()> {
-        let key = ("rooms", room, "users", username);
-        let keyp = pack(&key);
-        let val = tx.get(&keyp, true).await?;
+    #[test]
+    fn environment_variables_fill_in_missing_flags() {
+        let env = [
+            ("FDBCHAT_ROOM", "from-env"),
+            ("FDBCHAT_COALESCE_MS", "25"),
+            ("FDBCHAT_RING", "5"),
+        ];
 
-        let dbid: Uuid = match val {
-            Some(v) => unpack(&v).map_err(anyhow::Error::from)?,
-            None => return Err(anyhow::format_err!("Key is unset somehow").into()),
-        };
+        let args = parse_args(&env, &[]).unwrap();
+        assert_eq!(args.room.as_deref(), Some("from-env"));
+        assert_eq!(args.chat.follow.coalesce_ms, 25);
 
-        if dbid != id {
-            return Err(anyhow::format_err!("Unexpected ID").into());
+        let args = parse_args(&env, &["--room", "from-flag", "tail"]).unwrap();
+        assert_eq!(args.room.as_deref(), Some("from-flag"));
+        match args.command {
+            Some(Command::Tail(tail)) => {
+                assert_eq!(tail.ring, Some(5));
+                assert_eq!(tail.follow.coalesce_ms, 25);
+            }
+            command => panic!("Parsed {:?}, not tail", command),
         }
+    }
 
-        tx.clear(&keyp);
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn resuming_after_a_crash_delivers_each_message_once() {
+        let room = TestRoom::new();
+        let session = room.join("alice").await;
+        let count = 10;
+        write_numbered(&session, at("2022-03-01T12:00:00Z"), count).await;
 
-        Ok(())
+        for crash_after in 0..count {
+            let mut delivered = Vec::new();
+            let mut saved = String::new();
+            {
+                let mut iter = MessageIter::new(&session, None);
+                // Everything is read ahead, and lost from the buffer in the crash
+                iter.set_batch_size(count);
+                for _ in 0..crash_after {
+                    delivered.push(iter.next().await.unwrap().text_lossy().into_owned());
+                    saved = serde_json::to_string(&iter.checkpoint()).unwrap();
+                }
+                if crash_after > 0 {
+                    assert_eq!(iter.waiting.len(), count - crash_after);
+                }
+            }
+
+            let checkpoint: Checkpoint = if saved.is_empty() {
+                Checkpoint::default()
+            } else {
+                serde_json::from_str(&saved).unwrap()
+            };
+            let mut resumed = MessageIter::resume(&session, &checkpoint);
+            for _ in crash_after..count {
+                delivered.push(resumed.next().await.unwrap().text_lossy().into_owned());
+            }
+            let expected: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+            assert_eq!(delivered, expected, "Crashing after {}", crash_after);
+        }
+        room.clear().await;
     }
 
-    /// Leave the chat room and close the session.
-    pub async fn leave(self) -> AnyResult<()> {
-        let Session {
-            db,
-            room,
-            username,
-            id,
-        } = self;
-        let id = match id {
-            None => return Ok(()),
-            Some(id) => id,
-        };
-        db.transact_boxed_local(
-            (room, username, id),
-            |tx: &Transaction, (room, username, id)| {
-                Session::leave_tx(tx, *id, room, username).boxed_local()
-            },
-            CHAT_OPTS,
-        )
-        .await
+    /// How many entries the index by sender has for user.
+    async fn index_entries(room: &TestRoom, user: &str) -> usize {
+        let space = Subspace::from(&("rooms", &room.room, "by_user", user));
+        let trx = room.db().create_trx().unwrap();
+        let kvs = trx
+            .get_range(&RangeOption::from(space.range()), 1, false)
+            .await
+            .unwrap();
+        kvs.len()
     }
 
-    fn message_key(room: &str, dt: DateTime) -> (&str, &str, &str, String) {
-        ("rooms", room, "messages", Session::date_string(dt))
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn user_index_matches_messages_under_deletes() {
+        let room = TestRoom::new();
+        // alice joins before the index is enabled, and must keep it up to date anyway
+        let alice = room.join("alice").await;
+        let bob = room
+            .join_with_clock("bob", &TestClock::new(at("2022-03-01T11:00:00Z")))
+            .await;
+        bob.enable_user_index().await.unwrap();
+
+        let start = at("2022-03-01T12:00:00Z");
+        let ms = |i: i64| start + chrono::Duration::milliseconds(i);
+        let turns = [
+            (&alice, "a0"),
+            (&bob, "b1"),
+            (&alice, "a2"),
+            (&alice, "a3"),
+            (&bob, "b4"),
+            (&alice, "a5"),
+            (&alice, "a6"),
+            (&bob, "b7"),
+        ];
+        for (i, (session, text)) in turns.iter().enumerate() {
+            session.write(ms(i as i64), text).await.unwrap();
+        }
+        assert_eq!(index_entries(&room, "alice").await, 5);
+
+        // Deleting a range removes its entries; replacing a5 with bob's leaves alice's stale
+        assert_eq!(
+            alice
+                .clear_with(ClearScope::Range(ms(1), ms(3)))
+                .await
+                .unwrap(),
+            2
+        );
+        let replacement = message(
+            "2022-03-01T12:00:00.005Z",
+            "bob",
+            MessageKind::Text,
+            Encoding::Utf8,
+            b"b5",
+        );
+        bob.import(&[replacement]).await.unwrap();
+        assert_eq!(index_entries(&room, "alice").await, 4);
+
+        for session in [&alice, &bob] {
+            let from_alice = texts(&session.read_from_user("alice", 10).await.unwrap());
+            assert_eq!(from_alice, ["a6", "a3", "a0"]);
+            let from_bob = texts(&session.read_from_user("bob", 10).await.unwrap());
+            assert_eq!(from_bob, ["b7", "b5", "b4"]);
+        }
+        room.clear().await;
     }
 
-    fn message_recent_key(room: &str) -> (&str, &str, &str) {
-        ("rooms", room, "most_recent_message")
+    #[test]
+    fn lines_lose_byte_order_marks_and_carriage_returns() {
+        let clean = |line: &str, first| Input::clean_line(line.to_string(), first);
+        assert_eq!(clean("\u{feff}hello\r\n", true), "hello\n");
+        assert_eq!(clean("hello\r\n", false), "hello\n");
+        assert_eq!(clean("hello\n", true), "hello\n");
+        assert_eq!(clean("\r\n", false), "\n");
+        // Only the end of the line, and only the first line's mark
+        assert_eq!(clean("a\rb\n", false), "a\rb\n");
+        assert_eq!(clean("\u{feff}hello\n", false), "\u{feff}hello\n");
+        // The end of input
+        assert_eq!(clean("", true), "");
     }
 
-    pub async fn write(&self, dt: DateTime, message: &str) -> AnyResult<()> {
-        let message_key = Session::message_key(&self.room, dt);
-        let dt_key = message_key.3.as_ref();
-        let recent_key = Session::message_recent_key(&self.room);
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn returned_watches_fire_under_contention() {
+        let room = TestRoom::new();
+        let writer = room.join("alice").await;
+        let reader = room.join("bob").await;
+        let count = 50;
 
-        self.db
-            .transact_boxed_local(
-                (pack(&message_key), pack(&recent_key), dt_key, message),
-                |tx, (message_key, recent_key, dt_key, message)| {
-                    async move {
-                        tx.set(message_key, message.as_bytes());
-                        tx.set(recent_key, dt_key);
-                        Ok(())
+        let flood = async {
+            for i in 0..count {
+                writer.write(writer.now(), &i.to_string()).await.unwrap();
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let read = async {
+            let (mut received, mut last, mut watches) = (Vec::new(), None, 0);
+            while received.len() < count {
+                match reader.messages_or_watch(last, None).await.unwrap() {
+                    Ok(messages) => {
+                        if let Some((ts, _)) = messages.last() {
+                            last = Some(*ts);
+                        }
+                        received.extend(messages.into_iter().map(|(_, text)| text));
                     }
-                    .boxed_local()
-                },
-                CHAT_OPTS,
-            )
-            .await
+                    Err(watch) => {
+                        watches += 1;
+                        // A dead watch would never fire, however much is written
+                        async_std::future::timeout(Duration::from_secs(5), watch)
+                            .await
+                            .expect("Watch never fired")
+                            .unwrap();
+                    }
+                }
+            }
+            (received, watches)
+        };
+        let ((), (received, watches)) = futures::join!(flood, read);
+        let expected: Vec<String> = (0..count).map(|i| i.to_string()).collect();
+        assert_eq!(received, expected);
+        assert!(watches > 0);
+        room.clear().await;
     }
 
-    /// messages_or_watch returns a list of messages, or if none are available, a watch that will
-    /// trigger when at least one message is available.
-    ///
-    /// last: If None, start with the first message; otherwise, start after this message.
-    /// limit: if None, returns all waiting messages; otherwise, returns up to limit messages.
-    pub async fn messages_or_watch(
-        &self,
-        last: Option<DateTime>,
-        limit: Option<usize>,
-    ) -> AnyResult<Result<Vec<(DateTime, String)>, impl Future<Output = FdbResult<()>>>> {
-        let space = Subspace::from(&("rooms", &self.room, "messages"));
-        let recent_key = Session::message_recent_key(&self.room);
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn latest_falls_back_when_the_latest_is_deleted() {
+        let room = TestRoom::new();
+        let session = room.join("alice").await;
+        assert_eq!(session.latest().await.unwrap(), None);
 
-        let mut r: RangeOption = match last {
-            None => RangeOption::from(&space),
-            Some(dt) => {
-                let (_begin, end) = space.range();
-                let last_key = pack(&Session::message_key(&self.room, dt));
-                let ks = KeySelector::first_greater_than(last_key);
-                RangeOption::from((ks, KeySelector::first_greater_or_equal(end)))
-            }
-        };
+        let start = at("2022-03-01T12:00:00Z");
+        let ms = |i: i64| start + chrono::Duration::milliseconds(i);
+        for i in 0..3 {
+            session.write(ms(i), &i.to_string()).await.unwrap();
+        }
+        assert_eq!(
+            session.latest().await.unwrap(),
+            Some((ms(2), "2".to_string()))
+        );
 
-        r.limit = limit;
+        // most_recent_message still points at the deleted message
+        session
+            .clear_with(ClearScope::Range(ms(2), ms(3)))
+            .await
+            .unwrap();
+        let recent = Session::date_string(ms(2), KeyPrecision::default());
+        assert_eq!(session.latest_cursor().await.unwrap(), Some(recent));
+        assert_eq!(
+            session.latest().await.unwrap(),
+            Some((ms(1), "1".to_string()))
+        );
 
-        let kvs: Result<FdbValues, _> = self
-            .db
-            .transact_boxed_local::<_, _, _, FdbError>(
-                (&r, pack(&recent_key)),
-                |tx, (r, recent_key)| {
-                    async move {
-                        let kvs = tx.get_range(r, 1, false).await;
-                        match kvs {
-                            Err(e) => Err(e),
-                            Ok(kv) if kv.is_empty() => Ok(Err(tx.watch(recent_key))),
-                            Ok(kv) => Ok(Ok(kv)),
-                        }
-                    }
-                    .boxed_local()
-                },
-                CHAT_OPTS,
-            )
-            .await?;
+        session.clear_with(ClearScope::All).await.unwrap();
+        assert_eq!(session.latest().await.unwrap(), None);
+        room.clear().await;
+    }
 
-        match kvs {
-            Ok(kvs) => kvs
-                .iter()
-                .map(Session::parse_kv)
-                .collect::<AnyResult<Vec<_>>>()
-                .map(Ok),
-            Err(w) => Ok(Err(w)),
+    #[test]
+    fn send_at_must_be_an_rfc3339_time() {
+        let args = parse_args(&[], &["send", "--at", "2022-03-01T13:00:00+01:00", "hi"]).unwrap();
+        match args.command {
+            Some(Command::Send { at: sent_at, .. }) => {
+                assert_eq!(sent_at, Some(at("2022-03-01T12:00:00Z")))
+            }
+            command => panic!("Parsed {:?}, not send", command),
+        }
+        for bad in ["yesterday", "2022-03-01", "2022-03-01 12:00:00"] {
+            assert!(parse_args(&[], &["send", "--at", bad, "hi"]).is_err());
         }
     }
 
-    fn parse_kv(kv: &FdbKeyValue) -> AnyResult<(DateTime, String)> {
-        let (_, _, _, kdt): (String, String, String, String) =
-            unpack(kv.key()).context("Unpacking")?;
-        let fixed_dt = chrono::DateTime::parse_from_rfc3339(&kdt).context("Parsing date")?;
-        let dt = DateTime::from(fixed_dt);
+    #[test]
+    fn sending_out_of_place_warns() {
+        let now = at("2022-03-01T12:00:00Z");
+        let latest = Some(at("2022-03-01T11:00:00Z"));
+        assert!(send_time_warnings(now, now, latest).is_empty());
+        assert!(send_time_warnings(at("2022-03-01T11:30:00Z"), now, None).is_empty());
 
-        let msg = str::to_string(from_utf8(kv.value()).context("Parsing date")?);
+        let future = send_time_warnings(at("2022-03-01T13:00:00Z"), now, latest);
+        assert_eq!(future.len(), 1);
+        assert!(future[0].contains("in the future"));
 
-        Ok((dt, msg))
+        let early = send_time_warnings(at("2022-03-01T10:00:00Z"), now, latest);
+        assert_eq!(early.len(), 1);
+        assert!(early[0].contains("before the room's latest message"));
     }
-}
-
-pub struct MessageIter<'a> {
-    session: &'a Session,
-    last: Option<DateTime>,
-    waiting: VecDeque<(DateTime, String)>,
-}
 
-impl<'a> MessageIter<'a> {
-    pub fn new(session: &'a Session, last: Option<DateTime>) -> Self {
-        MessageIter {
-            session,
-            last,
-            waiting: VecDeque::new(),
+    #[test]
+    fn no_trim_keeps_whitespace_but_not_line_endings() {
+        assert_eq!(
+            message_text("  two spaces in  \n", false, true),
+            Some("two spaces in")
+        );
+        assert_eq!(
+            message_text("  two spaces in  \n", false, false),
+            Some("  two spaces in  ")
+        );
+        assert_eq!(
+            message_text("  indented\n  code  ", true, true),
+            Some("  indented\n  code")
+        );
+        assert_eq!(
+            message_text("  indented\n  code  ", true, false),
+            Some("  indented\n  code  ")
+        );
+        for trim in [true, false] {
+            assert_eq!(message_text("   \n", false, trim), None);
+            assert_eq!(message_text("", false, trim), None);
+            assert_eq!(message_text(" \n ", true, trim), None);
         }
+        assert!(parse_args(&[], &["--no-trim"]).unwrap().chat.no_trim);
     }
 
-    pub async fn next(&mut self) -> AnyResult<(DateTime, String)> {
-        if let Some(dm) = self.waiting.pop_front() {
-            return Ok(dm);
-        }
+    #[test]
+    fn cleanup_errors_dont_hide_the_command_error() {
+        let failed = |what: &str| Err(anyhow::format_err!("{} failed", what));
+        let message = |result: anyhow::Result<()>| result.unwrap_err().to_string();
 
-        // None left in the past; let's see if any are waiting, and wait if they are
-        let messages = loop {
-            let msg_res = self.session.messages_or_watch(self.last, Some(3)).await?;
-            match msg_res {
-                Ok(v) => {
-                    log::info!("MessageIter: Got {} messages", v.len());
-                    break v;
-                }
-                Err(w) => {
-                    log::info!("MessageIter: Waiting");
-                    w.await?
-                }
-            }
+        // A loop error and a leave error: the loop's is what ended the session
+        assert_eq!(
+            message(after_cleanup(failed("loop"), failed("leave"), "leave")),
+            "loop failed"
+        );
+        assert_eq!(
+            message(after_cleanup(failed("loop"), Ok(()), "leave")),
+            "loop failed"
+        );
+        // Without a loop error, failing to leave is the error
+        assert_eq!(
+            message(after_cleanup(Ok(()), failed("leave"), "leave")),
+            "leave failed"
+        );
+        assert!(after_cleanup(Ok(()), Ok(()), "leave").is_ok());
+
+        // Flushing and then leaving, as run does
+        let flushed = after_cleanup(failed("loop"), failed("flush"), "flush");
+        assert_eq!(
+            message(after_cleanup(flushed, failed("leave"), "leave")),
+            "loop failed"
+        );
+        let flushed = after_cleanup(Ok(()), failed("flush"), "flush");
+        assert_eq!(
+            message(after_cleanup(flushed, failed("leave"), "leave")),
+            "flush failed"
+        );
+    }
+
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn write_if_unchanged_only_writes_over_the_expected_latest() {
+        let room = TestRoom::new();
+        let alice = room.join("alice").await;
+        let bob = room.join("bob").await;
+        let start = at("2022-03-01T12:00:00Z");
+        let ms = |i: i64| start + chrono::Duration::milliseconds(i);
+
+        alice
+            .write_if_unchanged(None, ms(0), "first")
+            .await
+            .unwrap();
+        alice
+            .write_if_unchanged(Some(ms(0)), ms(1), "second")
+            .await
+            .unwrap();
+
+        let room_changed = |err: AnyErr| match err {
+            AnyErr::Any(e) => e.downcast::<RoomChanged>().expect("RoomChanged"),
+            AnyErr::Fdb(e) => panic!("Failed with {}, not RoomChanged", e),
         };
-        self.waiting.extend(messages);
+        // bob read the room before alice's second message
+        let err = bob
+            .write_if_unchanged(Some(ms(0)), ms(2), "stale")
+            .await
+            .unwrap_err();
+        let changed = room_changed(err);
+        assert_eq!(changed.expected, Some(ms(0)));
+        assert_eq!(changed.found, Some(ms(1)));
+        let err = bob
+            .write_if_unchanged(None, ms(2), "stale")
+            .await
+            .unwrap_err();
+        assert_eq!(room_changed(err).found, Some(ms(1)));
 
-        let (last_dt, _) = self.waiting.back().expect("Messages expected after watch");
-        self.last = Some(*last_dt);
+        let messages = texts(&alice.read_all_detailed().await.unwrap());
+        assert_eq!(messages, ["first", "second"]);
+        room.clear().await;
+    }
 
-        let msg = self
-            .waiting
-            .pop_front()
-            .expect("Really expected a front message after waiting for watch and extending");
+    #[test]
+    fn grouped_messages_line_up_under_their_timestamp() {
+        let ts = at("2022-03-01T12:00:00.123Z");
+        let lines: Vec<String> = ["one", "two\nlines", "three"]
+            .iter()
+            .map(|body| format_message(ts, MessageKind::Text, Some("alice"), body, None))
+            .collect();
+        assert_eq!(lines[0], "2022-03-01 12:00:00.123 UTC: one");
 
-        Ok(msg)
+        let blank = " ".repeat(ts.to_string().len());
+        assert_eq!(
+            blank_timestamp(lines[1].clone(), ts),
+            format!("{}: two\n{}  lines", blank, blank)
+        );
+        assert_eq!(
+            blank_timestamp(lines[2].clone(), ts),
+            format!("{}: three", blank)
+        );
+
+        let action = format_message(ts, MessageKind::Action, Some("bob"), "waves", None);
+        assert_eq!(
+            blank_timestamp(action, ts),
+            format!("{}: * bob waves", blank)
+        );
+        // A line starting with another timestamp is left alone
+        let later = ts + chrono::Duration::milliseconds(1);
+        assert_eq!(blank_timestamp(lines[0].clone(), later), lines[0]);
     }
-}
 
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
-    #[clap(short, long)]
-    username: String,
+    #[test]
+    fn stored_keys_and_values_decode_to_messages() {
+        let ts = at("2022-03-01T12:00:00.123Z");
+        let key = Session::message_key_bytes("general", ts, KeyPrecision::Millis);
+        let value = TupleCodec.encode(&text_message("alice", "hello"));
+        let msg = Session::parse_message_with(&TupleCodec, &key, &value).unwrap();
+        assert_eq!(msg.ts, ts);
+        assert_eq!(msg.sender.as_deref(), Some("alice"));
+        assert_eq!(msg.kind, MessageKind::Text);
+        assert_eq!(msg.text(), Some("hello"));
 
-    #[clap(short, long)]
-    room: String,
+        // Keys outside a room's messages don't decode
+        let user_key = Session::user_key_bytes("general", "alice");
+        assert!(Session::parse_message_with(&TupleCodec, &user_key, &value).is_err());
+    }
 
-    #[clap(short, long, parse(from_occurrences))]
-    debug: usize,
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn range_scans_decode_with_the_sessions_codec() {
+        let room = TestRoom::new();
+        let mut session = room.join("alice").await;
+        session.set_codec(Box::new(FlipCodec));
+        let ts = at("2022-03-01T12:00:00.123Z");
+        session.write(ts, "hello").await.unwrap();
 
-    #[clap(long)]
-    clear: bool,
-}
+        let (begin, end) = Session::messages_subspace(&room.room).range();
+        let trx = room.db().create_trx().unwrap();
+        let kvs = trx
+            .get_range(&RangeOption::from((begin, end)), 1, false)
+            .await
+            .unwrap();
+        let messages: Vec<Message> = kvs
+            .iter()
+            .map(|kv| session.decode_kv(kv).unwrap())
+            .collect();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].ts, ts);
+        assert_eq!(messages[0].sender.as_deref(), Some("alice"));
+        assert_eq!(messages[0].text(), Some("hello"));
+        room.clear().await;
+    }
 
-async fn message_print_loop(session: &Session) -> anyhow::Result<()> {
-    let mut iter = MessageIter::new(session, None);
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn messages_survive_a_clean_shutdown() {
+        let room = TestRoom::new();
+        let clock = TestClock::new(at("2022-03-01T12:00:00Z"));
+        let mut session = room.join_with_clock("alice", &clock).await;
+        let terminal = Terminal {
+            prompt: None,
+            editing: None,
+            wrap: None,
+        };
+        // The last line has no newline, as when input ends partway through one
+        let input = io::Cursor::new("one\n/multiline\ntwo\nthree\n.\nfour");
+        let channel = ActiveChannel::default();
+        let sent = send_loop(
+            &session,
+            &terminal,
+            Input::from_reader(&terminal, input),
+            None,
+            &channel,
+            SendOptions {
+                trim: true,
+                ..Default::default()
+            },
+        );
+        let sent = async_std::future::timeout(Duration::from_secs(10), sent)
+            .await
+            .expect("Sending didn't stop at the end of input");
+        shut_down(&mut session, sent).await.unwrap();
+        assert!(!session.is_active());
 
-    loop {
-        let (dt, msg) = iter.next().await?;
-        println!("{}: {}", dt, msg);
+        let reader = room.join("bob").await;
+        let messages = texts(&reader.read_all_detailed().await.unwrap());
+        assert_eq!(messages, ["one", "two\nthree", "four"]);
+        room.clear().await;
     }
-}
 
-async fn send_loop(session: &Session) -> anyhow::Result<()> {
-    let mut input = Input::new();
+    #[async_std::test]
+    #[ignore = "needs a FoundationDB cluster"]
+    async fn switching_channels_moves_the_cursor() {
+        let room = TestRoom::new();
+        let clock = TestClock::new(at("2022-03-01T12:00:00Z"));
+        let writer = room.join_with_clock("alice", &clock).await;
+        let reader = room.join("bob").await;
+        let room_ts = writer.now();
+        writer.write(room_ts, "in the room").await.unwrap();
+        let side_ts = writer.now();
+        writer
+            .write_to_channel("side", side_ts, "old news")
+            .await
+            .unwrap();
 
-    loop {
-        let line = input.next().await.context("Failed getting input line")?;
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
-        }
-        let now = chrono::Utc::now();
-        session.write(now, line).await?;
+        let active = ActiveChannel::default();
+        let mut iter = MessageIter::new(&reader, None);
+        iter.follow_channel(&active);
+        assert_eq!(iter.next().await.unwrap().text(), Some("in the room"));
+        let room_cursor = iter.cursor();
+        let expected = Session::date_string(room_ts, KeyPrecision::default());
+        assert_eq!(room_cursor, Some(expected));
+
+        // Once switched, even before anything new arrives there, the cursor is of the new
+        // channel's latest message
+        active.switch(Some("side".to_string()));
+        let next = async_std::future::timeout(Duration::from_millis(300), iter.next()).await;
+        assert!(next.is_err(), "Returned the channel's history");
+        assert_eq!(iter.channel(), Some("side"));
+        let side_cursor = Session::date_string(side_ts, KeyPrecision::default());
+        assert_eq!(iter.cursor(), Some(side_cursor.clone()));
+        assert_eq!(iter.checkpoint().cursor, Some(side_cursor));
+
+        // And back again
+        active.switch(None);
+        let next = async_std::future::timeout(Duration::from_millis(300), iter.next()).await;
+        assert!(next.is_err(), "Returned the room's history");
+        assert_eq!(iter.cursor(), room_cursor);
+        room.clear().await;
     }
-}
 
-async fn signal_loop() -> anyhow::Result<()> {
-    let mut signals = Signals::new(&[SIGHUP, SIGTERM, SIGINT, SIGQUIT])?;
-    let handle = signals.handle();
+    #[test]
+    fn live_reactions_reprint_only_changed_messages() {
+        let live = LiveReactions::default();
+        let first = at("2022-03-01T12:00:00Z");
+        let second = at("2022-03-01T12:00:01Z");
+        live.add(first, "first".to_string());
+        live.add(second, "second".to_string());
+        assert_eq!(live.timestamps(), [first, second]);
 
-    while let Some(signal) = signals.next().await {
-        match signal {
-            SIGHUP => {
-                log::warn!("Received SIGHUP, exiting.");
-                break;
-            }
-            SIGTERM => {
-                log::warn!("Received SIGTERM, exiting.");
-                break;
-            }
-            SIGINT => {
-                log::info!("Received SIGINT, exiting.");
-                break;
-            }
-            SIGQUIT => {
-                log::warn!("Received SIGQUIT, exiting.");
-                break;
-            }
-            _ => unreachable!(),
+        let thumbs = |users: &[&str]| -> Vec<(String, String)> {
+            users
+                .iter()
+                .map(|user| (user.to_string(), "👍".to_string()))
+                .collect()
+        };
+        assert_eq!(live.update(first, Vec::new()), None);
+        assert_eq!(
+            live.update(first, thumbs(&["alice", "bob"])),
+            Some("first [👍 x2 (alice, bob)]".to_string())
+        );
+        assert_eq!(live.update(first, thumbs(&["alice", "bob"])), None);
+        assert_eq!(
+            live.update(first, Vec::new()),
+            Some("first [no reactions]".to_string())
+        );
+        // Messages no longer shown aren't reprinted
+        assert_eq!(
+            live.update(at("2022-03-01T11:00:00Z"), thumbs(&["alice"])),
+            None
+        );
+
+        for i in 0..LIVE_REACTIONS_SHOWN {
+            live.add(
+                second + chrono::Duration::seconds(i as i64 + 1),
+                i.to_string(),
+            );
         }
+        assert_eq!(live.timestamps().len(), LIVE_REACTIONS_SHOWN);
+        assert_eq!(live.update(first, thumbs(&["alice"])), None);
     }
 
-    handle.close();
-    Ok(())
-}
+    #[async_std::test]
+    async fn reacting_fires_the_reactions_watch() {
+        let room = MemoryRoom::new();
+        let alice = room.join("alice").await;
+        let bob = room.join("bob").await;
+        let ts = at("2022-03-01T12:00:00Z");
+        alice.write(ts, "hi").await.unwrap();
 
-async fn main_loop() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let mut builder = env_logger::Builder::from_env("LOGLEVEL");
-    match args.debug {
-        0 => {}
-        1 => {
-            builder.filter_level(log::LevelFilter::Info);
-        }
-        _ => {
-            builder.filter_level(log::LevelFilter::Debug);
-        }
+        let (version, watch) = bob.watch_reactions().await.unwrap();
+        assert_eq!(version, 0);
+        assert!(alice.react(ts, "👍").await.unwrap());
+        async_std::future::timeout(Duration::from_secs(5), watch)
+            .await
+            .expect("Watch never fired")
+            .unwrap();
+
+        let (version, watch) = bob.watch_reactions().await.unwrap();
+        assert_eq!(version, 1);
+        alice.unreact(ts, "👍").await.unwrap();
+        async_std::future::timeout(Duration::from_secs(5), watch)
+            .await
+            .expect("Watch never fired")
+            .unwrap();
+        assert_eq!(bob.watch_reactions().await.unwrap().0, 2);
+
+        // Reacting to a message that doesn't exist changes nothing
+        let missing = ts + chrono::Duration::seconds(1);
+        assert!(!alice.react(missing, "👍").await.unwrap());
+        assert_eq!(bob.watch_reactions().await.unwrap().0, 2);
+    }
+
+    #[test]
+    fn command_args_need_a_space_or_nothing_after_the_command() {
+        assert_eq!(command_args("/seen", SEEN_COMMAND), Some(""));
+        assert_eq!(command_args("/seen alice", SEEN_COMMAND), Some(" alice"));
+        assert_eq!(command_args("/seenalice", SEEN_COMMAND), None);
+        assert_eq!(command_args("/reactions 12", REACT_COMMAND), None);
+        assert_eq!(
+            command_args("/reactions 12", REACTIONS_COMMAND),
+            Some(" 12")
+        );
+        assert_eq!(command_args("hi /seen", SEEN_COMMAND), None);
     }
-    builder.init();
 
-    let db = foundationdb::Database::default()?;
-    if args.clear {
-        Session::clear(&db, &args.room).await?;
+    #[test]
+    fn format_reactions_groups_by_emoji() {
+        let reactions: Vec<(String, String)> = [("bob", "👍"), ("alice", "🎉"), ("carol", "👍")]
+            .iter()
+            .map(|(user, emoji)| (user.to_string(), emoji.to_string()))
+            .collect();
+        assert_eq!(
+            format_reactions(&reactions),
+            vec![
+                "🎉 x1 (alice)".to_string(),
+                "👍 x2 (bob, carol)".to_string()
+            ]
+        );
+        assert!(format_reactions(&[]).is_empty());
     }
 
-    let session = Session::init(db, args.room, args.username).await?;
+    #[test]
+    fn quote_text_prefixes_each_line() {
+        assert_eq!(quote_text("hi"), "> hi\n");
+        assert_eq!(quote_text("one\ntwo\n"), "> one\n> two\n");
+        assert_eq!(quote_text(""), "");
+    }
 
-    {
-        let sender = send_loop(&session);
-        let receiver = message_print_loop(&session);
-        let signals = signal_loop();
-        pin_mut!(sender);
-        pin_mut!(receiver);
-        pin_mut!(signals);
-
-        match select(signals, select(sender, receiver)).await {
-            // Got a signal, so we're done
-            Either::Left((signal_result, _other_future)) => signal_result?,
-            // Either sender or receiver returned, so we take the first of the
-            // two and short-circuit on the error
-            Either::Right((inner, _other_future)) => inner.factor_first().0?,
+    #[test]
+    fn parse_duration_sums_units() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(
+            parse_duration(" 2d "),
+            Ok(Duration::from_secs(2 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("1m1m"), Ok(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn parse_duration_rejects_malformed_durations() {
+        for bad in [
+            "",
+            "  ",
+            "5",
+            "h",
+            "5x",
+            "1h30",
+            "1.5h",
+            "-1m",
+            "99999999999999999999s",
+        ] {
+            assert!(parse_duration(bad).is_err(), "{:?}", bad);
         }
-    };
+        assert!(parse_duration(&format!("{}d", u64::MAX)).is_err());
+    }
 
-    session.leave().await?;
+    #[test]
+    fn canonical_name_lowercases() {
+        assert_eq!(canonical_name("Alice"), "alice");
+        assert_eq!(canonical_name("ÉMILE"), "émile");
+        assert_eq!(canonical_name("general"), "general");
+        assert_eq!(canonical_name("Café"), canonical_name("CAFÉ"));
+    }
 
-    Ok(())
-}
+    #[test]
+    fn wrap_line_breaks_between_words() {
+        assert_eq!(
+            wrap_line("the quick brown fox", 10),
+            ["the quick", "brown fox"]
+        );
+        assert_eq!(wrap_line("short", 10), ["short"]);
+        assert_eq!(wrap_line("", 10), [""]);
+        // Exactly the width fits
+        assert_eq!(wrap_line("abcde fghij", 5), ["abcde", "fghij"]);
+    }
 
-#[async_std::main]
-async fn main() -> anyhow::Result<()> {
-    let network = unsafe { foundationdb::boot() };
+    #[test]
+    fn wrap_line_splits_long_words() {
+        assert_eq!(
+            wrap_line("see https://example.com/a/b ok", 8),
+            ["see", "https://", "example.", "com/a/b", "ok"]
+        );
+        // Width is counted in characters, not bytes
+        assert_eq!(wrap_line("ééééé ab", 3), ["ééé", "éé", "ab"]);
+    }
 
-    let result = main_loop().await;
+    #[test]
+    fn wrapped_messages_line_up_under_the_first_line() {
+        let ts = at("2022-03-01T12:00:00.123Z");
+        let prefix = format!("{}: ", ts);
+        let indent = " ".repeat(prefix.len());
+        let cols = prefix.len() + MIN_WRAP_WIDTH;
+        let body = "one two three four five six seven\neight";
+        assert_eq!(
+            format_message(ts, MessageKind::Text, None, body, Some(cols)),
+            format!(
+                "{}one two three four\n{}five six seven\n{}eight",
+                prefix, indent, indent
+            )
+        );
+        // Too narrow to wrap usefully, so left as-is
+        assert_eq!(
+            format_message(ts, MessageKind::Text, None, body, Some(cols - 1)),
+            format_message(ts, MessageKind::Text, None, body, None)
+        );
+    }
 
-    drop(network);
+    #[test]
+    fn config_fills_in_only_missing_options() {
+        let config = || Config {
+            room: Some("from-config".to_string()),
+            username: Some("carol".to_string()),
+            prompt: Some("> ".to_string()),
+            wrap: Some(60),
+            ..Default::default()
+        };
 
-    result
+        let mut args = parse_args(&[], &["--room", "lobby", "--wrap", "80"]).unwrap();
+        let mut command = args.take_command();
+        config().apply(&mut args, &mut command);
+        assert_eq!(args.room.as_deref(), Some("lobby"));
+        assert_eq!(args.username.as_deref(), Some("carol"));
+        match command {
+            Command::Chat(chat) => {
+                assert_eq!(chat.prompt, Some(Some("> ".to_string())));
+                assert_eq!(chat.follow.wrap, Some(80));
+            }
+            command => panic!("Parsed {:?}, not chat", command),
+        }
+
+        let mut args = parse_args(&[], &["--room", "lobby", "page"]).unwrap();
+        let mut command = args.take_command();
+        config().apply(&mut args, &mut command);
+        match command {
+            Command::Page { wrap } => assert_eq!(wrap, Some(60)),
+            command => panic!("Parsed {:?}, not page", command),
+        }
+    }
+
+    #[async_std::test]
+    async fn input_ends_with_none() {
+        let terminal = Terminal {
+            prompt: None,
+            editing: None,
+            wrap: None,
+        };
+        let mut input = Input::from_reader(&terminal, io::Cursor::new("one\r\n/multiline\ntwo"));
+        assert_eq!(input.next().await.unwrap().as_deref(), Some("one\n"));
+        assert_eq!(input.next().await.unwrap().as_deref(), Some("/multiline\n"));
+        // A message spanning several lines also ends with the input
+        assert_eq!(input.next_multiline().await.unwrap(), "two");
+        assert_eq!(input.next().await.unwrap(), None);
+        assert_eq!(input.next().await.unwrap(), None);
+    }
 }